@@ -0,0 +1,231 @@
+//! api.rs - the resource oriented version 2 of the api
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! mounted at `/api/v2`. Resources are read with GET, so that the answers can be
+//! cached, and changed with POST and DELETE. The user logs in with the
+//! `Authorization: Basic` header, see [BasicAuth], bodies are sent as json or form,
+//! see [Input].
+//!
+//! The routes outside of `/api` all use POST with the credentials in the body.
+//! They keep working as v1, but their answers are marked as deprecated, see [deprecate_v1].
+use crate::booking::BookingDefaults;
+use crate::database::{Connection, DatabaseConnection, DatabaseUtils, Storage};
+use crate::error::{self, Error};
+use crate::event::{Cancellation, Event};
+use crate::input::Input;
+use crate::room::{self, ListOptions, NewEvent, Room, RoomFilter, RoomList, RoomSummary};
+use crate::user::{self, BasicAuth, User};
+use crate::{image, MainDatabase};
+use bson::doc;
+use bson::oid::ObjectId;
+use chrono::Local;
+use rocket::fairing::AdHoc;
+use rocket::fs::NamedFile;
+use rocket::http::Header;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+
+fn parse_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| Error::Invalid(String::from("the id is not a valid id")))
+}
+
+/// the room of the event with the uid, with the event loaded
+async fn room_of_event(uid: &str, db: &Storage) -> Result<Room, Error> {
+    let stored = db
+        .event_collection()
+        .find_one(doc! {"event.uid": uid, "event.cancellation": null}, None)
+        .await?
+        .ok_or(Error::NotFound("event not found"))?;
+    let mut room = Room::getfromdb_id(&stored.room_id, db).await?;
+    room.load_event_uid(uid, db).await?;
+    Ok(room)
+}
+
+/// lists the rooms matching the filter, like /room/list
+#[get("/rooms?<sort>&<descending>&<page>&<per_page>&<bookable>&<filter..>")]
+#[allow(clippy::too_many_arguments)]
+async fn rooms(
+    sort: Option<&str>,
+    descending: Option<bool>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    bookable: Option<bool>,
+    filter: RoomFilter,
+    auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<Json<RoomList>, Error> {
+    let options = ListOptions {
+        sort,
+        descending,
+        page,
+        per_page,
+        bookable,
+    };
+    Ok(Json(
+        room::list_rooms(&auth.0, options, &filter, &db).await?,
+    ))
+}
+
+#[get("/rooms/<id>")]
+async fn get_room(
+    id: &str,
+    auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<Json<RoomSummary>, Error> {
+    let room = Room::getfromdb_id(&parse_id(id)?, &db).await?;
+    let may_book = auth.0.can_edit_room(&room.id());
+    Ok(Json(RoomSummary::new(room, may_book)))
+}
+
+/// the events of the room starting between from and to, given in RFC 3339
+#[get("/rooms/<id>/events?<from>&<to>&<include_cancelled>")]
+async fn events(
+    id: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+    include_cancelled: Option<bool>,
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<Event>>, Error> {
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err(Error::Invalid(String::from("from and to are needed")));
+    };
+    let start = error::parse_time("from", from)?;
+    let stop = error::parse_time("to", to)?;
+    let include_cancelled = include_cancelled == Some(true);
+    let mut room = Room::getfromdb_id(&parse_id(id)?, &db).await?;
+    room.load_events(start, stop, include_cancelled, &db)
+        .await?;
+    let in_range = room.events_in_range(start, stop, include_cancelled);
+    Ok(Json(in_range.into_iter().cloned().collect()))
+}
+
+/// books an event like /room/add_event, its location is `/api/v2/events/<uid>`
+#[post("/rooms/<id>/events", data = "<event>")]
+async fn book(
+    id: &str,
+    event: Input<NewEvent>,
+    auth: BasicAuth,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<status::Created<Json<Event>>, Error> {
+    let mut room = Room::getfromdb_id(&parse_id(id)?, &db).await?;
+    let event = room::book(&auth.0, &mut room, event.into_inner(), defaults, &db).await?;
+    Ok(status::Created::new(format!("/api/v2/events/{}", event.uid())).body(Json(event)))
+}
+
+#[get("/events/<uid>")]
+async fn get_event(
+    uid: &str,
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Event>, Error> {
+    let room = room_of_event(uid, &db).await?;
+    let event = room
+        .get_event_uid(uid)
+        .ok_or(Error::NotFound("event not found"))?;
+    Ok(Json(event.clone()))
+}
+
+/// cancels the event like /room/remove_event
+#[delete("/events/<uid>?<reason>")]
+async fn cancel_event(
+    uid: &str,
+    reason: Option<String>,
+    auth: BasicAuth,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<status::NoContent, Error> {
+    let user = auth.0;
+    let mut room = room_of_event(uid, &db).await?;
+    let cancellation = Cancellation {
+        by: user.id().to_hex(),
+        at: Local::now(),
+        reason,
+    };
+    let cancelled = room
+        .cancel_event_uid(uid, cancellation)
+        .ok_or(Error::NotFound("event not found"))?;
+    room::cancel(&user, &mut room, &cancelled, defaults, &db).await?;
+    Ok(status::NoContent)
+}
+
+/// the logged in user
+#[get("/user")]
+async fn get_user(auth: BasicAuth) -> Json<User> {
+    Json(auth.0)
+}
+
+/// the names of the rooms the user can edit, like /user/get_rooms
+#[get("/user/rooms?<filter..>")]
+async fn user_rooms(
+    filter: RoomFilter,
+    auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<String>>, Error> {
+    Ok(Json(user::editable_rooms(&auth.0, &filter, &db).await?))
+}
+
+#[get("/images")]
+async fn images(
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<String>>, Error> {
+    Ok(Json(image::list_names(db.tenant())?))
+}
+
+#[get("/images/<name>")]
+async fn get_image(
+    name: &str,
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<NamedFile, Error> {
+    image::open(name, false, db.tenant()).await
+}
+
+#[get("/images/<name>/preview")]
+async fn get_preview(
+    name: &str,
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Result<NamedFile, Error> {
+    image::open(name, true, db.tenant()).await
+}
+
+/// marks the answers of the v1 routes with a `Deprecation` header and links to v2.
+/// /status and the CalDAV routes are not part of the api
+pub fn deprecate_v1() -> AdHoc {
+    AdHoc::on_response("Deprecate v1", |request, response| {
+        Box::pin(async move {
+            if request.route().is_none() {
+                return;
+            }
+            match request.uri().path().segments().next() {
+                Some("api" | "caldav" | "status") | None => {}
+                Some(_) => {
+                    response.set_header(Header::new("Deprecation", "true"));
+                    response
+                        .set_header(Header::new("Link", "</api/v2>; rel=\"successor-version\""));
+                }
+            }
+        })
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        rooms,
+        get_room,
+        events,
+        book,
+        get_event,
+        cancel_event,
+        get_user,
+        user_rooms,
+        images,
+        get_image,
+        get_preview
+    ]
+}
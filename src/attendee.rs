@@ -0,0 +1,322 @@
+//! attendee.rs - invitations to events and the responses of the attendees
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! attendees are either users or external email addresses. Everyone with an
+//! email gets an iCalendar invitation (METHOD:REQUEST) with a link to respond,
+//! users can also respond with /attendee/respond.
+use crate::database::{Connection, DatabaseConnection};
+use crate::error::Error;
+use crate::event::{Attendee, Event, Rsvp};
+use crate::input::Input;
+use crate::mail::{self, Mail, MailService};
+use crate::room::{find_room, split_list, Room};
+use crate::{debug_println, ical, notification, user, MainDatabase};
+use bson::oid::ObjectId;
+use rocket::response::content::RawHtml;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+use serde::*;
+
+#[derive(Debug, Deserialize, FromForm)]
+struct InviteForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    uid: String,
+    /// comma separated usernames
+    usernames: Option<String>,
+    /// comma separated email addresses of external attendees
+    emails: Option<String>,
+}
+/// invites users and external attendees to an event of the booker.
+/// Attendees that were already invited are skipped.
+#[post("/invite", data = "<form>")]
+async fn invite(
+    form: Input<InviteForm<'_>>,
+    mailer: &State<MailService>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    room.load_event_uid(&form.uid, &db).await?;
+    let event = room
+        .get_event_uid(&form.uid)
+        .ok_or(Error::NotFound("event not found"))?;
+    if !user.can_act_for_id(event.booker_id(), &db).await && !room.can_approve(&user) {
+        return Err(Error::Forbidden("you cannot invite to this event"));
+    }
+    let mut attendees = Vec::new();
+    for username in split_list(form.usernames.as_deref().unwrap_or_default()) {
+        let invited = user::User::getfromdb_name(&username, &db).await?;
+        attendees.push(Attendee::create(
+            Some(invited.id()),
+            invited.email().map(String::from),
+            Some(invited.full_name()),
+        ));
+    }
+    for email in split_list(form.emails.as_deref().unwrap_or_default()) {
+        if !mail::is_valid_address(&email) {
+            return Err(Error::Invalid(format!("{} is not an email address", email)));
+        }
+        attendees.push(Attendee::create(None, Some(email), None));
+    }
+    let added = room
+        .update_event(&form.uid, &db, |event| {
+            Some(
+                attendees
+                    .iter()
+                    .filter(|attendee| event.add_attendee((*attendee).clone()))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await?
+        .unwrap_or_default();
+    let event = room
+        .get_event_uid(&form.uid)
+        .ok_or(Error::NotFound("event not found"))?;
+
+    let invitation = ical::write_invitation(event, room.name(), user.email());
+    for attendee in &added {
+        if let Some(user_id) = attendee.user_id {
+            let message = format!(
+                "{} invited you to \"{}\" in {} on {}",
+                user.full_name(),
+                event.headline(),
+                room.name(),
+                event.start().format("%d.%m.%Y %H:%M")
+            );
+            if let Err(err) = notification::notify(&user_id.to_hex(), message, &db).await {
+                debug_println!("could not notify attendee: {:?}", err);
+            }
+        }
+        if let Some(email) = &attendee.email {
+            mailer
+                .send(invitation_mail(
+                    email,
+                    event,
+                    &room,
+                    attendee,
+                    &invitation,
+                    &mailer.base_url,
+                ))
+                .await;
+        }
+    }
+    Ok(())
+}
+
+fn invitation_mail(
+    to: &str,
+    event: &Event,
+    room: &Room,
+    attendee: &Attendee,
+    invitation: &str,
+    base_url: &str,
+) -> Mail {
+    let link = |response: &str| {
+        format!(
+            "{}/attendee/reply?room={}&uid={}&token={}&response={}",
+            base_url,
+            room.id().to_hex(),
+            event.uid(),
+            attendee.token,
+            response
+        )
+    };
+    Mail {
+        to: String::from(to),
+        subject: format!("Einladung: {}", event.headline()),
+        body: format!(
+            "{}\n{} - {}\nRaum: {}\n\nZusagen: {}\nVielleicht: {}\nAbsagen: {}\n",
+            event.headline(),
+            event.start().format("%d.%m.%Y %H:%M"),
+            event.stop().format("%H:%M"),
+            room.name(),
+            link("accepted"),
+            link("tentative"),
+            link("declined")
+        ),
+        calendar: Some((String::from(invitation), "REQUEST")),
+    }
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct RespondForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    uid: String,
+    response: Rsvp,
+}
+/// stores the response of the logged in user to an invitation
+#[post("/respond", data = "<form>")]
+async fn respond(form: Input<RespondForm<'_>>, db: Connection<MainDatabase>) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    room.update_event(&form.uid, &db, |event| {
+        event.respond(form.response, |attendee| {
+            attendee.user_id == Some(user.id())
+        })
+    })
+    .await?
+    .ok_or(Error::Forbidden("you are not invited to this event"))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// the link in the invitation mail, used by attendees without an account.
+/// Links are opened by mail scanners and previews as well, so the response is
+/// only stored after it was confirmed with the form, see [confirm_reply].
+/// room is the hex representation of the room id
+#[get("/reply?<room>&<uid>&<token>&<response>")]
+async fn reply(
+    room: &str,
+    uid: &str,
+    token: &str,
+    response: Rsvp,
+) -> Result<RawHtml<String>, Error> {
+    let room_id = ObjectId::parse_str(room)
+        .map_err(|_| Error::Invalid(String::from("room is not a valid id")))?;
+    let (value, label) = match response {
+        Rsvp::NeedsAction => ("needs_action", "Antwort zurücksetzen"),
+        Rsvp::Accepted => ("accepted", "Zusagen"),
+        Rsvp::Tentative => ("tentative", "Vielleicht"),
+        Rsvp::Declined => ("declined", "Absagen"),
+    };
+    Ok(RawHtml(format!(
+        "<!DOCTYPE html>\n<html><body>\n\
+         <form method=\"post\" action=\"reply\">\n\
+         <input type=\"hidden\" name=\"room\" value=\"{}\">\n\
+         <input type=\"hidden\" name=\"uid\" value=\"{}\">\n\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+         <input type=\"hidden\" name=\"response\" value=\"{}\">\n\
+         <button type=\"submit\">{}</button>\n\
+         </form>\n</body></html>\n",
+        room_id.to_hex(),
+        escape_html(uid),
+        escape_html(token),
+        value,
+        label
+    )))
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct ReplyForm {
+    room: String,
+    uid: String,
+    token: String,
+    response: Rsvp,
+}
+/// stores the response confirmed on the page of [reply]
+#[post("/reply", data = "<form>")]
+async fn confirm_reply(
+    form: Input<ReplyForm>,
+    db: Connection<MainDatabase>,
+) -> Result<&'static str, Error> {
+    let room_id = ObjectId::parse_str(&form.room)
+        .map_err(|_| Error::Invalid(String::from("room is not a valid id")))?;
+    let mut room = Room::getfromdb_id(&room_id, &db).await?;
+    room.update_event(&form.uid, &db, |event| {
+        event.respond(form.response, |attendee| attendee.token == form.token)
+    })
+    .await?
+    .ok_or(Error::Forbidden("the link is not valid"))?;
+    Ok("Danke für die Antwort")
+}
+
+#[derive(Debug, Serialize)]
+struct AttendeeInfo {
+    user_id: Option<ObjectId>,
+    email: Option<String>,
+    name: Option<String>,
+    response: Rsvp,
+}
+#[derive(Debug, Serialize)]
+struct AttendeeList {
+    capacity: Option<u32>,
+    invited: usize,
+    accepted: usize,
+    tentative: usize,
+    declined: usize,
+    /// more attendees that did not decline than fit into the room
+    over_capacity: bool,
+    attendees: Vec<AttendeeInfo>,
+}
+#[derive(Debug, Deserialize, FromForm)]
+struct ListForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    uid: String,
+}
+/// lists the attendees of an event with their responses as json,
+/// counted against the capacity of the room.
+/// Only the booker, the approvers of the room and the attendees can see them
+#[post("/list", data = "<form>")]
+async fn list(
+    form: Input<ListForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<AttendeeList>, Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    room.load_event_uid(&form.uid, &db).await?;
+    let event = room
+        .get_event_uid(&form.uid)
+        .ok_or(Error::NotFound("event not found"))?;
+    let is_attendee = event
+        .attendees()
+        .iter()
+        .any(|attendee| attendee.user_id == Some(user.id()));
+    if !is_attendee
+        && !room.can_approve(&user)
+        && !user.can_act_for_id(event.booker_id(), &db).await
+    {
+        return Err(Error::Forbidden(
+            "you cannot see the attendees of this event",
+        ));
+    }
+    let count = |rsvp: Rsvp| {
+        event
+            .attendees()
+            .iter()
+            .filter(|attendee| attendee.response == rsvp)
+            .count()
+    };
+    let list = AttendeeList {
+        capacity: room.capacity(),
+        invited: event.attendees().len(),
+        accepted: count(Rsvp::Accepted),
+        tentative: count(Rsvp::Tentative),
+        declined: count(Rsvp::Declined),
+        over_capacity: room.capacity().map_or(false, |capacity| {
+            event.expected_attendees() > capacity as usize
+        }),
+        // the tokens are only sent to the attendees themselves
+        attendees: event
+            .attendees()
+            .iter()
+            .map(|attendee| AttendeeInfo {
+                user_id: attendee.user_id,
+                email: attendee.email.clone(),
+                name: attendee.name.clone(),
+                response: attendee.response,
+            })
+            .collect(),
+    };
+    Ok(Json(list))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![invite, respond, reply, confirm_reply, list]
+}
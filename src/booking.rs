@@ -0,0 +1,859 @@
+//! booking.rs - rules that decide whether an [Event] may be booked
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! a [Room] can have its own [OpeningHours], blackout periods and
+//! [HolidayCalendar]s. Rooms without opening hours use the global default
+//! of the `booking` section in Rocket.toml:
+//! ```toml
+//! [default.booking]
+//! opening_hours = "Mon-Fri 07:00-20:00; Sat 08:00-14:00"
+//! holiday_calendars = ["Schulferien Niedersachsen"]
+//! [default.booking.policy]
+//! max_duration = 240
+//! ```
+//! The [BookingPolicy] of a room is the one of the room itself, or of its
+//! [RoomGroup], or the global one, whichever is found first.
+use crate::database::{self, Collection, Connection, DatabaseConnection, DatabaseUtils, Storage};
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::input::Input;
+use crate::room::Room;
+use crate::{ical, user, MainDatabase};
+use bson::doc;
+use bson::oid::ObjectId;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, Weekday};
+use rocket::serde::json::Json;
+use rocket::{fairing, Build, Rocket, Route};
+use serde::*;
+use std::fmt;
+
+/// weekly opening hours, e.g. "Mon-Fri 07:30-18:00; Sat 08:00-12:00".
+/// Days without an entry are closed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct OpeningHours(Vec<OpeningTime>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct OpeningTime {
+    weekday: Weekday,
+    open: NaiveTime,
+    close: NaiveTime,
+}
+
+impl OpeningHours {
+    /// the event needs to start and stop on the same day within one opening time
+    pub fn check(&self, event: &Event) -> Result<(), BookingError> {
+        let (start, stop) = (event.start(), event.stop());
+        let weekday = start.weekday();
+        let fits = self.0.iter().any(|time| {
+            time.weekday == weekday
+                && start.date_naive() == stop.date_naive()
+                && time.open <= start.time()
+                && stop.time() <= time.close
+        });
+        if fits {
+            return Ok(());
+        }
+        Err(BookingError::OutsideOpeningHours {
+            weekday,
+            opening_hours: self.clone(),
+        })
+    }
+}
+
+impl TryFrom<String> for OpeningHours {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut times = Vec::new();
+        for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (days, hours) = entry
+                .split_once(' ')
+                .ok_or_else(|| format!("missing hours in \"{}\"", entry))?;
+            let (open, close) = hours
+                .trim()
+                .split_once('-')
+                .ok_or_else(|| format!("hours need to look like 07:30-18:00: \"{}\"", entry))?;
+            let parse_time = |time: &str| {
+                NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                    .map_err(|_| format!("invalid time \"{}\"", time))
+            };
+            let (open, close) = (parse_time(open)?, parse_time(close)?);
+            if open >= close {
+                return Err(format!("\"{}\" closes before it opens", entry));
+            }
+            for weekday in parse_weekdays(days)? {
+                times.push(OpeningTime {
+                    weekday,
+                    open,
+                    close,
+                });
+            }
+        }
+        Ok(OpeningHours(times))
+    }
+}
+
+/// parses "Mon", "Mon,Wed" or "Mon-Fri"
+fn parse_weekdays(days: &str) -> Result<Vec<Weekday>, String> {
+    let parse = |day: &str| {
+        day.trim()
+            .parse::<Weekday>()
+            .map_err(|_| format!("unknown weekday \"{}\"", day))
+    };
+    let mut ret = Vec::new();
+    for part in days.split(',') {
+        if let Some((from, to)) = part.split_once('-') {
+            let (mut day, to) = (parse(from)?, parse(to)?);
+            ret.push(day);
+            while day != to {
+                day = day.succ();
+                ret.push(day);
+            }
+        } else {
+            ret.push(parse(part)?);
+        }
+    }
+    Ok(ret)
+}
+
+impl From<OpeningHours> for String {
+    fn from(value: OpeningHours) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for OpeningHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<String> = self
+            .0
+            .iter()
+            .map(|time| {
+                format!(
+                    "{} {}-{}",
+                    time.weekday,
+                    time.open.format("%H:%M"),
+                    time.close.format("%H:%M")
+                )
+            })
+            .collect();
+        write!(f, "{}", entries.join("; "))
+    }
+}
+
+/// a period in which a room cannot be booked, e.g. a holiday
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Blackout {
+    pub start: DateTime<Local>,
+    pub stop: DateTime<Local>,
+    pub reason: String,
+}
+impl Blackout {
+    pub fn check(&self, event: &Event) -> Result<(), BookingError> {
+        if event.start() < self.stop && self.start < event.stop() {
+            return Err(BookingError::Blackout(self.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// a named collection of blackouts imported from an iCalendar file,
+/// e.g. the school holidays of a federal state
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HolidayCalendar {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    name: String,
+    blackouts: Vec<Blackout>,
+    #[serde(default)]
+    version: i64,
+}
+impl database::DatabaseConnection for HolidayCalendar {
+    #[inline]
+    fn id(&self) -> ObjectId {
+        self.id
+    }
+    #[inline]
+    fn version(&self) -> i64 {
+        self.version
+    }
+    #[inline]
+    fn set_version(&mut self, version: i64) {
+        self.version = version;
+    }
+    #[inline]
+    fn collection(db: &Storage) -> Collection<Self> {
+        db.holiday_calendar_collection()
+    }
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+    #[inline]
+    fn index_name() -> &'static str {
+        "name"
+    }
+}
+
+/// the highest limit in minutes of a [BookingPolicy], a year
+const MAX_POLICY_MINUTES: i64 = 366 * 24 * 60;
+/// the highest limit in days of a [BookingPolicy], ten years
+const MAX_POLICY_DAYS: i64 = 10 * 366;
+
+/// like [Duration::minutes], but None instead of a panic if it is out of range
+pub fn checked_minutes(minutes: i64) -> Option<Duration> {
+    minutes.checked_mul(60 * 1000).map(Duration::milliseconds)
+}
+
+/// limits for the events a single user can book.
+/// Durations are given in minutes, unset fields are not limited.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, FromForm)]
+pub struct BookingPolicy {
+    pub min_duration: Option<i64>,
+    pub max_duration: Option<i64>,
+    /// how many days in advance an event can be booked
+    pub max_days_in_advance: Option<i64>,
+    /// how many minutes before the start an event has to be booked
+    pub min_notice: Option<i64>,
+    /// number of events of the user that have not ended yet, in all rooms
+    pub max_future_bookings: Option<usize>,
+    /// booked hours of the user in the calendar week of the event, in all rooms
+    pub max_hours_per_week: Option<i64>,
+}
+impl BookingPolicy {
+    /// checks that the limits are not negative and not too large to calculate with
+    pub fn validate(&self) -> Result<(), Error> {
+        let minutes = [
+            ("min_duration", self.min_duration),
+            ("max_duration", self.max_duration),
+            ("min_notice", self.min_notice),
+        ];
+        for (field, value) in minutes {
+            if let Some(value) = value {
+                error::check_range(field, value, 0..=MAX_POLICY_MINUTES)?;
+            }
+        }
+        if let Some(days) = self.max_days_in_advance {
+            error::check_range("max_days_in_advance", days, 0..=MAX_POLICY_DAYS)?;
+        }
+        if let Some(hours) = self.max_hours_per_week {
+            error::check_range("max_hours_per_week", hours, 0..=7 * 24)?;
+        }
+        Ok(())
+    }
+    /// context needs to contain the events of the booker.
+    /// Limits too large to calculate with, e.g. stored before [BookingPolicy::validate],
+    /// are treated as if the event was beyond them
+    pub fn check(&self, event: &Event, context: &BookingContext) -> Result<(), BookingError> {
+        let duration = event.stop() - event.start();
+        let after_now = |minutes: i64| {
+            checked_minutes(minutes).and_then(|minutes| context.now.checked_add_signed(minutes))
+        };
+        if let Some(min) = self.min_duration
+            && checked_minutes(min).map_or(true, |min| duration < min)
+        {
+            return Err(BookingError::TooShort { min_minutes: min });
+        }
+        if let Some(max) = self.max_duration
+            && checked_minutes(max).map_or(false, |max| duration > max)
+        {
+            return Err(BookingError::TooLong { max_minutes: max });
+        }
+        if let Some(max) = self.max_days_in_advance
+            && max
+                .checked_mul(24 * 60)
+                .and_then(after_now)
+                .map_or(false, |latest| event.start() > latest)
+        {
+            return Err(BookingError::TooFarInAdvance { max_days: max });
+        }
+        if let Some(min) = self.min_notice
+            && after_now(min).map_or(true, |earliest| event.start() < earliest)
+        {
+            return Err(BookingError::TooShortNotice { min_minutes: min });
+        }
+        if let Some(max) = self.max_future_bookings {
+            let future = context
+                .booker_events
+                .iter()
+                .filter(|booked| booked.stop() > context.now)
+                .count();
+            if future >= max {
+                return Err(BookingError::TooManyBookings { max });
+            }
+        }
+        if let Some(max) = self.max_hours_per_week {
+            let week = event.start().iso_week();
+            let booked = context
+                .booker_events
+                .iter()
+                .filter(|booked| booked.start().iso_week() == week)
+                .fold(duration, |sum, booked| {
+                    sum + (booked.stop() - booked.start())
+                });
+            let limit = max.checked_mul(60).and_then(checked_minutes);
+            if limit.map_or(false, |limit| booked > limit) {
+                return Err(BookingError::WeeklyHoursExceeded { max_hours: max });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// rooms that share a [BookingPolicy], e.g. all computer labs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomGroup {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    name: String,
+    policy: BookingPolicy,
+    #[serde(default)]
+    version: i64,
+}
+impl database::DatabaseConnection for RoomGroup {
+    #[inline]
+    fn id(&self) -> ObjectId {
+        self.id
+    }
+    #[inline]
+    fn version(&self) -> i64 {
+        self.version
+    }
+    #[inline]
+    fn set_version(&mut self, version: i64) {
+        self.version = version;
+    }
+    #[inline]
+    fn collection(db: &Storage) -> Collection<Self> {
+        db.room_group_collection()
+    }
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+    #[inline]
+    fn index_name() -> &'static str {
+        "name"
+    }
+}
+
+/// the global defaults, read from the `booking` section of the config
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct BookingDefaults {
+    #[serde(default)]
+    pub opening_hours: Option<OpeningHours>,
+    /// names of [HolidayCalendar]s that apply to every room
+    #[serde(default)]
+    pub holiday_calendars: Vec<String>,
+    #[serde(default)]
+    pub policy: Option<BookingPolicy>,
+}
+
+/// A fairing that reads the [BookingDefaults] and manages them.
+/// A missing `booking` section leaves every room open all the time.
+pub async fn load_defaults(rocket: Rocket<Build>) -> fairing::Result {
+    let defaults = match rocket.figment().extract_inner::<BookingDefaults>("booking") {
+        Ok(defaults) => defaults,
+        Err(err) if err.missing() => BookingDefaults::default(),
+        Err(err) => {
+            println!("invalid booking config: {}", err);
+            return Err(rocket);
+        }
+    };
+    if let Some(Err(err)) = defaults.policy.as_ref().map(BookingPolicy::validate) {
+        println!("invalid booking config: {}", err);
+        return Err(rocket);
+    }
+    Ok(rocket.manage(defaults))
+}
+
+/// everything outside of the room needed to validate an event
+#[derive(Debug, Default)]
+pub struct BookingContext {
+    pub now: DateTime<Local>,
+    /// used if the room has no own opening hours
+    pub opening_hours: Option<OpeningHours>,
+    /// blackouts of all holiday calendars of the room
+    pub blackouts: Vec<Blackout>,
+    /// the policy of the room, its group or the global one
+    pub policy: Option<BookingPolicy>,
+    /// all events of the booker in all rooms, used for the quotas of the policy
+    pub booker_events: Vec<Event>,
+}
+impl BookingContext {
+    /// loads the holiday calendars of the room and the global ones,
+    /// the policy of the room and the events of the booker
+    pub async fn load(
+        room: &Room,
+        booker_id: &str,
+        defaults: &BookingDefaults,
+        db: &Storage,
+    ) -> Result<Self, database::Error> {
+        let filter = doc! {"$or": [
+            {"_id": {"$in": room.holiday_calendars()}},
+            {"name": {"$in": &defaults.holiday_calendars}},
+        ]};
+        let mut blackouts = Vec::new();
+        let mut calendars = db.holiday_calendar_collection().find(filter, None).await?;
+        while calendars.advance().await? {
+            blackouts.append(&mut calendars.deserialize_current()?.blackouts);
+        }
+        let policy = match (room.policy(), room.group()) {
+            (Some(policy), _) => Some(policy.clone()),
+            (None, Some(group)) => match RoomGroup::getfromdb_id(&group, db).await {
+                Ok(group) => Some(group.policy),
+                Err(database::Error::NotFound) => defaults.policy.clone(),
+                Err(err) => return Err(err),
+            },
+            (None, None) => defaults.policy.clone(),
+        };
+        let booker_events = if policy.is_some() {
+            Self::load_booker_events(booker_id, db).await?
+        } else {
+            Vec::new()
+        };
+        Ok(BookingContext {
+            now: Local::now(),
+            opening_hours: defaults.opening_hours.clone(),
+            blackouts,
+            policy,
+            booker_events,
+        })
+    }
+
+    /// collects the events of the booker from all rooms
+    async fn load_booker_events(
+        booker_id: &str,
+        db: &Storage,
+    ) -> Result<Vec<Event>, database::Error> {
+        let filter = doc! {"event.booker_id": booker_id, "event.cancellation": null};
+        let mut events = Vec::new();
+        let mut cursor = db.event_collection().find(filter, None).await?;
+        while cursor.advance().await? {
+            events.push(cursor.deserialize_current()?.event);
+        }
+        Ok(events)
+    }
+
+    /// checks the event against the policy, if there is one
+    pub fn check_policy(&self, event: &Event) -> Result<(), BookingError> {
+        match &self.policy {
+            Some(policy) => policy.check(event, self),
+            None => Ok(()),
+        }
+    }
+}
+
+/// the reason why an event could not be booked.
+/// It is serialized with the violated rule as tag, e.g. `{"rule":"too_long","max_minutes":90}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum BookingError {
+    /// another event takes place at the same time
+    Overlap,
+    OutsideOpeningHours {
+        weekday: Weekday,
+        opening_hours: OpeningHours,
+    },
+    Blackout(Blackout),
+    TooShort {
+        min_minutes: i64,
+    },
+    TooLong {
+        max_minutes: i64,
+    },
+    TooFarInAdvance {
+        max_days: i64,
+    },
+    TooShortNotice {
+        min_minutes: i64,
+    },
+    TooManyBookings {
+        max: usize,
+    },
+    WeeklyHoursExceeded {
+        max_hours: i64,
+    },
+    /// the event is only a placeholder for comparisons, see [Event::create_dummy]
+    Dummy,
+}
+impl BookingError {
+    /// the serialized error with the human readable message in "message"
+    pub fn to_json(&self) -> String {
+        self.to_json_value().to_string()
+    }
+    /// like [BookingError::to_json], for adding more fields
+    pub fn to_json_value(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(object) = value.as_object_mut() {
+            object.insert(String::from("message"), self.to_string().into());
+        }
+        value
+    }
+}
+impl fmt::Display for BookingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookingError::Overlap => write!(f, "the room is already booked at that time"),
+            BookingError::OutsideOpeningHours {
+                weekday,
+                opening_hours,
+            } => write!(
+                f,
+                "the event on {} is outside of the opening hours \"{}\"",
+                weekday, opening_hours
+            ),
+            BookingError::Blackout(blackout) => write!(
+                f,
+                "the room cannot be booked from {} to {}: {}",
+                blackout.start.format("%d.%m.%Y %H:%M"),
+                blackout.stop.format("%d.%m.%Y %H:%M"),
+                blackout.reason
+            ),
+            BookingError::TooShort { min_minutes } => {
+                write!(f, "events need to last at least {} minutes", min_minutes)
+            }
+            BookingError::TooLong { max_minutes } => {
+                write!(f, "events can last at most {} minutes", max_minutes)
+            }
+            BookingError::TooFarInAdvance { max_days } => {
+                write!(
+                    f,
+                    "events can be booked at most {} days in advance",
+                    max_days
+                )
+            }
+            BookingError::TooShortNotice { min_minutes } => write!(
+                f,
+                "events need to be booked at least {} minutes before they start",
+                min_minutes
+            ),
+            BookingError::TooManyBookings { max } => {
+                write!(f, "you cannot have more than {} upcoming bookings", max)
+            }
+            BookingError::WeeklyHoursExceeded { max_hours } => write!(
+                f,
+                "you cannot book more than {} hours in one week",
+                max_hours
+            ),
+            BookingError::Dummy => write!(f, "dummy events cannot be booked"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct ImportHolidaysForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    name: String,
+    /// content of an iCalendar file, every VEVENT becomes a blackout
+    ics: String,
+}
+/// creates or replaces the holiday calendar with the given name.
+/// Only admins can import calendars.
+#[post("/import_holidays", data = "<form>")]
+async fn import_holidays(
+    form: Input<ImportHolidaysForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    if !user.can_create_rooms() {
+        return Err(Error::Forbidden("only admins can import holidays"));
+    }
+    let blackouts: Vec<Blackout> = ical::parse_events(&form.ics)
+        .into_iter()
+        .map(|vevent| Blackout {
+            start: vevent.start,
+            stop: vevent.stop,
+            reason: vevent.summary.unwrap_or_else(|| form.name.clone()),
+        })
+        .collect();
+    if blackouts.is_empty() {
+        return Err(Error::Invalid(String::from("the file contains no events")));
+    }
+    match HolidayCalendar::getfromdb_name(&form.name, &db).await {
+        Ok(calendar) => {
+            HolidayCalendar::modify(&calendar.id(), &db, |calendar| {
+                calendar.blackouts = blackouts.clone();
+                Some(())
+            })
+            .await?;
+        }
+        Err(database::Error::NotFound) => {
+            let calendar = HolidayCalendar {
+                id: ObjectId::new(),
+                name: form.name.clone(),
+                blackouts,
+                version: 0,
+            };
+            calendar.insert(&db).await?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// lists the names of all holiday calendars
+#[post("/holidays", data = "<form>")]
+async fn holidays(
+    form: Input<user::UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<String>>, Error> {
+    form.login(&db).await?;
+    let mut names = Vec::new();
+    let mut calendars = HolidayCalendar::get_all_from_db(&db).await?;
+    while calendars.advance().await? {
+        names.push(calendars.deserialize_current()?.name);
+    }
+    Ok(Json(names))
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct SetPolicyForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    /// either the room or the group to set the policy for
+    room_name: Option<String>,
+    group: Option<String>,
+    /// removes the policy of the room, so that the one of the group is used
+    clear: Option<bool>,
+    #[serde(default)]
+    policy: BookingPolicy,
+}
+/// sets the policy of a room or a room group. Rooms can only be changed by admins
+/// and their owner, groups are created if they do not exist and can only be changed by admins.
+#[post("/set_policy", data = "<form>")]
+async fn set_policy(
+    form: Input<SetPolicyForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    form.policy.validate()?;
+    if let Some(room_name) = &form.room_name {
+        let room = Room::getfromdb_name(room_name, &db).await?;
+        if !room.can_change_rules(&user) {
+            return Err(Error::Forbidden(
+                "only admins and the owner can change the policy of the room",
+            ));
+        }
+        let policy = (form.clear != Some(true)).then(|| form.policy.clone());
+        Room::modify(&room.id(), &db, |room| {
+            room.set_policy(policy.clone());
+            Some(())
+        })
+        .await?;
+        return Ok(());
+    }
+    if !user.can_create_rooms() {
+        return Err(Error::Forbidden("only admins can change room groups"));
+    }
+    let name = form
+        .group
+        .clone()
+        .ok_or_else(|| Error::Invalid(String::from("either room_name or group is needed")))?;
+    match RoomGroup::getfromdb_name(&name, &db).await {
+        Ok(group) => {
+            RoomGroup::modify(&group.id(), &db, |group| {
+                group.policy = form.policy.clone();
+                Some(())
+            })
+            .await?;
+        }
+        Err(database::Error::NotFound) => {
+            let group = RoomGroup {
+                id: ObjectId::new(),
+                name,
+                policy: form.policy.clone(),
+                version: 0,
+            };
+            group.insert(&db).await?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct SetGroupForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: String,
+    /// an empty group removes the room from its group
+    group: String,
+}
+/// adds the room to a room group
+#[post("/set_group", data = "<form>")]
+async fn set_group(
+    form: Input<SetGroupForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let room = Room::getfromdb_name(&form.room_name, &db).await?;
+    // the group decides the policy of rooms without their own
+    if !room.can_change_rules(&user) {
+        return Err(Error::Forbidden(
+            "only admins and the owner can change the group of the room",
+        ));
+    }
+    let group = if form.group.is_empty() {
+        None
+    } else {
+        Some(RoomGroup::getfromdb_name(&form.group, &db).await?.id())
+    };
+    Room::modify(&room.id(), &db, |room| {
+        room.set_group(group);
+        Some(())
+    })
+    .await?;
+    Ok(())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![import_holidays, holidays, set_policy, set_group]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::tests::event;
+    use chrono::{Duration, TimeZone};
+
+    fn event_at(weekday_offset: i64, from: (u32, u32), to: (u32, u32)) -> Event {
+        // 2023-01-02 is a monday
+        let day =
+            Local.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap() + Duration::days(weekday_offset);
+        event(
+            day,
+            Duration::minutes((from.0 * 60 + from.1) as i64),
+            Duration::minutes((to.0 * 60 + to.1) as i64),
+        )
+    }
+
+    #[test]
+    fn parse_opening_hours() {
+        let hours =
+            OpeningHours::try_from(String::from("Mon-Wed 07:30-18:00; Sat,Sun 08:00-12:00"))
+                .unwrap();
+        assert_eq!(hours.0.len(), 5);
+        assert_eq!(
+            hours.to_string(),
+            "Mon 07:30-18:00; Tue 07:30-18:00; Wed 07:30-18:00; Sat 08:00-12:00; Sun 08:00-12:00"
+        );
+        assert!(OpeningHours::try_from(String::from("Mon 18:00-07:00")).is_err());
+        assert!(OpeningHours::try_from(String::from("Xyz 07:00-18:00")).is_err());
+    }
+    #[test]
+    fn check_opening_hours() {
+        let hours = OpeningHours::try_from(String::from("Mon-Fri 07:30-18:00")).unwrap();
+        assert!(hours.check(&event_at(0, (8, 0), (9, 30))).is_ok());
+        assert!(hours.check(&event_at(4, (7, 30), (18, 0))).is_ok());
+        assert!(hours.check(&event_at(0, (7, 0), (9, 30))).is_err());
+        assert!(hours.check(&event_at(5, (8, 0), (9, 30))).is_err());
+        assert!(hours.check(&event_at(0, (17, 0), (24 + 8, 0))).is_err());
+    }
+    #[test]
+    fn check_policy() {
+        let policy = BookingPolicy {
+            min_duration: Some(30),
+            max_duration: Some(120),
+            max_days_in_advance: Some(7),
+            min_notice: Some(60),
+            max_future_bookings: Some(2),
+            max_hours_per_week: Some(4),
+        };
+        let mut context = BookingContext {
+            now: Local.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap(),
+            ..Default::default()
+        };
+        assert!(policy.check(&event_at(0, (8, 0), (9, 0)), &context).is_ok());
+        assert_eq!(
+            policy.check(&event_at(0, (8, 0), (8, 15)), &context),
+            Err(BookingError::TooShort { min_minutes: 30 })
+        );
+        assert_eq!(
+            policy.check(&event_at(0, (8, 0), (11, 0)), &context),
+            Err(BookingError::TooLong { max_minutes: 120 })
+        );
+        assert_eq!(
+            policy.check(&event_at(7, (8, 0), (9, 0)), &context),
+            Err(BookingError::TooFarInAdvance { max_days: 7 })
+        );
+        assert_eq!(
+            policy.check(&event_at(-1, (12, 30), (13, 30)), &context),
+            Err(BookingError::TooShortNotice { min_minutes: 60 })
+        );
+        context.booker_events = vec![event_at(1, (8, 0), (11, 30))];
+        assert_eq!(
+            policy.check(&event_at(0, (8, 0), (9, 0)), &context),
+            Err(BookingError::WeeklyHoursExceeded { max_hours: 4 })
+        );
+        // events in other weeks do not count
+        context.booker_events = vec![event_at(-6, (8, 0), (11, 30))];
+        assert!(policy.check(&event_at(0, (8, 0), (9, 0)), &context).is_ok());
+        context.booker_events = vec![event_at(1, (8, 0), (9, 0)), event_at(2, (8, 0), (9, 0))];
+        assert_eq!(
+            policy.check(&event_at(0, (8, 0), (8, 30)), &context),
+            Err(BookingError::TooManyBookings { max: 2 })
+        );
+    }
+    #[test]
+    fn policy_out_of_range() {
+        let policy = BookingPolicy {
+            max_days_in_advance: Some(i64::MAX),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+        let context = BookingContext {
+            now: Local.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap(),
+            ..Default::default()
+        };
+        // stored before it was validated, it must not panic
+        assert!(policy.check(&event_at(0, (8, 0), (9, 0)), &context).is_ok());
+        let policy = BookingPolicy {
+            min_notice: Some(i64::MAX),
+            max_hours_per_week: Some(i64::MAX),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+        assert_eq!(
+            policy.check(&event_at(0, (8, 0), (9, 0)), &context),
+            Err(BookingError::TooShortNotice {
+                min_minutes: i64::MAX
+            })
+        );
+        let policy = BookingPolicy {
+            max_duration: Some(120),
+            max_days_in_advance: Some(7),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_ok());
+        assert!(BookingPolicy {
+            min_duration: Some(-1),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+    #[test]
+    fn error_as_json() {
+        let json = BookingError::TooLong { max_minutes: 90 }.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["rule"], "too_long");
+        assert_eq!(value["max_minutes"], 90);
+        assert_eq!(value["message"], "events can last at most 90 minutes");
+    }
+    #[test]
+    fn check_blackout() {
+        let blackout = Blackout {
+            start: Local.with_ymd_and_hms(2023, 1, 3, 0, 0, 0).unwrap(),
+            stop: Local.with_ymd_and_hms(2023, 1, 4, 0, 0, 0).unwrap(),
+            reason: String::from("Feiertag"),
+        };
+        assert!(blackout.check(&event_at(0, (8, 0), (24, 0))).is_ok());
+        assert_eq!(
+            blackout.check(&event_at(1, (8, 0), (9, 0))),
+            Err(BookingError::Blackout(blackout.clone()))
+        );
+    }
+}
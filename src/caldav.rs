@@ -0,0 +1,476 @@
+//! caldav.rs - minimal CalDAV (RFC 4791) interface for rooms
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! every [Room] is a calendar collection at `/caldav/<room name>/` and every
+//! [Event] is a resource `<uid>.ics` in it. Bookings go through
+//! [Room::book_event] and [Room::cancel_event_uid], so the same overlap
+//! rules and permission checks apply as for the form routes.
+//! Calendar clients authenticate with HTTP Basic auth, see [user::BasicAuth].
+//!
+//! Rocket does not accept the WebDAV methods PROPFIND and REPORT, so they have
+//! to be sent as POST with the `X-HTTP-Method-Override` header set to the
+//! original method. Calendar clients send them as they are, so they can only sync
+//! through a reverse proxy in front of the server that rewrites them, e.g. with nginx:
+//! ```nginx
+//! map $request_method $caldav_method {
+//!     PROPFIND POST;
+//!     REPORT POST;
+//!     default $request_method;
+//! }
+//! location /caldav/ {
+//!     proxy_pass http://127.0.0.1:2005;
+//!     proxy_method $caldav_method;
+//!     proxy_set_header X-HTTP-Method-Override $request_method;
+//! }
+//! ```
+use crate::booking::{BookingContext, BookingDefaults};
+use crate::database::{self, Connection, DatabaseConnection};
+use crate::error::Error;
+use crate::event::{self, Cancellation, Event};
+use crate::room::{self, Room};
+use crate::user::BasicAuth;
+use crate::{debug_println, ical, MainDatabase};
+use chrono::Local;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Route, State};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+type DavResponse = (Status, (ContentType, String));
+
+/// the original method of a tunneled WebDAV request
+pub struct MethodOverride<'r>(&'r str);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MethodOverride<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("X-HTTP-Method-Override") {
+            Some(method) => Outcome::Success(MethodOverride(method)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// sent with 401, so that calendar clients ask for credentials
+#[derive(Responder)]
+#[response(status = 401)]
+struct Unauthorized(&'static str, Header<'static>);
+
+#[catch(401)]
+fn unauthorized() -> Unauthorized {
+    Unauthorized(
+        "",
+        Header::new("WWW-Authenticate", "Basic realm=\"helper:Paper\""),
+    )
+}
+
+fn calendar_type() -> ContentType {
+    ContentType::new("text", "calendar").with_params(("charset", "utf-8"))
+}
+
+/// the etag changes with every change to the content of the event
+fn etag(event: &Event) -> String {
+    let mut hasher = DefaultHasher::new();
+    event.uid().hash(&mut hasher);
+    event.headline().hash(&mut hasher);
+    event.description().hash(&mut hasher);
+    event.start().timestamp().hash(&mut hasher);
+    event.stop().timestamp().hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+/// the ctag changes whenever an event of the room changes
+fn ctag(room: &Room) -> String {
+    let mut hasher = DefaultHasher::new();
+    for event in room.events() {
+        etag(event).hash(&mut hasher);
+    }
+    format!("\"{:x}\"", hasher.finish())
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn room_href(room: &Room) -> String {
+    format!("/caldav/{}/", room.name())
+}
+
+fn event_href(room: &Room, event: &Event) -> String {
+    format!("/caldav/{}/{}.ics", room.name(), event.uid())
+}
+
+fn multistatus(responses: String) -> DavResponse {
+    (
+        Status::MultiStatus,
+        (
+            ContentType::XML,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                 <D:multistatus xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:caldav\" \
+                 xmlns:CS=\"http://calendarserver.org/ns/\">{}</D:multistatus>",
+                responses
+            ),
+        ),
+    )
+}
+
+fn propstat(href: &str, props: &str) -> String {
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        xml_escape(href),
+        props
+    )
+}
+
+fn collection_props(room: &Room) -> String {
+    format!(
+        "<D:resourcetype><D:collection/><C:calendar/></D:resourcetype>\
+         <D:displayname>{}</D:displayname>\
+         <C:supported-calendar-component-set><C:comp name=\"VEVENT\"/></C:supported-calendar-component-set>\
+         <CS:getctag>{}</CS:getctag>",
+        xml_escape(room.name()),
+        ctag(room)
+    )
+}
+
+fn event_props(event: &Event, with_data: bool) -> String {
+    let mut props = format!(
+        "<D:resourcetype/><D:getcontenttype>text/calendar; component=vevent</D:getcontenttype>\
+         <D:getetag>{}</D:getetag>",
+        xml_escape(&etag(event))
+    );
+    if with_data {
+        props.push_str(&format!(
+            "<C:calendar-data>{}</C:calendar-data>",
+            xml_escape(&ical::write_calendar("", [event]))
+        ));
+    }
+    props
+}
+
+/// returns the value of the first `attribute="..."` in the xml body
+fn xml_attribute<'a>(body: &'a str, attribute: &str) -> Option<&'a str> {
+    let start = body.find(&format!("{}=\"", attribute))? + attribute.len() + 2;
+    let stop = body[start..].find('"')? + start;
+    Some(&body[start..stop])
+}
+
+/// returns the content of all `<...href>...</...href>` elements of the xml body
+fn xml_hrefs(body: &str) -> Vec<&str> {
+    let mut hrefs = Vec::new();
+    let mut rest = body;
+    while let Some(open) = rest.find("href>") {
+        let after = &rest[open + 5..];
+        let Some(close) = after.find("</") else {
+            break;
+        };
+        let href = after[..close].trim();
+        if !href.is_empty() {
+            hrefs.push(href);
+        }
+        rest = &after[close..];
+        // skip the closing tag, as it also ends with "href>"
+        rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+    }
+    hrefs
+}
+
+/// the resource name without ".ics" is the uid of the event
+fn resource_uid(resource: &str) -> &str {
+    resource.strip_suffix(".ics").unwrap_or(resource)
+}
+
+/// PROPFIND on the root lists all rooms as calendars.
+#[post("/", data = "<body>")]
+async fn propfind_root(
+    method: MethodOverride<'_>,
+    _auth: BasicAuth,
+    body: String,
+    db: Connection<MainDatabase>,
+) -> Option<DavResponse> {
+    if !method.0.eq_ignore_ascii_case("PROPFIND") {
+        return None;
+    }
+    debug_println!("PROPFIND /caldav/ {}", body);
+    let mut responses = propstat(
+        "/caldav/",
+        "<D:resourcetype><D:collection/></D:resourcetype>",
+    );
+    let mut rooms = Room::get_all_from_db(&db).await.ok()?;
+    while rooms.advance().await.ok()? {
+        let mut room: Room = rooms.deserialize_current().ok()?;
+        room.load_all_events(&db).await.ok()?;
+        responses.push_str(&propstat(&room_href(&room), &collection_props(&room)));
+    }
+    Some(multistatus(responses))
+}
+
+/// PROPFIND returns the properties of the calendar and with `Depth: 1` of all events,
+/// REPORT supports calendar-query with a time-range and calendar-multiget.
+#[post("/<room>", data = "<body>")]
+async fn dav(
+    room: &str,
+    method: MethodOverride<'_>,
+    depth: Depth,
+    _auth: BasicAuth,
+    body: String,
+    db: Connection<MainDatabase>,
+) -> Option<DavResponse> {
+    let mut room = Room::getfromdb_name(room, &db).await.ok()?;
+    // the ctag depends on all events anyway
+    room.load_all_events(&db).await.ok()?;
+    if method.0.eq_ignore_ascii_case("PROPFIND") {
+        let mut responses = propstat(&room_href(&room), &collection_props(&room));
+        if depth.0 > 0 {
+            for event in room.events() {
+                responses.push_str(&propstat(
+                    &event_href(&room, event),
+                    &event_props(event, false),
+                ));
+            }
+        }
+        return Some(multistatus(responses));
+    }
+    if !method.0.eq_ignore_ascii_case("REPORT") {
+        return None;
+    }
+    let mut responses = String::new();
+    if body.contains("calendar-multiget") {
+        for href in xml_hrefs(&body) {
+            let Some(resource) = href.rsplit('/').next() else {
+                continue;
+            };
+            if let Some(event) = room.get_event_uid(resource_uid(resource)) {
+                responses.push_str(&propstat(
+                    &event_href(&room, event),
+                    &event_props(event, true),
+                ));
+            }
+        }
+        return Some(multistatus(responses));
+    }
+    let start = xml_attribute(&body, "start").and_then(ical::parse_datetime);
+    let stop = xml_attribute(&body, "end").and_then(ical::parse_datetime);
+    let events: Vec<&Event> = match (start, stop) {
+        (Some(start), Some(stop)) => room.get_event_range(start, stop).await,
+        _ => room.events().collect(),
+    };
+    for event in events {
+        responses.push_str(&propstat(
+            &event_href(&room, event),
+            &event_props(event, true),
+        ));
+    }
+    Some(multistatus(responses))
+}
+
+/// the Depth header of PROPFIND, "infinity" is treated as 1
+pub struct Depth(u8);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Depth {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("Depth") {
+            Some("0") => Outcome::Success(Depth(0)),
+            _ => Outcome::Success(Depth(1)),
+        }
+    }
+}
+
+/// the whole room as one iCalendar file
+#[get("/<room>")]
+async fn get_calendar(
+    room: &str,
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Option<(ContentType, String)> {
+    let mut room = Room::getfromdb_name(room, &db).await.ok()?;
+    room.load_all_events(&db).await.ok()?;
+    Some((
+        calendar_type(),
+        ical::write_calendar(room.name(), room.events()),
+    ))
+}
+
+#[get("/<room>/<resource>")]
+async fn get_event(
+    room: &str,
+    resource: &str,
+    _auth: BasicAuth,
+    db: Connection<MainDatabase>,
+) -> Option<(ContentType, String)> {
+    let mut room = Room::getfromdb_name(room, &db).await.ok()?;
+    room.load_event_uid(resource_uid(resource), &db)
+        .await
+        .ok()?;
+    let event = room.get_event_uid(resource_uid(resource))?;
+    Some((calendar_type(), ical::write_calendar(room.name(), [event])))
+}
+
+/// creates or replaces the event with the uid of the resource name.
+/// Only the first VEVENT of the body is used.
+#[put("/<room>/<resource>", data = "<body>")]
+async fn put_event(
+    room: &str,
+    resource: &str,
+    auth: BasicAuth,
+    body: String,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Status {
+    let user = auth.0;
+    let Ok(mut room) = Room::getfromdb_name(room, &db).await else {
+        return Status::NotFound;
+    };
+    let Some(status) = room.booking_status(&user) else {
+        return Status::Forbidden;
+    };
+    let Some(vevent) = ical::parse_events(&body).into_iter().next() else {
+        return Status::BadRequest;
+    };
+    let summary = vevent.summary.unwrap_or_else(|| String::from("CalDAV"));
+    if !event::is_valid_headline(&summary) {
+        return Status::BadRequest;
+    }
+    let uid = resource_uid(resource);
+    let previous = match room.load_event_uid(uid, &db).await {
+        Ok(()) => room.remove_event_uid(uid),
+        Err(database::Error::NotFound) => None,
+        Err(_) => return Status::InternalServerError,
+    };
+    let event = match &previous {
+        // only the time, the summary and the description are changed,
+        // the booker, the attendees and the link are kept
+        Some(previous) => {
+            if !room.can_approve(&user) && !user.can_act_for_id(previous.booker_id(), &db).await {
+                return Status::Forbidden;
+            }
+            let Some(mut event) = previous.clone().with_times(vevent.start, vevent.stop) else {
+                return Status::BadRequest;
+            };
+            event.set_headline(summary);
+            event.set_description(vevent.description);
+            event
+        }
+        None => {
+            let Some(event) = Event::create(
+                user.id().to_string(),
+                summary,
+                vevent.description,
+                vevent.start,
+                vevent.stop,
+            ) else {
+                return Status::BadRequest;
+            };
+            event.with_uid(uid.to_string())
+        }
+    };
+    let event = event.with_status(status);
+    let Ok(mut context) = BookingContext::load(&room, event.booker_id(), defaults, &db).await
+    else {
+        return Status::InternalServerError;
+    };
+    // the changed event replaces the previous one, which must not count against the quotas
+    context.booker_events.retain(|booked| booked.uid() != uid);
+    let booked = if previous.is_some() {
+        room.move_event(event, &context, &db).await
+    } else {
+        room.book_event(event, &context, &db).await
+    };
+    match booked {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            debug_println!("could not add event: {}", err);
+            return Status::Conflict;
+        }
+        // changed by someone else in the meantime
+        Err(database::Error::Conflict) => return Status::PreconditionFailed,
+        Err(_) => return Status::InternalServerError,
+    }
+    if previous.is_some() {
+        Status::NoContent
+    } else {
+        Status::Created
+    }
+}
+
+#[delete("/<room>/<resource>")]
+async fn delete_event(
+    room: &str,
+    resource: &str,
+    auth: BasicAuth,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Status {
+    let Ok(mut room) = Room::getfromdb_name(room, &db).await else {
+        return Status::NotFound;
+    };
+    if room
+        .load_event_uid(resource_uid(resource), &db)
+        .await
+        .is_err()
+    {
+        return Status::NotFound;
+    }
+    let cancellation = Cancellation {
+        by: auth.0.id().to_hex(),
+        at: Local::now(),
+        reason: None,
+    };
+    let Some(cancelled) = room.cancel_event_uid(resource_uid(resource), cancellation) else {
+        return Status::NotFound;
+    };
+    match room::cancel(&auth.0, &mut room, &cancelled, defaults, &db).await {
+        Ok(()) => Status::NoContent,
+        // changed by someone else in the meantime
+        Err(Error::Database(database::Error::Conflict)) => Status::PreconditionFailed,
+        Err(err) => err.status(),
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        propfind_root,
+        dav,
+        get_calendar,
+        get_event,
+        put_event,
+        delete_event
+    ]
+}
+
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![unauthorized]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multiget_hrefs() {
+        let body = "<C:calendar-multiget xmlns:D=\"DAV:\"><D:prop><D:getetag/></D:prop>\
+                    <D:href>/caldav/A101/abc.ics</D:href><D:href>/caldav/A101/def.ics</D:href>\
+                    </C:calendar-multiget>";
+        assert_eq!(
+            xml_hrefs(body),
+            vec!["/caldav/A101/abc.ics", "/caldav/A101/def.ics"]
+        );
+    }
+    #[test]
+    fn parse_time_range() {
+        let body = "<C:time-range start=\"20230101T000000Z\" end=\"20230201T000000Z\"/>";
+        assert_eq!(xml_attribute(body, "start"), Some("20230101T000000Z"));
+        assert_eq!(xml_attribute(body, "end"), Some("20230201T000000Z"));
+    }
+}
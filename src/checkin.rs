@@ -0,0 +1,120 @@
+//! checkin.rs - confirming on site that a booked event takes place
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! rooms with `release_after_minutes` release events nobody checked in to,
+//! see [crate::tasks]. Every released event is stored as [NoShow] for reporting.
+use crate::database::{Connection, DatabaseConnection, DatabaseUtils};
+use crate::error::Error;
+use crate::event::Event;
+use crate::input::Input;
+use crate::room::{find_room, Room, CHECK_IN_EARLY_MINUTES};
+use crate::{user, MainDatabase};
+use bson::doc;
+use bson::oid::ObjectId;
+use chrono::{DateTime, Duration, Local};
+use rocket::serde::json::Json;
+use rocket::Route;
+use serde::*;
+
+/// an event that was released, as nobody checked in
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoShow {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    room_id: ObjectId,
+    room_name: String,
+    released: DateTime<Local>,
+    event: Event,
+}
+impl NoShow {
+    pub fn create(room: &Room, event: Event, released: DateTime<Local>) -> Self {
+        NoShow {
+            id: ObjectId::new(),
+            room_id: room.id(),
+            room_name: String::from(room.name()),
+            released,
+            event,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct CheckInForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    /// the event to check in to, the current one of the room if not set.
+    /// A QR code on the door only needs to contain the room
+    uid: Option<String>,
+}
+/// checks in to the event that currently runs in the room or starts within
+/// [crate::room::CHECK_IN_EARLY_MINUTES]. Used by the kiosk of the room
+/// or by scanning its QR code, so every logged in user can check in.
+#[post("/", data = "<form>")]
+async fn check_in(form: Input<CheckInForm<'_>>, db: Connection<MainDatabase>) -> Result<(), Error> {
+    form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    let now = Local::now();
+    room.load_events(
+        now,
+        now + Duration::minutes(CHECK_IN_EARLY_MINUTES),
+        false,
+        &db,
+    )
+    .await?;
+    let current = room
+        .current_event(now)
+        .ok_or(Error::NotFound("no event takes place in the room now"))?
+        .uid();
+    // only the current event can be checked in to
+    if let Some(uid) = &form.uid && *uid != current {
+        return Err(Error::Conflict(String::from(
+            "the event does not take place now",
+        )));
+    }
+    room.update_event(&current, &db, |event| {
+        event.check_in(now);
+        Some(())
+    })
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct NoShowForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+}
+/// lists the released events of a room as json, newest first.
+/// Only users who can approve bookings of the room can see them
+#[post("/no_shows", data = "<form>")]
+async fn no_shows(
+    form: Input<NoShowForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<NoShow>>, Error> {
+    let user = form.userdata.login(&db).await?;
+    let room = find_room(&form.room_id, &form.room_name, &db).await?;
+    if !room.can_approve(&user) {
+        return Err(Error::Forbidden("you cannot approve bookings of the room"));
+    }
+    let options = mongodb::options::FindOptions::builder()
+        .sort(doc! {"released": -1})
+        .build();
+    let mut cursor = db
+        .no_show_collection()
+        .find(doc! {"room_id": room.id()}, options)
+        .await?;
+    let mut no_shows = Vec::new();
+    while cursor.advance().await? {
+        no_shows.push(cursor.deserialize_current()?);
+    }
+    Ok(Json(no_shows))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![check_in, no_shows]
+}
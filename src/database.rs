@@ -1,53 +1,448 @@
-//! database.rs - Rocket hook for the MongoDB database
+//! database.rs - Rocket hook for the database, either MongoDB or in memory
 //!
 //! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
 //!
 //! all items that are in the database can be addressed either by name or
 //! by id with [DatabaseConnection::getfromdb_name] or [DatabaseConnection::getfromdb_id] respectivly
 
+use crate::booking::{HolidayCalendar, RoomGroup};
+use crate::checkin::NoShow;
 use crate::debug_println;
+use crate::event::StoredEvent;
+use crate::memory::{self, MemoryStore};
+use crate::migration::AppliedMigration;
+use crate::notification::Notification;
 use crate::room::Room;
+use crate::tenant;
 use crate::user::User;
+use crate::waitlist::WaitlistEntry;
 use bson::oid::ObjectId;
+use bson::{Bson, Document};
+use mongodb::options::{FindOptions, UpdateOptions};
 use mongodb::{bson::doc, options::IndexOptions, IndexModel};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
 use rocket::{fairing, Build, Rocket};
-pub use rocket_db_pools::Connection;
 use rocket_db_pools::{mongodb, Database};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
 
 /// a default [Database] created using the database macro
 #[derive(Database)]
 #[database("main_db")]
 pub struct MainDatabase(mongodb::Client);
 
-/// trait which is only implemented by [Client][mongodb::Client] and provides get functions
-/// for the [mongodb::Database] and the different collections
+/// the database used if `database_name` is not configured
+pub const DEFAULT_DATABASE: &str = "helper:Paper";
+
+/// where the documents are stored, selected with the `storage` config key,
+/// and the name of the database. It is managed by [attach_storage] and handed
+/// to the routes as [Connection], with the database of the tenant of the request
+#[derive(Clone)]
+pub enum Storage {
+    MongoDB(mongodb::Client, String),
+    Memory(Arc<MemoryStore>, String),
+}
+impl Storage {
+    /// an empty storage that only lives as long as the server, e.g. for the tests
+    pub fn memory() -> Self {
+        Storage::Memory(Arc::default(), String::from(DEFAULT_DATABASE))
+    }
+    /// the same storage with another database, e.g. the one of a [tenant]
+    pub fn with_database(&self, name: &str) -> Self {
+        match self {
+            Storage::MongoDB(client, _) => Storage::MongoDB(client.clone(), String::from(name)),
+            Storage::Memory(store, _) => Storage::Memory(store.clone(), String::from(name)),
+        }
+    }
+    pub fn database_name(&self) -> &str {
+        match self {
+            Storage::MongoDB(_, name) | Storage::Memory(_, name) => name,
+        }
+    }
+}
+
+/// the value of the `storage` config key
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    MongoDB,
+    Memory,
+}
+impl StorageKind {
+    pub fn from_config(rocket: &Rocket<Build>) -> Result<Self, rocket::figment::Error> {
+        match rocket.figment().extract_inner::<StorageKind>("storage") {
+            Ok(kind) => Ok(kind),
+            Err(err) if err.missing() => Ok(StorageKind::default()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// A fairing that manages the [Storage] selected in the config with the
+/// database from the `database_name` config key.
+/// For "mongodb" the [MainDatabase] has to be attached before
+pub async fn attach_storage(rocket: Rocket<Build>) -> fairing::Result {
+    let name = match rocket.figment().extract_inner::<String>("database_name") {
+        Ok(name) => name,
+        Err(err) if err.missing() => String::from(DEFAULT_DATABASE),
+        Err(err) => {
+            println!("invalid database name: {}", err);
+            return Err(rocket);
+        }
+    };
+    let storage = match StorageKind::from_config(&rocket) {
+        Ok(StorageKind::Memory) => Storage::memory().with_database(&name),
+        Ok(StorageKind::MongoDB) => match MainDatabase::fetch(&rocket) {
+            Some(db) => Storage::MongoDB(db.0.clone(), name),
+            None => return Err(rocket),
+        },
+        Err(err) => {
+            println!("invalid storage config: {}", err);
+            return Err(rocket);
+        }
+    };
+    Ok(rocket.manage(storage))
+}
+
+/// request guard for the [Storage] of the tenant of the request, used like
+/// the connection of rocket_db_pools, e.g. `db: Connection<MainDatabase>`
+pub struct Connection<D> {
+    storage: Storage,
+    tenant: Option<String>,
+    marker: PhantomData<fn() -> D>,
+}
+impl<D> Connection<D> {
+    /// the name of the tenant, None if the server has no tenants
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, D: 'static> FromRequest<'r> for Connection<D> {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match tenant::storage_for(request) {
+            Ok((storage, tenant)) => Outcome::Success(Connection {
+                storage,
+                tenant,
+                marker: PhantomData,
+            }),
+            Err(failure) => Outcome::Failure(failure),
+        }
+    }
+}
+impl<D> Deref for Connection<D> {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+/// trait which is only implemented by [Storage] and provides get functions
+/// for the different collections
 pub trait DatabaseUtils {
-    fn db(&self) -> mongodb::Database;
+    fn get_collection<T>(&self, name: &str) -> Collection<T>;
 
-    fn user_collection(&self) -> mongodb::Collection<User> {
-        self.db().collection::<User>("users")
+    fn user_collection(&self) -> Collection<User> {
+        self.get_collection::<User>("users")
     }
 
-    fn room_collection(&self) -> mongodb::Collection<Room> {
-        self.db().collection::<Room>("rooms")
+    fn room_collection(&self) -> Collection<Room> {
+        self.get_collection::<Room>("rooms")
     }
 
-    fn epaper_collection(&self) -> mongodb::Collection<Room> {
-        self.db().collection::<Room>("epapers")
+    fn epaper_collection(&self) -> Collection<Room> {
+        self.get_collection::<Room>("epapers")
     }
 
-    fn layout_collection(&self) -> mongodb::Collection<Room> {
-        self.db().collection::<Room>("layouts")
+    fn layout_collection(&self) -> Collection<Room> {
+        self.get_collection::<Room>("layouts")
+    }
+
+    fn holiday_calendar_collection(&self) -> Collection<HolidayCalendar> {
+        self.get_collection::<HolidayCalendar>("holiday_calendars")
+    }
+
+    fn room_group_collection(&self) -> Collection<RoomGroup> {
+        self.get_collection::<RoomGroup>("room_groups")
+    }
+
+    fn notification_collection(&self) -> Collection<Notification> {
+        self.get_collection::<Notification>("notifications")
+    }
+
+    fn no_show_collection(&self) -> Collection<NoShow> {
+        self.get_collection::<NoShow>("no_shows")
+    }
+
+    fn event_collection(&self) -> Collection<StoredEvent> {
+        self.get_collection::<StoredEvent>("events")
+    }
+
+    fn waitlist_collection(&self) -> Collection<WaitlistEntry> {
+        self.get_collection::<WaitlistEntry>("waitlist")
+    }
+
+    fn migration_collection(&self) -> Collection<AppliedMigration> {
+        self.get_collection::<AppliedMigration>("migrations")
+    }
+}
+
+impl DatabaseUtils for Storage {
+    fn get_collection<T>(&self, name: &str) -> Collection<T> {
+        match self {
+            Storage::MongoDB(client, database) => {
+                Collection::MongoDB(client.database(database).collection::<T>(name))
+            }
+            Storage::Memory(store, database) => Collection::Memory(
+                store.collection(&format!("{}.{}", database, name)),
+                PhantomData,
+            ),
+        }
+    }
+}
+
+/// a collection of either storage. The functions take the same arguments as the
+/// ones of [mongodb::Collection], the in-memory storage only uses the sort of the
+/// [FindOptions] and the upsert of the [UpdateOptions]
+pub enum Collection<T> {
+    MongoDB(mongodb::Collection<T>),
+    Memory(memory::Collection, PhantomData<fn() -> T>),
+}
+
+/// the result of an update, like [mongodb::results::UpdateResult]
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateResult {
+    pub matched_count: u64,
+    pub modified_count: u64,
+}
+
+/// the result of a deletion, like [mongodb::results::DeleteResult]
+#[derive(Debug, Clone, Copy)]
+pub struct DeleteResult {
+    pub deleted_count: u64,
+}
+
+impl<T> Collection<T> {
+    pub async fn update_one(
+        &self,
+        filter: Document,
+        update: Document,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> mongodb::error::Result<UpdateResult> {
+        self.update(filter, update, options.into(), false).await
+    }
+    pub async fn update_many(
+        &self,
+        filter: Document,
+        update: Document,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> mongodb::error::Result<UpdateResult> {
+        self.update(filter, update, options.into(), true).await
+    }
+    async fn update(
+        &self,
+        filter: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+        many: bool,
+    ) -> mongodb::error::Result<UpdateResult> {
+        match self {
+            Collection::MongoDB(collection) => {
+                let result = if many {
+                    collection.update_many(filter, update, options).await?
+                } else {
+                    collection.update_one(filter, update, options).await?
+                };
+                Ok(UpdateResult {
+                    matched_count: result.matched_count,
+                    modified_count: result.modified_count,
+                })
+            }
+            Collection::Memory(collection, _) => {
+                let upsert = options.and_then(|options| options.upsert).unwrap_or(false);
+                let count = collection.update(&filter, &update, many, upsert);
+                Ok(UpdateResult {
+                    matched_count: count.matched,
+                    modified_count: count.modified,
+                })
+            }
+        }
+    }
+    pub async fn delete_one(
+        &self,
+        filter: Document,
+        options: impl Into<Option<mongodb::options::DeleteOptions>>,
+    ) -> mongodb::error::Result<DeleteResult> {
+        let deleted_count = match self {
+            Collection::MongoDB(collection) => {
+                collection.delete_one(filter, options).await?.deleted_count
+            }
+            Collection::Memory(collection, _) => collection.delete(&filter, false),
+        };
+        Ok(DeleteResult { deleted_count })
+    }
+    pub async fn delete_many(
+        &self,
+        filter: Document,
+        options: impl Into<Option<mongodb::options::DeleteOptions>>,
+    ) -> mongodb::error::Result<DeleteResult> {
+        let deleted_count = match self {
+            Collection::MongoDB(collection) => {
+                collection.delete_many(filter, options).await?.deleted_count
+            }
+            Collection::Memory(collection, _) => collection.delete(&filter, true),
+        };
+        Ok(DeleteResult { deleted_count })
+    }
+    pub async fn count_documents(
+        &self,
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<mongodb::options::CountOptions>>,
+    ) -> mongodb::error::Result<u64> {
+        match self {
+            Collection::MongoDB(collection) => collection.count_documents(filter, options).await,
+            Collection::Memory(collection, _) => Ok(collection
+                .find(&filter.into().unwrap_or_default(), None)
+                .len() as u64),
+        }
+    }
+    pub async fn distinct(
+        &self,
+        field_name: &str,
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<mongodb::options::DistinctOptions>>,
+    ) -> mongodb::error::Result<Vec<Bson>> {
+        match self {
+            Collection::MongoDB(collection) => {
+                collection.distinct(field_name, filter, options).await
+            }
+            Collection::Memory(collection, _) => {
+                Ok(collection.distinct(field_name, &filter.into().unwrap_or_default()))
+            }
+        }
+    }
+    pub async fn create_index(
+        &self,
+        index: IndexModel,
+        options: impl Into<Option<mongodb::options::CreateIndexOptions>>,
+    ) -> mongodb::error::Result<()> {
+        if let Collection::MongoDB(collection) = self {
+            collection.create_index(index, options).await?;
+        }
+        Ok(())
+    }
+    pub async fn create_indexes(
+        &self,
+        indexes: impl IntoIterator<Item = IndexModel>,
+        options: impl Into<Option<mongodb::options::CreateIndexOptions>>,
+    ) -> mongodb::error::Result<()> {
+        if let Collection::MongoDB(collection) = self {
+            collection.create_indexes(indexes, options).await?;
+        }
+        Ok(())
+    }
+}
+impl<T> Collection<T>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    pub async fn find(
+        &self,
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<FindOptions>>,
+    ) -> mongodb::error::Result<Cursor<T>> {
+        match self {
+            Collection::MongoDB(collection) => {
+                Ok(Cursor::MongoDB(collection.find(filter, options).await?))
+            }
+            Collection::Memory(collection, _) => {
+                let sort = options.into().and_then(|options| options.sort);
+                let found = collection.find(&filter.into().unwrap_or_default(), sort.as_ref());
+                Ok(Cursor::Memory(found.into(), None, PhantomData))
+            }
+        }
+    }
+    pub async fn find_one(
+        &self,
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<mongodb::options::FindOneOptions>>,
+    ) -> mongodb::error::Result<Option<T>> {
+        match self {
+            Collection::MongoDB(collection) => collection.find_one(filter, options).await,
+            Collection::Memory(collection, _) => {
+                match collection
+                    .find(&filter.into().unwrap_or_default(), None)
+                    .into_iter()
+                    .next()
+                {
+                    Some(document) => Ok(Some(bson::from_document(document)?)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+}
+impl<T: Serialize> Collection<T> {
+    pub async fn insert_one(
+        &self,
+        item: impl Borrow<T>,
+        options: impl Into<Option<mongodb::options::InsertOneOptions>>,
+    ) -> mongodb::error::Result<()> {
+        match self {
+            Collection::MongoDB(collection) => {
+                collection.insert_one(item, options).await?;
+            }
+            Collection::Memory(collection, _) => {
+                collection.insert(bson::to_document(item.borrow())?);
+            }
+        }
+        Ok(())
     }
 }
 
-impl DatabaseUtils for mongodb::Client {
-    fn db(&self) -> mongodb::Database {
-        self.database("helper:Paper")
+/// the documents found by [Collection::find]
+pub enum Cursor<T> {
+    MongoDB(mongodb::Cursor<T>),
+    Memory(VecDeque<Document>, Option<Document>, PhantomData<fn() -> T>),
+}
+impl<T> Cursor<T>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    /// moves to the next document, returns false if there is none
+    pub async fn advance(&mut self) -> mongodb::error::Result<bool> {
+        match self {
+            Cursor::MongoDB(cursor) => cursor.advance().await,
+            Cursor::Memory(documents, current, _) => {
+                *current = documents.pop_front();
+                Ok(current.is_some())
+            }
+        }
+    }
+    /// the current document, can only be called after [Cursor::advance] returned true
+    pub fn deserialize_current(&self) -> mongodb::error::Result<T> {
+        match self {
+            Cursor::MongoDB(cursor) => cursor.deserialize_current(),
+            Cursor::Memory(_, current, _) => {
+                let document = current.clone().unwrap_or_default();
+                Ok(bson::from_document(document)?)
+            }
+        }
     }
 }
 
-/// used by Structs which can be inserted into [MainDatabase]
+/// used by Structs which can be inserted into the [Storage]
 pub trait DatabaseConnection
 where
     Self: serde::Serialize + Sized + serde::de::DeserializeOwned + Unpin + Sync + std::marker::Send,
@@ -55,9 +450,13 @@ where
     /// get function for ObjectId ($_id)
     fn id(&self) -> ObjectId;
 
+    /// the version of the stored document, incremented by every [DatabaseConnection::update]
+    fn version(&self) -> i64;
+    fn set_version(&mut self, version: i64);
+
     /// returns the collection of the struct
     /// Self::collection is the same as db.<Self>_collection
-    fn collection(db: &mongodb::Client) -> mongodb::Collection<Self>;
+    fn collection(db: &Storage) -> Collection<Self>;
 
     /// get function for the Name, which is indexed
     fn name(&self) -> &str;
@@ -66,26 +465,83 @@ where
     /// e.g. "username" for [User] or "name" for [Room]
     fn index_name() -> &'static str;
 
-    /// updates the content of the Database
-    /// If the object is not updated, it will throw the [Error::NoUpdate] Error
-    /// as it updates the whole document, it is not efficient to use this function
-    /// TODO: implement an update-specific function
-    async fn update(&mut self, db: &mongodb::Client) -> Result<(), Error> {
-        let filter = doc! {"_id": self.id()};
-        let update_doc = doc! {"$set": bson::to_document(self)?};
+    /// updates the content of the Database, if it was not changed since self was loaded.
+    /// Otherwise it throws the [Error::Conflict] Error and self needs to be loaded again,
+    /// see [DatabaseConnection::modify].
+    /// If the object is not in the database, it will throw the [Error::NotFound] Error
+    /// as it updates the whole document, it is not efficient to use this function,
+    /// [DatabaseConnection::update_fields] only writes the changed fields
+    async fn update(&mut self, db: &Storage) -> Result<(), Error> {
+        let version = self.version();
+        let filter = doc! {"_id": self.id(), "version": version_filter(version)};
+        self.set_version(version + 1);
+        let update_doc = match bson::to_document(self) {
+            Ok(document) => doc! {"$set": document},
+            Err(err) => {
+                self.set_version(version);
+                return Err(err.into());
+            }
+        };
         let update_result = Self::collection(&db)
             .update_one(filter, update_doc, None)
+            .await;
+        match update_result {
+            Ok(result) if result.matched_count > 0 => Ok(()),
+            Ok(_) => {
+                self.set_version(version);
+                if Self::getfromdb_id(&self.id(), db).await.is_ok() {
+                    Err(Error::Conflict)
+                } else {
+                    Err(Error::NotFound)
+                }
+            }
+            Err(err) => {
+                self.set_version(version);
+                Err(err.into())
+            }
+        }
+    }
+    /// loads the item, applies the change and updates it. If the item was changed
+    /// in between, it is loaded again and the change is applied to the new version,
+    /// at most [UPDATE_RETRIES] times. Returns `Ok(None)` if the change returns None,
+    /// e.g. because the user lacks a permission, without writing anything.
+    async fn modify<T>(
+        id: &ObjectId,
+        db: &Storage,
+        mut change: impl FnMut(&mut Self) -> Option<T> + Send,
+    ) -> Result<Option<T>, Error> {
+        for _ in 0..UPDATE_RETRIES {
+            let mut item = Self::getfromdb_id(id, db).await?;
+            let Some(ret) = change(&mut item) else {
+                return Ok(None);
+            };
+            match item.update(db).await {
+                Ok(()) => return Ok(Some(ret)),
+                Err(Error::Conflict) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Conflict)
+    }
+    /// writes only the fields of the update and increments the version, so changes
+    /// to other fields made in the meantime are kept. An update that does not change
+    /// anything is not an error, only a missing item throws [Error::NotFound]
+    async fn update_fields(id: &ObjectId, update: Update<Self>, db: &Storage) -> Result<(), Error> {
+        if update.is_empty() {
+            return Self::getfromdb_id(id, db).await.map(|_| ());
+        }
+        let result = Self::collection(&db)
+            .update_one(doc! {"_id": id}, update.into_document(), None)
             .await?;
-        if update_result.modified_count > 0 {
-            Ok(())
-        } else {
-            Err(Error::NoUpdate)
+        if result.matched_count == 0 {
+            return Err(Error::NotFound);
         }
+        Ok(())
     }
     /// Inserts [self] into the Database.
     /// If there is already an object with the name in the Database, it throws the
     /// [Error::AlreadyInDB] Error
-    async fn insert(&self, db: &mongodb::Client) -> Result<(), Error> {
+    async fn insert(&self, db: &Storage) -> Result<(), Error> {
         if self.isindb(db).await {
             return Err(Error::AlreadyInDB);
         }
@@ -95,7 +551,7 @@ where
     /// retrieves the item with given id from the Database.
     /// returns [Error::NotFound] if the item is not in the Database.
     /// Note that some structs have own get methods, as [User] with login.
-    async fn getfromdb_id(id: &ObjectId, db: &mongodb::Client) -> Result<Self, Error> {
+    async fn getfromdb_id(id: &ObjectId, db: &Storage) -> Result<Self, Error> {
         if let Some(_self) = Self::collection(&db)
             .find_one(doc! {"_id": id}, None)
             .await?
@@ -108,7 +564,7 @@ where
     /// retrieves the item with given name from the Database.
     /// returns [Error::NotFound] if the item is not in the Database.
     /// Note that some structs have own get methods, as [User] with login.
-    async fn getfromdb_name(name: &str, db: &mongodb::Client) -> Result<Self, Error> {
+    async fn getfromdb_name(name: &str, db: &Storage) -> Result<Self, Error> {
         if let Some(_self) = Self::collection(&db)
             .find_one(doc! {Self::index_name(): name}, None)
             .await?
@@ -119,14 +575,14 @@ where
         }
     }
     /// Returns a cursor to all items found in the Database.
-    async fn get_all_from_db(db: &mongodb::Client) -> Option<mongodb::Cursor<Self>> {
-        Self::collection(&db).find(None, None).await.ok()
+    async fn get_all_from_db(db: &Storage) -> Result<Cursor<Self>, Error> {
+        Ok(Self::collection(&db).find(None, None).await?)
     }
     /// looks up if the item is already in the database.
     /// It is considered to be in the database if:
     ///     1. an item with the name is in the database
     ///     2. an item with the id is in the database
-    async fn isindb(&self, db: &mongodb::Client) -> bool {
+    async fn isindb(&self, db: &Storage) -> bool {
         if Self::getfromdb_name(&self.name(), &db).await.is_ok() {
             return true;
         }
@@ -137,16 +593,134 @@ where
     }
 }
 
+/// a field of the documents of T with values of type V, used for an [Update].
+/// The fields are declared as constants of T, e.g. [User::DELEGATES]
+pub struct Field<T, V> {
+    name: &'static str,
+    marker: PhantomData<fn() -> (T, V)>,
+}
+impl<T, V> Field<T, V> {
+    pub const fn new(name: &'static str) -> Self {
+        Field {
+            name,
+            marker: PhantomData,
+        }
+    }
+}
+// derived implementations would require T and V to be Clone
+impl<T, V> Clone for Field<T, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T, V> Copy for Field<T, V> {}
+
+/// a partial update of a document of T, see [DatabaseConnection::update_fields].
+/// e.g. `Update::new().set(User::EMAIL, &email)?.add_to_set(User::DELEGATES, &id)?`
+pub struct Update<T> {
+    set: bson::Document,
+    push: bson::Document,
+    add_to_set: bson::Document,
+    pull: bson::Document,
+    marker: PhantomData<fn() -> T>,
+}
+impl<T> Update<T> {
+    pub fn new() -> Self {
+        Update {
+            set: bson::Document::new(),
+            push: bson::Document::new(),
+            add_to_set: bson::Document::new(),
+            pull: bson::Document::new(),
+            marker: PhantomData,
+        }
+    }
+    /// replaces the value of the field
+    pub fn set<V: serde::Serialize>(
+        mut self,
+        field: Field<T, V>,
+        value: &V,
+    ) -> Result<Self, Error> {
+        self.set.insert(field.name, bson::to_bson(value)?);
+        Ok(self)
+    }
+    /// appends the value to the array
+    pub fn push<V: serde::Serialize>(
+        mut self,
+        field: Field<T, Vec<V>>,
+        value: &V,
+    ) -> Result<Self, Error> {
+        self.push.insert(field.name, bson::to_bson(value)?);
+        Ok(self)
+    }
+    /// appends the value to the array, if it is not in it yet
+    pub fn add_to_set<V: serde::Serialize>(
+        mut self,
+        field: Field<T, Vec<V>>,
+        value: &V,
+    ) -> Result<Self, Error> {
+        self.add_to_set.insert(field.name, bson::to_bson(value)?);
+        Ok(self)
+    }
+    /// removes all occurrences of the value from the array
+    pub fn pull<V: serde::Serialize>(
+        mut self,
+        field: Field<T, Vec<V>>,
+        value: &V,
+    ) -> Result<Self, Error> {
+        self.pull.insert(field.name, bson::to_bson(value)?);
+        Ok(self)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+            && self.push.is_empty()
+            && self.add_to_set.is_empty()
+            && self.pull.is_empty()
+    }
+    /// the update document, which also increments the version,
+    /// so that [DatabaseConnection::update] notices the change
+    fn into_document(self) -> bson::Document {
+        let mut document = doc! {"$inc": {"version": 1_i64}};
+        for (operator, fields) in [
+            ("$set", self.set),
+            ("$push", self.push),
+            ("$addToSet", self.add_to_set),
+            ("$pull", self.pull),
+        ] {
+            if !fields.is_empty() {
+                document.insert(operator, fields);
+            }
+        }
+        document
+    }
+}
+impl<T> Default for Update<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// how often a change is applied again after a [Error::Conflict]
+pub const UPDATE_RETRIES: usize = 3;
+
+/// matches the documents with the version. Documents stored before
+/// the version was introduced do not have one, they count as version 0
+pub fn version_filter(version: i64) -> bson::Bson {
+    if version == 0 {
+        bson::Bson::Document(doc! {"$in": [0_i64, bson::Bson::Null]})
+    } else {
+        bson::Bson::Int64(version)
+    }
+}
+
 /// a new enum of all errors that can happen while interacting with the database
-/// [Error::NoUpdate] is also seen as an Error, as calling the update method on e.g. [crate::user::User]
-/// should always be done after insert.
+/// [Error::Conflict] is thrown if the document was changed by someone else in the meantime.
 #[derive(Debug)]
 pub enum Error {
     MongoDB(mongodb::error::Error),
     Bson(bson::ser::Error),
-    NoUpdate,
     NotFound,
     AlreadyInDB,
+    Conflict,
 }
 impl From<mongodb::error::Error> for Error {
     fn from(error: mongodb::error::Error) -> Self {
@@ -159,7 +733,7 @@ impl From<bson::ser::Error> for Error {
     }
 }
 
-/// takes in the [mongodb::Collection] as an argument,
+/// takes in the [Collection] as an argument,
 /// and the field name to create an index.
 /// note that this does not override any existing index,
 /// meaning that this will not fix broken indices.
@@ -181,22 +755,69 @@ macro_rules! create_unique_index {
         }
     };
 }
-/// A fairing creates the indices that map the name field of every Struct in the database.
+/// A fairing creates the indices that map the name field of every Struct in the database
+/// of every tenant.
 pub async fn create_indices(rocket: Rocket<Build>) -> fairing::Result {
     
     
     debug_println!("building indices");
-    if let Some(db) = MainDatabase::fetch(&rocket) {
-        if create_unique_index!(db.0.user_collection(), "username").is_err()
-            || create_unique_index!(db.0.room_collection(), "name").is_err()
-            || create_unique_index!(db.0.layout_collection(), "name").is_err()
-            || create_unique_index!(db.0.epaper_collection(), "name").is_err()
+    let storages = tenant::storages(&rocket);
+    if storages.is_empty() {
+        return Err(rocket);
+    }
+    for db in &storages {
+        if create_unique_index!(db.user_collection(), "username").is_err()
+            || create_unique_index!(db.room_collection(), "name").is_err()
+            || create_unique_index!(db.layout_collection(), "name").is_err()
+            || create_unique_index!(db.epaper_collection(), "name").is_err()
+            || create_unique_index!(db.holiday_calendar_collection(), "name").is_err()
+            || create_unique_index!(db.room_group_collection(), "name").is_err()
+            || create_event_indices(db).await.is_err()
         {
             //return Ok(rocket);
             return Err(rocket);
         }
-        return Ok(rocket);
-    } else {
-        Err(rocket)
+    }
+    Ok(rocket)
+}
+
+/// the events are looked up by their time range and by their uid
+async fn create_event_indices(db: &Storage) -> Result<(), Error> {
+    let indices = [
+        IndexModel::builder()
+            .keys(doc! {"room_id": 1, "start": 1, "stop": 1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"room_id": 1, "event.uid": 1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"event.booker_id": 1})
+            .build(),
+    ];
+    db.event_collection().create_indexes(indices, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_document() {
+        let delegate = ObjectId::new();
+        let update = Update::new()
+            .set(User::EMAIL, &Some(String::from("a@b.de")))
+            .unwrap()
+            .add_to_set(User::DELEGATES, &delegate)
+            .unwrap();
+        assert_eq!(
+            update.into_document(),
+            doc! {
+                "$inc": {"version": 1_i64},
+                "$set": {"email": "a@b.de"},
+                "$addToSet": {"delegates": delegate},
+            }
+        );
+        assert!(Update::<User>::new().is_empty());
     }
 }
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 
+use crate::database::{Connection, DatabaseConnection};
+use crate::error::Error;
+use crate::input::Input;
+use crate::room::Room;
 use crate::user;
 use crate::MainDatabase;
 use bson::oid::ObjectId;
-use rocket::form::Form;
-//use crate::room::Room;
-use rocket_db_pools::Connection;
-//use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, Timelike};
+use rocket::serde::json::Json;
 use serde::{Deserialize, Serialize};
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Epaper {
@@ -20,17 +22,77 @@ pub struct Epaper {
 }
 
 #[post("/get", data = "<form>")]
-async fn get(form: Form<user::UserData<'_>>, db: Connection<MainDatabase>) -> Option<String> {
-    let _user = &user::User::login(form.username, String::from(form.password), &*db)
-        .await
-        .ok()?;
+async fn get(
+    form: Input<user::UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<HashMap<&'static str, &'static str>>, Error> {
+    form.login(&db).await?;
     println!("user");
     let mut map = HashMap::new();
     map.insert("neues Epaper", "http://192.168.43.36:8000");
     map.insert("altes Epaper", "http://192.168.43.249:8000");
     println!("output");
-    Some(rocket::serde::json::to_string(&map).ok()?)
+    Ok(Json(map))
+}
+/// an event as shown on the display, blocked_from and blocked_until
+/// include the setup and teardown time of the room
+#[derive(Debug, Serialize)]
+struct ScheduleEntry {
+    headline: String,
+    start: DateTime<Local>,
+    stop: DateTime<Local>,
+    blocked_from: DateTime<Local>,
+    blocked_until: DateTime<Local>,
+    /// the kiosk shows a check in button while this is not set
+    checked_in: Option<DateTime<Local>>,
+}
+#[derive(Debug, Serialize)]
+struct Schedule {
+    room: String,
+    setup_minutes: i64,
+    teardown_minutes: i64,
+    events: Vec<ScheduleEntry>,
 }
+/// the events of the current day of a room, as shown on its display
+#[post("/schedule?<room>", data = "<form>")]
+async fn schedule(
+    room: &str,
+    form: Input<user::UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Schedule>, Error> {
+    form.login(&db).await?;
+    let mut room = Room::getfromdb_name(room, &db).await?;
+    let start = Local::now()
+        .with_hour(0)
+        .and_then(|start| start.with_minute(0))
+        .and_then(|start| start.with_second(0))
+        .and_then(|start| start.with_nanosecond(0))
+        .ok_or_else(|| Error::Internal(String::from("the day has no midnight")))?;
+    let stop = start
+        .checked_add_days(chrono::Days::new(1))
+        .ok_or_else(|| Error::Internal(String::from("the day is out of range")))?;
+    room.load_events(start, stop, false, &db).await?;
+    let events = room
+        .get_event_range(start, stop)
+        .await
+        .into_iter()
+        .map(|event| ScheduleEntry {
+            headline: event.headline().to_string(),
+            start: event.start(),
+            stop: event.stop(),
+            blocked_from: event.start() - room.setup(),
+            blocked_until: event.stop() + room.teardown(),
+            checked_in: event.checked_in(),
+        })
+        .collect();
+    Ok(Json(Schedule {
+        room: room.name().to_string(),
+        setup_minutes: room.setup().num_minutes(),
+        teardown_minutes: room.teardown().num_minutes(),
+        events,
+    }))
+}
+
 pub fn routes() -> Vec<rocket::Route> {
-    routes![get]
+    routes![get, schedule]
 }
@@ -0,0 +1,297 @@
+//! error.rs - the errors the routes respond with
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! every error is sent with its status code and a json body containing a
+//! machine readable code and a human readable message, e.g.
+//! `{"code":"forbidden","message":"only admins can create rooms"}`.
+//! Rejected bookings also contain the violated rule in "details", see [BookingError].
+//! Internal errors are only logged, the body contains a "correlation_id" to find them in the log.
+use crate::booking::BookingError;
+use crate::database;
+use crate::input::InputError;
+use bson::oid::ObjectId;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::Catcher;
+use serde::Serialize;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// the error of a route
+#[derive(Debug)]
+pub enum Error {
+    /// the login failed
+    Unauthorized(&'static str),
+    /// the user is logged in, but not allowed to do this
+    Forbidden(&'static str),
+    /// the request is malformed or breaks a rule
+    Invalid(String),
+    NotFound(&'static str),
+    Conflict(String),
+    /// the booking violates a rule of the room, e.g. it overlaps another event
+    Booking {
+        error: BookingError,
+        room: Option<String>,
+    },
+    Database(database::Error),
+    Internal(String),
+}
+
+/// the json body of an [Error]
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    /// set for internal errors, it is logged with the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Error {
+    /// the booking error of the room, for bookings across several rooms
+    pub fn booking_in(error: BookingError, room: &str) -> Self {
+        Error::Booking {
+            error,
+            room: Some(String::from(room)),
+        }
+    }
+    pub fn status(&self) -> Status {
+        match self {
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::Invalid(_) => Status::BadRequest,
+            Error::NotFound(_) | Error::Database(database::Error::NotFound) => Status::NotFound,
+            Error::Conflict(_)
+            | Error::Booking { .. }
+            | Error::Database(database::Error::Conflict | database::Error::AlreadyInDB) => {
+                Status::Conflict
+            }
+            Error::Database(_) | Error::Internal(_) => Status::InternalServerError,
+        }
+    }
+    /// the machine readable code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::Invalid(_) => "invalid",
+            Error::NotFound(_) | Error::Database(database::Error::NotFound) => "not_found",
+            Error::Conflict(_) | Error::Database(database::Error::Conflict) => "conflict",
+            Error::Database(database::Error::AlreadyInDB) => "already_exists",
+            Error::Booking { .. } => "booking_rejected",
+            Error::Database(_) => "database",
+            Error::Internal(_) => "internal",
+        }
+    }
+    pub fn body(&self) -> ErrorBody {
+        let details = match self {
+            Error::Booking { error, room } => {
+                let mut value = error.to_json_value();
+                if let (Some(object), Some(room)) = (value.as_object_mut(), room) {
+                    object.insert(String::from("room"), room.as_str().into());
+                }
+                Some(value)
+            }
+            _ => None,
+        };
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details,
+            correlation_id: None,
+        }
+    }
+}
+
+/// a new id for an internal error, sent to the client and logged with the error
+fn correlation_id() -> String {
+    ObjectId::new().to_hex()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unauthorized(message) | Error::Forbidden(message) | Error::NotFound(message) => {
+                write!(f, "{}", message)
+            }
+            Error::Invalid(message) | Error::Conflict(message) => write!(f, "{}", message),
+            Error::Booking { error, .. } => write!(f, "{}", error),
+            Error::Database(database::Error::NotFound) => write!(f, "not found"),
+            Error::Database(database::Error::AlreadyInDB) => write!(f, "the name is already taken"),
+            Error::Database(database::Error::Conflict) => {
+                write!(
+                    f,
+                    "it was changed by someone else in the meantime, try again"
+                )
+            }
+            // the details of the database are only logged
+            Error::Database(_) | Error::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let mut body = self.body();
+        if status.class().is_server_error() {
+            let id = correlation_id();
+            println!(
+                "[{}] {} {}: {:?}",
+                id,
+                request.method(),
+                request.uri(),
+                self
+            );
+            body.correlation_id = Some(id);
+        }
+        (status, Json(body)).respond_to(request)
+    }
+}
+
+impl From<database::Error> for Error {
+    fn from(error: database::Error) -> Self {
+        Error::Database(error)
+    }
+}
+impl From<mongodb::error::Error> for Error {
+    fn from(error: mongodb::error::Error) -> Self {
+        Error::Database(error.into())
+    }
+}
+impl From<bson::ser::Error> for Error {
+    fn from(error: bson::ser::Error) -> Self {
+        Error::Database(error.into())
+    }
+}
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Internal(error.to_string())
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Internal(error.to_string())
+    }
+}
+impl From<BookingError> for Error {
+    fn from(error: BookingError) -> Self {
+        Error::Booking { error, room: None }
+    }
+}
+
+/// parses an RFC 3339 time like "2023-04-01T10:00:00+02:00" from a form field
+pub fn parse_time(
+    field: &'static str,
+    value: &str,
+) -> Result<chrono::DateTime<chrono::Local>, Error> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(Into::into)
+        .map_err(|_| Error::Invalid(format!("{} is not an RFC 3339 time", field)))
+}
+
+/// checks that a number from a form field is inside of range,
+/// e.g. before it is turned into a [chrono::Duration], which panics on overflow
+pub fn check_range(
+    field: &'static str,
+    value: i64,
+    range: RangeInclusive<i64>,
+) -> Result<i64, Error> {
+    if range.contains(&value) {
+        return Ok(value);
+    }
+    Err(Error::Invalid(format!(
+        "{} needs to be between {} and {}",
+        field,
+        range.start(),
+        range.end()
+    )))
+}
+
+/// answers the requests that failed before reaching a route,
+/// e.g. with a malformed form, in the format of [Error]
+#[catch(default)]
+fn default_catcher(status: Status, request: &Request) -> (Status, Json<ErrorBody>) {
+    let code = match status.code {
+        400 => "invalid",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        409 => "conflict",
+        422 => "invalid",
+        500..=599 => "internal",
+        _ => "error",
+    };
+    // the reason why the body was rejected, see [crate::input::Input]
+    let message = request
+        .local_cache(|| InputError(None))
+        .0
+        .clone()
+        .unwrap_or_else(|| String::from(status.reason_lossy()));
+    let body = ErrorBody {
+        code,
+        message,
+        details: None,
+        correlation_id: None,
+    };
+    (status, Json(body))
+}
+
+/// answers the requests that failed unexpectedly, e.g. because the route panicked.
+/// The cause is logged by rocket right before, the id makes it possible to find it
+#[catch(500)]
+fn internal_error(request: &Request) -> (Status, Json<ErrorBody>) {
+    let id = correlation_id();
+    println!(
+        "[{}] {} {}: internal error",
+        id,
+        request.method(),
+        request.uri()
+    );
+    let body = ErrorBody {
+        code: "internal",
+        message: String::from("internal error"),
+        details: None,
+        correlation_id: Some(id),
+    };
+    (Status::InternalServerError, Json(body))
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![default_catcher, internal_error]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_and_code() {
+        let error = Error::from(database::Error::Conflict);
+        assert_eq!(error.status(), Status::Conflict);
+        assert_eq!(error.code(), "conflict");
+        let error = Error::from(database::Error::NotFound);
+        assert_eq!(error.status(), Status::NotFound);
+        assert_eq!(error.code(), "not_found");
+        assert_eq!(Error::Forbidden("").status(), Status::Forbidden);
+
+        let body = Error::booking_in(BookingError::TooLong { max_minutes: 90 }, "A101").body();
+        assert_eq!(body.code, "booking_rejected");
+        let details = body.details.unwrap();
+        assert_eq!(details["rule"], "too_long");
+        assert_eq!(details["room"], "A101");
+        assert!(body.correlation_id.is_none());
+    }
+
+    #[test]
+    fn range_of_numbers() {
+        assert_eq!(check_range("duration", 30, 1..=60).unwrap(), 30);
+        assert!(check_range("duration", 0, 1..=60).is_err());
+        assert!(check_range("duration", i64::MAX, 1..=60).is_err());
+    }
+}
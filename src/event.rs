@@ -1,6 +1,7 @@
 //! event.rs - a room reservation used by [crate::room::Room]
 //!
 //! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+use bson::oid::ObjectId;
 use chrono::Timelike;
 use chrono::{DateTime, Local};
 use serde::*;
@@ -9,13 +10,146 @@ use std::cmp::Ordering;
 
 /// events tracks the time upto a minute
 pub struct Event {
+    /// used to address the event from outside, e.g. as the iCalendar UID.
+    /// older events do not have one, see [Event::uid]
+    #[serde(default)]
+    uid: String,
+    /// the organizer, in whose name the event is booked
     booker_id: String,
+    /// who entered the booking, if it was not the organizer, see [Event::creator_id]
+    #[serde(default)]
+    creator_id: Option<String>,
     headline: String,
     description: Option<String>,
     start: DateTime<Local>,
     stop: DateTime<Local>,
     isdummy: bool,
+    #[serde(default)]
+    status: EventStatus,
+    #[serde(default)]
+    attendees: Vec<Attendee>,
+    /// when someone confirmed on site that the event takes place
+    #[serde(default)]
+    checked_in: Option<DateTime<Local>>,
+    /// shared by the events of a booking across several rooms
+    #[serde(default)]
+    link: Option<String>,
+    /// set for the events in the history of cancelled events of a room
+    #[serde(default)]
+    cancellation: Option<Cancellation>,
+    /// incremented by every [crate::room::Room::save_event]
+    #[serde(default)]
+    version: i64,
 }
+
+/// an [Event] as it is stored in the events collection.
+/// start and stop are repeated as BSON dates, so they can be used in range queries
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredEvent {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub room_id: ObjectId,
+    pub start: bson::DateTime,
+    pub stop: bson::DateTime,
+    pub event: Event,
+}
+impl StoredEvent {
+    pub fn create(room_id: ObjectId, event: Event) -> Self {
+        StoredEvent {
+            id: ObjectId::new(),
+            room_id,
+            start: bson::DateTime::from_chrono(event.start),
+            stop: bson::DateTime::from_chrono(event.stop),
+            // events stored before the uid was introduced keep the one derived from their start
+            event: Event {
+                uid: event.uid(),
+                ..event
+            },
+        }
+    }
+}
+
+/// who cancelled an event, when and why
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cancellation {
+    /// the hex id of the user, like [Event::booker_id]
+    pub by: String,
+    pub at: DateTime<Local>,
+    pub reason: Option<String>,
+}
+impl Cancellation {
+    /// [Cancellation::by] of the events the server cancels itself
+    pub const SYSTEM: &'static str = "system";
+}
+
+/// whether the event is booked or just requested
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventStatus {
+    #[default]
+    Confirmed,
+    /// waits for the approval of the room owner or a manager, but already holds the slot
+    Pending,
+    /// holds the slot until it is confirmed or expires
+    Held { expires: DateTime<Local> },
+}
+
+/// someone invited to an [Event], either a user or an external email address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attendee {
+    pub user_id: Option<ObjectId>,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub response: Rsvp,
+    /// sent with the invitation, so external attendees can respond without an account
+    pub token: String,
+}
+impl Attendee {
+    pub fn create(user_id: Option<ObjectId>, email: Option<String>, name: Option<String>) -> Self {
+        Attendee {
+            user_id,
+            email,
+            name,
+            response: Rsvp::NeedsAction,
+            token: ObjectId::new().to_hex(),
+        }
+    }
+    /// attendees are the same if they are the same user or have the same email
+    pub fn is_same(&self, other: &Attendee) -> bool {
+        (self.user_id.is_some() && self.user_id == other.user_id)
+            || (self.email.is_some() && self.email == other.email)
+    }
+}
+
+/// the answer of an [Attendee] to the invitation, named like the iCalendar PARTSTAT
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum Rsvp {
+    #[default]
+    #[field(value = "needs_action")]
+    NeedsAction,
+    Accepted,
+    Declined,
+    Tentative,
+}
+impl Rsvp {
+    pub fn partstat(&self) -> &'static str {
+        match self {
+            Rsvp::NeedsAction => "NEEDS-ACTION",
+            Rsvp::Accepted => "ACCEPTED",
+            Rsvp::Declined => "DECLINED",
+            Rsvp::Tentative => "TENTATIVE",
+        }
+    }
+}
+
+/// headlines are sent as mail subjects, so they must not contain line breaks
+/// or other control characters
+pub fn is_valid_headline(headline: &str) -> bool {
+    !headline.is_empty() && !headline.chars().any(char::is_control)
+}
+
 impl Event {
     pub fn create(
         booker_id: String,
@@ -26,30 +160,158 @@ impl Event {
     ) -> Option<Self> {
         let start = start.with_nanosecond(0)?.with_second(0)?;
         let stop = stop.with_nanosecond(0)?.with_second(0)?;
-        if start >= stop || booker_id.is_empty() || headline.is_empty() {
+        if start >= stop || booker_id.is_empty() || !is_valid_headline(&headline) {
             return None;
         }
         Some(Event {
+            uid: ObjectId::new().to_hex(),
             booker_id,
+            creator_id: None,
             headline,
             description,
             start,
             stop,
             isdummy: false,
+            status: EventStatus::Confirmed,
+            attendees: Vec::new(),
+            checked_in: None,
+            link: None,
+            cancellation: None,
+            version: 0,
         })
     }
     /// creates a dummy used for comparing to event
     pub fn create_dummy(date: DateTime<Local>) -> Self {
         Event {
+            uid: "".to_string(),
             booker_id: "".to_string(),
+            creator_id: None,
             headline: "".to_string(),
             description: None,
             start: date,
             stop: date,
             isdummy: true,
+            status: EventStatus::Confirmed,
+            attendees: Vec::new(),
+            checked_in: None,
+            link: None,
+            cancellation: None,
+            version: 0,
         }
     }
 
+    /// moves the event to the new time, which is checked like in [Event::create]
+    pub fn with_times(mut self, start: DateTime<Local>, stop: DateTime<Local>) -> Option<Self> {
+        let start = start.with_nanosecond(0)?.with_second(0)?;
+        let stop = stop.with_nanosecond(0)?.with_second(0)?;
+        if start >= stop {
+            return None;
+        }
+        self.start = start;
+        self.stop = stop;
+        Some(self)
+    }
+    /// sets the uid, e.g. to the one given by a calendar client
+    pub fn with_uid(mut self, uid: String) -> Self {
+        self.uid = uid;
+        self
+    }
+    /// events stored before the uid was introduced are addressed by their start
+    pub fn uid(&self) -> String {
+        if self.uid.is_empty() {
+            return self.start.timestamp().to_string();
+        }
+        self.uid.clone()
+    }
+    pub fn status(&self) -> EventStatus {
+        self.status
+    }
+    pub fn with_status(mut self, status: EventStatus) -> Self {
+        self.status = status;
+        self
+    }
+    /// whether the event is a hold that expired before now
+    pub fn is_expired_hold(&self, now: DateTime<Local>) -> bool {
+        matches!(self.status, EventStatus::Held { expires } if expires <= now)
+    }
+    pub fn cancellation(&self) -> Option<&Cancellation> {
+        self.cancellation.as_ref()
+    }
+    pub fn with_cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+    pub fn with_version(mut self, version: i64) -> Self {
+        self.version = version;
+        self
+    }
+    pub fn link(&self) -> Option<&str> {
+        self.link.as_deref()
+    }
+    pub fn with_link(mut self, link: String) -> Self {
+        self.link = Some(link);
+        self
+    }
+    pub fn set_headline(&mut self, headline: String) {
+        self.headline = headline;
+    }
+    pub fn set_description(&mut self, description: Option<String>) {
+        self.description = description;
+    }
+    pub fn checked_in(&self) -> Option<DateTime<Local>> {
+        self.checked_in
+    }
+    /// only the first check in is stored
+    pub fn check_in(&mut self, now: DateTime<Local>) {
+        self.checked_in.get_or_insert(now);
+    }
+    pub fn attendees(&self) -> &Vec<Attendee> {
+        &self.attendees
+    }
+    /// adds the attendee, returns false if it was already invited
+    pub fn add_attendee(&mut self, attendee: Attendee) -> bool {
+        if self.attendees.iter().any(|known| known.is_same(&attendee)) {
+            return false;
+        }
+        self.attendees.push(attendee);
+        true
+    }
+    /// stores the response of the attendee matching the predicate
+    pub fn respond(&mut self, rsvp: Rsvp, is_attendee: impl Fn(&Attendee) -> bool) -> Option<()> {
+        let attendee = self.attendees.iter_mut().find(|a| is_attendee(a))?;
+        attendee.response = rsvp;
+        Some(())
+    }
+    /// the number of attendees that did not decline
+    pub fn expected_attendees(&self) -> usize {
+        self.attendees
+            .iter()
+            .filter(|attendee| attendee.response != Rsvp::Declined)
+            .count()
+    }
+    pub fn booker_id(&self) -> &str {
+        &self.booker_id
+    }
+    /// who entered the booking, the booker unless it was booked on behalf of them
+    pub fn creator_id(&self) -> &str {
+        self.creator_id.as_deref().unwrap_or(&self.booker_id)
+    }
+    /// stores who booked the event on behalf of the booker
+    pub fn with_creator(mut self, creator_id: String) -> Self {
+        if creator_id != self.booker_id {
+            self.creator_id = Some(creator_id);
+        }
+        self
+    }
+    pub fn headline(&self) -> &str {
+        &self.headline
+    }
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
     pub fn start(&self) -> DateTime<Local> {
         self.start
     }
@@ -128,13 +390,31 @@ mod tests {
         }
     }
     use super::*;
+    use crate::booking::BookingContext;
     use crate::room::Room;
     #[test]
     /// just try to add an [Event] on a newly created room
     fn test_add_event_on_empty() {
         let mut room = Room::default();
         let event = defaultevent(Local::now(), None);
-        (room.add_event(event)).unwrap()
+        (room.add_event(event, &BookingContext::default())).unwrap()
+    }
+    #[test]
+    fn headline_is_a_single_line() {
+        let start = Local::now();
+        let create = |headline: &str| {
+            Event::create(
+                String::from("test_booker_id"),
+                String::from(headline),
+                None,
+                start,
+                start + chrono::Duration::hours(1),
+            )
+        };
+        assert!(create("Fachkonferenz").is_some());
+        assert!(create("").is_none());
+        assert!(create("Fachkonferenz\r\nBcc: all@example.com").is_none());
+        assert!(create("Fach\tkonferenz").is_none());
     }
     #[test]
     #[should_panic]
@@ -152,10 +432,11 @@ mod tests {
         event.start = event.start.checked_sub_days(Days::new(1)).unwrap();
         event.stop = event.stop.checked_add_days(Days::new(1)).unwrap();
         println!("first event:{:?}", event);
-        room.add_event(event.clone()).unwrap();
+        room.add_event(event.clone(), &BookingContext::default())
+            .unwrap();
         println!("second event:{:?}", event);
         //this part should fail
-        room.add_event(event).unwrap();
+        room.add_event(event, &BookingContext::default()).unwrap();
     }
     #[test]
     /// As one event could start at 12 while another stops at 12, partial overlap is allowed.
@@ -166,11 +447,46 @@ mod tests {
         event.stop = event.start; //subtract the one minute of defaultevent
         event.start = event.start.checked_sub_days(Days::new(1)).unwrap();
         println!("first event:{:?}", event);
-        room.add_event(event).unwrap();
+        room.add_event(event, &BookingContext::default()).unwrap();
         let mut event = defaultevent(now, None);
         event.stop = event.stop.checked_add_days(Days::new(1)).unwrap();
         println!("second event:{:?}", event);
-        room.add_event(event).unwrap();
+        room.add_event(event, &BookingContext::default()).unwrap();
+    }
+
+    #[test]
+    fn attendees_are_unique() {
+        let mut event = defaultevent(Local::now(), None);
+        let email = Some(String::from("max@example.com"));
+        assert!(event.add_attendee(Attendee::create(None, email.clone(), None)));
+        assert!(!event.add_attendee(Attendee::create(None, email.clone(), None)));
+        assert!(event.add_attendee(Attendee::create(Some(ObjectId::new()), None, None)));
+        event
+            .respond(Rsvp::Declined, |attendee| attendee.email == email)
+            .unwrap();
+        assert_eq!(event.expected_attendees(), 1);
+    }
+
+    #[test]
+    fn creator_defaults_to_booker() {
+        let event = defaultevent(Local::now(), None);
+        assert_eq!(event.creator_id(), "test_booker_id");
+        let event = event.with_creator(String::from("secretary"));
+        assert_eq!(event.creator_id(), "secretary");
+        assert_eq!(event.booker_id(), "test_booker_id");
+    }
+    #[test]
+    fn stored_event_keeps_derived_uid() {
+        let start = Local::now();
+        let mut event = defaultevent(start, None);
+        event.uid = String::new();
+        let uid = event.uid();
+        let stored = StoredEvent::create(ObjectId::new(), event);
+        assert_eq!(stored.event.uid, uid);
+        assert_eq!(
+            stored.start.timestamp_millis(),
+            stored.event.start().timestamp_millis()
+        );
     }
 
     #[test]
@@ -187,7 +503,8 @@ mod tests {
         let mut room = Room::default();
         let now = Local::now();
         let event = defaultevent(now, None);
-        room.add_event(event.clone()).unwrap();
+        room.add_event(event.clone(), &BookingContext::default())
+            .unwrap();
         assert!(room
             .remove_event_datetime(DateTime::<Local>::MAX_UTC.into())
             .is_some());
@@ -197,7 +514,8 @@ mod tests {
         let mut room = Room::default();
         let now = Local::now();
         let event = defaultevent(now, None);
-        room.add_event(event.clone()).unwrap();
+        room.add_event(event.clone(), &BookingContext::default())
+            .unwrap();
         assert!(room.remove_event_datetime(event.start).is_some());
     }
 }
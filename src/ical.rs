@@ -0,0 +1,350 @@
+//! ical.rs - minimal iCalendar (RFC 5545) reader and writer
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! only the parts of VEVENT needed to map to [Event] are supported:
+//! UID, SUMMARY, DESCRIPTION, DTSTART and DTEND.
+//! Written events also contain their status and attendees.
+use crate::event::{Event, EventStatus};
+use crate::mail;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+/// a VEVENT as it was read from an iCalendar file
+#[derive(Debug, Clone)]
+pub struct VEvent {
+    pub uid: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub start: DateTime<Local>,
+    pub stop: DateTime<Local>,
+}
+
+/// reads all VEVENTs of an iCalendar file.
+/// VEVENTs without a valid DTSTART are skipped, a missing DTEND
+/// is treated like an all day event.
+pub fn parse_events(text: &str) -> Vec<VEvent> {
+    let mut events = Vec::new();
+    let mut current: Option<PartialEvent> = None;
+    for line in unfold(text) {
+        let Some((name, params, value)) = split_line(&line) else {
+            continue;
+        };
+        match (name.as_str(), value) {
+            ("BEGIN", "VEVENT") => current = Some(PartialEvent::default()),
+            ("END", "VEVENT") => {
+                if let Some(event) = current.take().and_then(PartialEvent::finish) {
+                    events.push(event);
+                }
+            }
+            _ => {
+                if let Some(event) = current.as_mut() {
+                    event.set(&name, params, value);
+                }
+            }
+        }
+    }
+    events
+}
+
+/// writes a VCALENDAR containing all given events
+pub fn write_calendar<'a>(name: &str, events: impl IntoIterator<Item = &'a Event>) -> String {
+    let mut ret = String::new();
+    push_line(&mut ret, "BEGIN:VCALENDAR");
+    push_line(&mut ret, "VERSION:2.0");
+    push_line(&mut ret, "PRODID:-//helper:Paper//usermanagement//DE");
+    push_line(&mut ret, &format!("X-WR-CALNAME:{}", escape(name)));
+    for event in events {
+        write_event(&mut ret, event);
+    }
+    push_line(&mut ret, "END:VCALENDAR");
+    ret
+}
+
+/// writes a METHOD:REQUEST calendar (RFC 5546) used to invite the attendees of the event
+pub fn write_invitation(event: &Event, location: &str, organizer: Option<&str>) -> String {
+    let mut ret = String::new();
+    push_line(&mut ret, "BEGIN:VCALENDAR");
+    push_line(&mut ret, "VERSION:2.0");
+    push_line(&mut ret, "PRODID:-//helper:Paper//usermanagement//DE");
+    push_line(&mut ret, "METHOD:REQUEST");
+    let mut extra = vec![format!("LOCATION:{}", escape(location))];
+    if let Some(organizer) = organizer.filter(|organizer| mail::is_valid_address(organizer)) {
+        extra.push(format!("ORGANIZER:mailto:{}", organizer));
+    }
+    write_vevent(&mut ret, event, &extra);
+    push_line(&mut ret, "END:VCALENDAR");
+    ret
+}
+
+/// writes a single VEVENT, without the surrounding VCALENDAR
+pub fn write_event(ret: &mut String, event: &Event) {
+    write_vevent(ret, event, &[]);
+}
+
+/// writes the VEVENT with the additional, already formatted properties
+fn write_vevent(ret: &mut String, event: &Event, extra: &[String]) {
+    push_line(ret, "BEGIN:VEVENT");
+    push_line(ret, &format!("UID:{}", escape(&event.uid())));
+    push_line(ret, &format!("DTSTAMP:{}", format_datetime(Local::now())));
+    push_line(ret, &format!("DTSTART:{}", format_datetime(event.start())));
+    push_line(ret, &format!("DTEND:{}", format_datetime(event.stop())));
+    push_line(ret, &format!("SUMMARY:{}", escape(event.headline())));
+    push_line(
+        ret,
+        match event.status() {
+            EventStatus::Confirmed => "STATUS:CONFIRMED",
+            EventStatus::Pending | EventStatus::Held { .. } => "STATUS:TENTATIVE",
+        },
+    );
+    if let Some(description) = event.description() {
+        push_line(ret, &format!("DESCRIPTION:{}", escape(description)));
+    }
+    for attendee in event.attendees() {
+        // attendees without an email cannot be addressed by calendar clients
+        let Some(email) = attendee
+            .email
+            .as_deref()
+            .filter(|email| mail::is_valid_address(email))
+        else {
+            continue;
+        };
+        let mut line = format!(
+            "ATTENDEE;PARTSTAT={};RSVP=TRUE",
+            attendee.response.partstat()
+        );
+        if let Some(name) = &attendee.name {
+            let name: String = name
+                .chars()
+                .filter(|c| *c != '"' && !c.is_control())
+                .collect();
+            line.push_str(&format!(";CN=\"{}\"", name));
+        }
+        push_line(ret, &format!("{}:mailto:{}", line, email));
+    }
+    for line in extra {
+        push_line(ret, line);
+    }
+    push_line(ret, "END:VEVENT");
+}
+
+/// formats the datetime as UTC, e.g. 20230102T120000Z
+pub fn format_datetime(datetime: DateTime<Local>) -> String {
+    datetime
+        .with_timezone(&Utc)
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// parses DATE-TIME values in UTC ("...Z") or floating local time, and DATE values
+/// (which are interpreted as midnight local time).
+/// TZID parameters are not resolved, the time is taken as local time instead.
+pub fn parse_datetime(value: &str) -> Option<DateTime<Local>> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        let naive = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some(Utc.from_utc_datetime(&naive).with_timezone(&Local));
+    }
+    let naive = match NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        Ok(naive) => naive,
+        Err(_) => NaiveDate::parse_from_str(value, "%Y%m%d")
+            .ok()?
+            .and_hms_opt(0, 0, 0)?,
+    };
+    Local.from_local_datetime(&naive).earliest()
+}
+
+#[derive(Default)]
+struct PartialEvent {
+    uid: Option<String>,
+    summary: Option<String>,
+    description: Option<String>,
+    start: Option<DateTime<Local>>,
+    stop: Option<DateTime<Local>>,
+    all_day: bool,
+}
+impl PartialEvent {
+    fn set(&mut self, name: &str, params: &str, value: &str) {
+        match name {
+            "UID" => self.uid = Some(unescape(value)),
+            "SUMMARY" => self.summary = Some(unescape(value)),
+            "DESCRIPTION" => self.description = Some(unescape(value)),
+            "DTSTART" => {
+                self.all_day = params.contains("VALUE=DATE") && !params.contains("DATE-TIME");
+                self.start = parse_datetime(value);
+            }
+            "DTEND" => self.stop = parse_datetime(value),
+            _ => {}
+        }
+    }
+    fn finish(self) -> Option<VEvent> {
+        let start = self.start?;
+        let stop = match self.stop {
+            Some(stop) => stop,
+            None if self.all_day => start.checked_add_days(chrono::Days::new(1))?,
+            None => return None,
+        };
+        Some(VEvent {
+            uid: self.uid,
+            summary: self.summary,
+            description: self.description,
+            start,
+            stop,
+        })
+    }
+}
+
+/// joins folded lines, a line starting with a space or tab continues the previous one
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')) {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(rest);
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+    lines
+}
+
+/// splits "NAME;PARAM=x:value" into ("NAME", "PARAM=x", "value")
+fn split_line(line: &str) -> Option<(String, &str, &str)> {
+    let (head, value) = line.split_once(':')?;
+    let (name, params) = head.split_once(';').unwrap_or((head, ""));
+    Some((name.to_ascii_uppercase(), params, value))
+}
+
+/// lines longer than 75 chars are folded
+fn push_line(ret: &mut String, line: &str) {
+    let mut count = 0;
+    for c in line.chars() {
+        if count == 75 {
+            ret.push_str("\r\n ");
+            count = 1;
+        }
+        ret.push(c);
+        count += 1;
+    }
+    ret.push_str("\r\n");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\r', "")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => ret.push('\n'),
+            Some(other) => ret.push(other),
+            None => {}
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Attendee;
+    use chrono::Timelike;
+
+    #[test]
+    fn parse_folded_event() {
+        let text = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:abc\r\nSUMMARY:Mathe\\, Klausur\r\nDESCRIPTION:lange\r\n  Beschreibung\r\nDTSTART:20230102T080000Z\r\nDTEND:20230102T093000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+        let events = parse_events(text);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].uid.as_deref(), Some("abc"));
+        assert_eq!(events[0].summary.as_deref(), Some("Mathe, Klausur"));
+        assert_eq!(events[0].description.as_deref(), Some("lange Beschreibung"));
+        assert_eq!(
+            events[0].stop - events[0].start,
+            chrono::Duration::minutes(90)
+        );
+    }
+    #[test]
+    fn parse_all_day_event() {
+        let text = "BEGIN:VEVENT\nDTSTART;VALUE=DATE:20231225\nSUMMARY:Weihnachten\nEND:VEVENT\n";
+        let events = parse_events(text);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].start.hour(), 0);
+        assert_eq!(events[0].stop - events[0].start, chrono::Duration::days(1));
+    }
+    #[test]
+    fn write_and_read_back() {
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let event = Event::create(
+            String::from("booker"),
+            String::from("headline; with, chars"),
+            None,
+            start,
+            start + chrono::Duration::hours(1),
+        )
+        .unwrap();
+        let text = write_calendar("room", [&event]);
+        let events = parse_events(&text);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].uid, Some(event.uid()));
+        assert_eq!(events[0].summary.as_deref(), Some("headline; with, chars"));
+        assert_eq!(events[0].start, event.start());
+    }
+    #[test]
+    fn invitation_lists_attendees() {
+        let start = Local::now();
+        let mut event = Event::create(
+            String::from("booker"),
+            String::from("Fachkonferenz"),
+            None,
+            start,
+            start + chrono::Duration::hours(1),
+        )
+        .unwrap();
+        event.add_attendee(Attendee::create(
+            None,
+            Some(String::from("max@example.com")),
+            Some(String::from("Max")),
+        ));
+        let text = write_invitation(&event, "A101", Some("booker@example.com"));
+        assert!(text.contains("METHOD:REQUEST\r\n"));
+        assert!(text.contains(
+            "ATTENDEE;PARTSTAT=NEEDS-ACTION;RSVP=TRUE;CN=\"Max\":mailto:max@example.com\r\n"
+        ));
+        assert!(text.contains("ORGANIZER:mailto:booker@example.com\r\nEND:VEVENT"));
+        assert_eq!(parse_events(&text).len(), 1);
+    }
+    #[test]
+    fn invitation_skips_invalid_addresses() {
+        let start = Local::now();
+        let mut event = Event::create(
+            String::from("booker"),
+            String::from("Fachkonferenz"),
+            None,
+            start,
+            start + chrono::Duration::hours(1),
+        )
+        .unwrap();
+        event.add_attendee(Attendee::create(
+            None,
+            Some(String::from("max@example.com\r\nX-INJECTED:1")),
+            Some(String::from("Max\r\nX-INJECTED:2")),
+        ));
+        let text = write_invitation(&event, "A101", Some("booker@example.com\r\nX-INJECTED:3"));
+        assert!(!text.contains("ATTENDEE"));
+        assert!(!text.contains("ORGANIZER"));
+        assert!(!text.contains("\nX-INJECTED"));
+    }
+}
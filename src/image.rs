@@ -1,11 +1,14 @@
+use crate::database::Connection;
 use crate::debug_println;
+use crate::error::Error;
+use crate::input::Input;
+use crate::tenant;
 use crate::user;
 use crate::MainDatabase;
-use rocket::form::Form;
 use rocket::fs::NamedFile;
 use rocket::http::ContentType;
+use rocket::serde::json::Json;
 use rocket::Route;
-use rocket_db_pools::Connection;
 use rocket_multipart_form_data::{
     mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
 };
@@ -23,107 +26,134 @@ async fn upload(
     content_type: &ContentType,
     data: rocket::Data<'_>,
     db: Connection<MainDatabase>,
-) -> Option<()> {
+) -> Result<(), Error> {
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
         MultipartFormDataField::file("image")
             .size_limit(1024 * 200 * 1024)
             .content_type_by_string(Some(mime::IMAGE_STAR))
-            .unwrap(),
+            .map_err(|err| Error::Internal(err.to_string()))?,
         MultipartFormDataField::text("username"),
         MultipartFormDataField::text("password"),
     ]);
 
     let mut multipart_form_data = MultipartFormData::parse(content_type, data, options)
         .await
-        .unwrap();
+        .map_err(|err| Error::Invalid(format!("invalid upload: {}", err)))?;
 
-    let photo = multipart_form_data.files.get("image")?;
-    let username = multipart_form_data.texts.remove("username")?.remove(0);
-    let password = multipart_form_data.texts.remove("password")?.remove(0);
+    let missing = |field: &str| Error::Invalid(format!("{} is needed", field));
+    let photo = multipart_form_data
+        .files
+        .get("image")
+        .ok_or_else(|| missing("image"))?;
+    let username = multipart_form_data
+        .texts
+        .remove("username")
+        .ok_or_else(|| missing("username"))?
+        .remove(0);
+    let password = multipart_form_data
+        .texts
+        .remove("password")
+        .ok_or_else(|| missing("password"))?
+        .remove(0);
     debug_println!("login");
-    let _logged_in_user = user::User::login(&username.text, password.text, &*db)
-        .await
-        .ok()?;
+    let _logged_in_user = user::User::login(&username.text, password.text, &*db).await?;
 
     let file_field = &photo[0];
 
     let _content_type = &file_field.content_type;
-    let _file_name = file_field.file_name.clone()?;
+    let _file_name = file_field
+        .file_name
+        .clone()
+        .ok_or_else(|| missing("the file name of the image"))?;
     let _path = &file_field.path;
+    let images = tenant::directory("./images", db.tenant());
+    let previews = tenant::directory("./previews", db.tenant());
+    std::fs::create_dir_all(&images)?;
+    std::fs::create_dir_all(&previews)?;
     {
         debug_println!("{}", _file_name);
         debug_println!("path:{}", _path.display());
-        let mut src_file = File::open(_path).ok()?;
+        let mut src_file = File::open(_path)?;
         debug_println!("opened src_file");
-        let mut dst_file = File::create(format!("./images/{}", _file_name)).ok()?;
+        let mut dst_file = File::create(images.join(&_file_name))?;
         debug_println!("opened dst_file");
-        std::io::copy(&mut src_file, &mut dst_file).ok()?;
+        std::io::copy(&mut src_file, &mut dst_file)?;
     }
     debug_println!("create preview");
-    let img = image::open(format!("./images/{}", _file_name)).ok()?;
+    let img = image::open(images.join(&_file_name))
+        .map_err(|_| Error::Invalid(String::from("the file is not an image")))?;
     //let img = image::open(_path).ok()?;
     debug_println!("opened preview");
     let preview_img = img.thumbnail(300, 300);
-    let preview_path = format!("./previews/{}", _file_name);
-    let mut preview_file = File::create(&preview_path).ok()?;
+    let preview_path = previews.join(&_file_name);
+    let mut preview_file = File::create(&preview_path)?;
     debug_println!("createt preview file");
     preview_img
         .write_to(&mut preview_file, image::ImageOutputFormat::Jpeg(40))
-        .ok()?;
-    Some(())
+        .map_err(|err| Error::Internal(err.to_string()))?;
+    Ok(())
 }
 
-#[post("/list", data = "<form>")]
-async fn list(form: Form<user::UserData<'_>>, db: Connection<MainDatabase>) -> Option<String> {
-    let _logged_in_user = user::User::login(form.username, String::from(form.password), &*db)
-        .await
-        .ok()?;
-    let dir = std::fs::read_dir(Path::new("./images")).ok()?;
+/// the file names of all images uploaded by the tenant
+pub(crate) fn list_names(tenant: Option<&str>) -> Result<Vec<String>, Error> {
+    let directory = tenant::directory("./images", tenant);
     let mut names: Vec<String> = Vec::new();
-    for file in dir.into_iter() {
-        names.push(file.ok()?.file_name().into_string().ok()?);
-    } //TODO: change it do be a string to begin with
-    let mut ret = String::new();
-    for file_name in names {
-        ret.push_str(&file_name);
-        ret.push_str("\n");
+    // nothing was uploaded yet
+    if !directory.is_dir() {
+        return Ok(names);
     }
-    ret.pop();
-    Some(ret)
+    for file in std::fs::read_dir(directory)? {
+        // file names that are not valid UTF-8 cannot be requested anyway
+        if let Ok(name) = file?.file_name().into_string() {
+            names.push(name);
+        }
+    }
+    Ok(names)
 }
-async fn get_with_directory(
-    directory: &Path,
-    image: &str,
-    form: Form<user::UserData<'_>>,
+/// lists the file names of all uploaded images
+#[post("/list", data = "<form>")]
+async fn list(
+    form: Input<user::UserData<'_>>,
     db: Connection<MainDatabase>,
-) -> Option<NamedFile> {
-    let _logged_in_user = user::User::login(form.username, String::from(form.password), &*db)
-        .await
-        .ok()?;
-    let path_name = directory.join(Path::new(image));
+) -> Result<Json<Vec<String>>, Error> {
+    form.login(&db).await?;
+    Ok(Json(list_names(db.tenant())?))
+}
+/// opens an uploaded image of the tenant, or its preview
+pub(crate) async fn open(
+    image: &str,
+    preview: bool,
+    tenant: Option<&str>,
+) -> Result<NamedFile, Error> {
+    let base = if preview { "./previews/" } else { "./images/" };
+    let path_name = tenant::directory(base, tenant).join(Path::new(image));
     let path = Path::new(&path_name);
     if path.is_dir() {
-        return None;
+        return Err(Error::NotFound("image not found"));
     }
 
-    NamedFile::open(path).await.ok()
+    NamedFile::open(path)
+        .await
+        .map_err(|_| Error::NotFound("image not found"))
 }
 
 #[post("/get/<image>", data = "<form>")]
 async fn get(
     image: &str,
-    form: Form<user::UserData<'_>>,
+    form: Input<user::UserData<'_>>,
     db: Connection<MainDatabase>,
-) -> Option<NamedFile> {
-    get_with_directory(Path::new("./images/"), image, form, db).await
+) -> Result<NamedFile, Error> {
+    form.login(&db).await?;
+    open(image, false, db.tenant()).await
 }
 #[post("/preview/<image>", data = "<form>")]
 async fn preview(
     image: &str,
-    form: Form<user::UserData<'_>>,
+    form: Input<user::UserData<'_>>,
     db: Connection<MainDatabase>,
-) -> Option<NamedFile> {
-    get_with_directory(Path::new("./previews/"), image, form, db).await
+) -> Result<NamedFile, Error> {
+    form.login(&db).await?;
+    open(image, true, db.tenant()).await
 }
 pub fn routes() -> Vec<Route> {
     routes![upload, list, get, preview]
@@ -0,0 +1,63 @@
+//! input.rs - request bodies sent as form or as json
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! every route accepts its data as `application/x-www-form-urlencoded` with nested keys,
+//! e.g. `userdata.username=Mustermann.Max&userdata.password=1234&name=A101`,
+//! or as `application/json` with nested objects, e.g.
+//! `{"userdata":{"username":"Mustermann.Max","password":"1234"},"name":"A101"}`.
+//! Both are validated by the same types.
+use crate::error::Error;
+use rocket::data::{self, Data, FromData};
+use rocket::form::{Form, FromForm};
+use rocket::request::Request;
+use rocket::serde::json::Json;
+use serde::Deserialize;
+use std::ops::Deref;
+
+/// the body of a request, parsed as json if the content type is json, else as form
+#[derive(Debug)]
+pub struct Input<T>(pub T);
+
+impl<T> Input<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Input<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// why the body could not be parsed, shown by the catchers as message
+pub struct InputError(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r> + Deserialize<'r>> FromData<'r> for Input<T> {
+    type Error = Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let is_json = request
+            .content_type()
+            .map_or(false, |content_type| content_type.is_json());
+        let outcome = if is_json {
+            Json::<T>::from_data(request, data)
+                .await
+                .map(|json| Input(json.into_inner()))
+                .map_failure(|(status, err)| (status, err.to_string()))
+        } else {
+            Form::<T>::from_data(request, data)
+                .await
+                .map(|form| Input(form.into_inner()))
+                .map_failure(|(status, errors)| (status, errors.to_string()))
+        };
+        outcome.map_failure(|(status, message)| {
+            request.local_cache(|| InputError(Some(message.clone())));
+            (status, Error::Invalid(message))
+        })
+    }
+}
@@ -0,0 +1,283 @@
+//! mail.rs - pluggable sending of emails
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! the backend is chosen in the `mail` section of Rocket.toml:
+//! ```toml
+//! [default.mail]
+//! backend = "sendmail" # or "log", which just prints the mails
+//! from = "raumbuchung@schule.de"
+//! sendmail = "/usr/sbin/sendmail"
+//! base_url = "https://raumbuchung.schule.de"
+//! ```
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rocket::{fairing, Build, Rocket};
+use serde::*;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// a mail with an optional iCalendar attachment
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    /// the content of a text/calendar part and its METHOD, e.g. "REQUEST"
+    pub calendar: Option<(String, &'static str)>,
+}
+
+/// whether the address can be written into mail headers and iCalendar lines,
+/// which rules out whitespace, control characters and separators like `,` and `<`
+pub fn is_valid_address(address: &str) -> bool {
+    let Some((local, domain)) = address.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.is_empty()
+        && !address.chars().any(|c| {
+            c.is_control() || c.is_whitespace() || matches!(c, ',' | ';' | ':' | '<' | '>' | '"')
+        })
+}
+
+/// the text as header value. Control characters are replaced by spaces and text
+/// that is not plain ASCII is sent as RFC 2047 encoded words
+fn encode_header(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    if text.is_ascii() {
+        return text;
+    }
+    // an encoded word is at most 75 characters long, which leaves room for 45 bytes
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in text.chars() {
+        if chunk.len() + c.len_utf8() > 45 {
+            words.push(format!("=?utf-8?B?{}?=", STANDARD.encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(format!("=?utf-8?B?{}?=", STANDARD.encode(&chunk)));
+    words.join("\r\n ")
+}
+
+impl Mail {
+    /// the mail as RFC 5322 message, fails if an address could break the headers
+    pub fn to_message(&self, from: &str) -> Result<String, String> {
+        for address in [from, &self.to] {
+            if !is_valid_address(address) {
+                return Err(format!("{:?} is not a valid address", address));
+            }
+        }
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n",
+            from,
+            self.to,
+            encode_header(&self.subject)
+        );
+        let Some((calendar, method)) = &self.calendar else {
+            message.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+            message.push_str(&self.body);
+            return Ok(message);
+        };
+        let boundary = format!("helper-paper-{}", bson::oid::ObjectId::new());
+        message.push_str(&format!(
+            "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n\
+             --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n\
+             --{boundary}\r\nContent-Type: text/calendar; charset=utf-8; method={}\r\n\r\n{}\r\n\
+             --{boundary}--\r\n",
+            boundary,
+            self.body,
+            method,
+            calendar,
+            boundary = boundary
+        ));
+        Ok(message)
+    }
+}
+
+/// implemented by every way to deliver mails
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), String>;
+}
+
+/// prints the mails instead of sending them, used if no backend is configured
+pub struct LogMailer;
+impl Mailer for LogMailer {
+    fn send(&self, mail: &Mail) -> Result<(), String> {
+        println!("mail to {}: {}\n{}", mail.to, mail.subject, mail.body);
+        Ok(())
+    }
+}
+
+/// pipes the mails to the sendmail binary of the system
+pub struct SendmailMailer {
+    path: String,
+    from: String,
+}
+impl Mailer for SendmailMailer {
+    fn send(&self, mail: &Mail) -> Result<(), String> {
+        let message = mail.to_message(&self.from)?;
+        let mut child = Command::new(&self.path)
+            .arg("-t")
+            .arg("-i")
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| err.to_string())?;
+        child
+            .stdin
+            .take()
+            .ok_or("no stdin")?
+            .write_all(message.as_bytes())
+            .map_err(|err| err.to_string())?;
+        let status = child.wait().map_err(|err| err.to_string())?;
+        if !status.success() {
+            return Err(format!("sendmail exited with {}", status));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MailConfig {
+    #[serde(default)]
+    pub backend: Option<String>,
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub sendmail: Option<String>,
+    /// the address under which the server is reachable, used for links in mails
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+/// the configured [Mailer], managed by rocket
+pub struct MailService {
+    mailer: Arc<dyn Mailer>,
+    pub base_url: String,
+}
+impl MailService {
+    pub fn new(mailer: Box<dyn Mailer>, base_url: String) -> Self {
+        MailService {
+            mailer: Arc::from(mailer),
+            base_url,
+        }
+    }
+    /// sends the mail on a blocking thread, failures are only logged
+    pub async fn send(&self, mail: Mail) {
+        // the mailer can block, e.g. while sendmail is running
+        let mailer = self.mailer.clone();
+        let to = mail.to.clone();
+        let result = tokio::task::spawn_blocking(move || mailer.send(&mail)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => println!("could not send mail to {}: {}", to, err),
+            Err(err) => println!("sending the mail to {} failed: {}", to, err),
+        }
+    }
+}
+
+/// A fairing that creates the [MailService] from the `mail` config
+pub async fn load(rocket: Rocket<Build>) -> fairing::Result {
+    let config = match rocket.figment().extract_inner::<MailConfig>("mail") {
+        Ok(config) => config,
+        Err(err) if err.missing() => MailConfig::default(),
+        Err(err) => {
+            println!("invalid mail config: {}", err);
+            return Err(rocket);
+        }
+    };
+    let mailer: Box<dyn Mailer> = match config.backend.as_deref() {
+        None | Some("log") => Box::new(LogMailer),
+        Some("sendmail") => Box::new(SendmailMailer {
+            path: config
+                .sendmail
+                .unwrap_or_else(|| String::from("/usr/sbin/sendmail")),
+            from: config
+                .from
+                .unwrap_or_else(|| String::from("helper-paper@localhost")),
+        }),
+        Some(backend) => {
+            println!("unknown mail backend {}", backend);
+            return Err(rocket);
+        }
+    };
+    let base_url = config
+        .base_url
+        .unwrap_or_else(|| String::from("http://127.0.0.1:2005"));
+    Ok(rocket.manage(MailService::new(mailer, base_url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_with_calendar() {
+        let mail = Mail {
+            to: String::from("max@example.com"),
+            subject: String::from("Einladung"),
+            body: String::from("Hallo"),
+            calendar: Some((String::from("BEGIN:VCALENDAR"), "REQUEST")),
+        };
+        let message = mail.to_message("raum@example.com").unwrap();
+        assert!(message.starts_with("From: raum@example.com\r\nTo: max@example.com\r\n"));
+        assert!(message.contains("Content-Type: text/calendar; charset=utf-8; method=REQUEST"));
+        assert!(message.contains("BEGIN:VCALENDAR"));
+    }
+    #[test]
+    fn headers_cannot_be_injected() {
+        let mut mail = Mail {
+            to: String::from("max@example.com\r\nBcc: all@example.com"),
+            subject: String::from("Raum\r\nBcc: all@example.com"),
+            body: String::from("Hallo"),
+            calendar: None,
+        };
+        assert!(mail.to_message("raum@example.com").is_err());
+        mail.to = String::from("max@example.com");
+        let message = mail.to_message("raum@example.com").unwrap();
+        assert!(message.contains("Subject: Raum  Bcc: all@example.com\r\n"));
+        assert!(!is_valid_address("max@example.com, all@example.com"));
+    }
+
+    #[test]
+    fn non_ascii_subject_is_encoded() {
+        assert_eq!(encode_header("Einladung"), "Einladung");
+        assert_eq!(encode_header("Bücherei"), "=?utf-8?B?QsO8Y2hlcmVp?=");
+        let long = encode_header(&"ä".repeat(40));
+        assert!(long.split("\r\n ").all(|word| word.len() <= 75));
+        assert_eq!(long.split("\r\n ").count(), 2);
+    }
+    /// remembers the recipients of the sent mails
+    struct RecordingMailer(std::sync::Mutex<Vec<String>>);
+    impl Mailer for RecordingMailer {
+        fn send(&self, mail: &Mail) -> Result<(), String> {
+            self.0.lock().unwrap().push(mail.to.clone());
+            Ok(())
+        }
+    }
+
+    /// tokio::test uses a single threaded runtime, on which blocking in place panics
+    #[tokio::test]
+    async fn send_on_current_thread_runtime() {
+        let mailer = Arc::new(RecordingMailer(std::sync::Mutex::new(Vec::new())));
+        let service = MailService {
+            mailer: mailer.clone(),
+            base_url: String::new(),
+        };
+        let mail = Mail {
+            to: String::from("max@example.com"),
+            subject: String::from("Einladung"),
+            body: String::from("Hallo"),
+            calendar: None,
+        };
+        service.send(mail).await;
+        assert_eq!(
+            *mailer.0.lock().unwrap(),
+            vec![String::from("max@example.com")]
+        );
+    }
+}
@@ -1,7 +1,8 @@
 //! main.rs - A Rocket backend for a Room Reservation
 //!
 //! This backend is based on a REST API style whitch uses x-form
-//! to validate the identity of the request
+//! to validate the identity of the request.
+//! The resource oriented version 2 is mounted at `/api/v2`, see [api]
 //!
 //! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
 #![feature(let_chains)]
@@ -11,32 +12,98 @@
 #[macro_use]
 extern crate rocket;
 
+mod api;
+mod attendee;
+mod booking;
+mod caldav;
+mod checkin;
 mod database;
 mod epaper;
+mod error;
 mod event;
+mod ical;
 mod image;
+mod input;
+mod mail;
+mod memory;
+mod migration;
+mod multiroom;
+mod notification;
 mod room;
+mod tasks;
+mod tenant;
 mod user;
+mod waitlist;
 
-use database::MainDatabase;
+use database::{MainDatabase, StorageKind};
 use rocket::fairing::AdHoc;
+use rocket::{Build, Rocket};
 use rocket_db_pools::Database;
 
-#[launch]
-fn rocket() -> _ {
+/// starts the server, or runs the `migrate` command, see [migration]
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        let rocket = storage(rocket::build()).ignite().await?;
+        if let Err(err) = migration::cli(&rocket, args.get(1).map(String::as_str)).await {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     println!("{:?}", user::routes()[1].uri);
-    rocket::build()
-        .attach(database::MainDatabase::init())
+    let _ = build(rocket::build()).launch().await?;
+    Ok(())
+}
+
+/// attaches the storage and the tenants.
+/// The MongoDB pool is only attached if the `storage` config key selects it
+fn storage(rocket: Rocket<Build>) -> Rocket<Build> {
+    let rocket = match StorageKind::from_config(&rocket) {
+        Ok(StorageKind::Memory) => rocket,
+        _ => rocket.attach(MainDatabase::init()),
+    };
+    rocket
+        .attach(AdHoc::try_on_ignite(
+            "Attach storage",
+            database::attach_storage,
+        ))
+        .attach(AdHoc::try_on_ignite("Load tenants", tenant::load))
+}
+
+/// attaches the fairings and mounts the routes
+fn build(rocket: Rocket<Build>) -> Rocket<Build> {
+    storage(rocket)
         .attach(AdHoc::try_on_ignite(
             "Create collection indices",
             database::create_indices,
         ))
-        .register("/", catchers![not_found])
+        .attach(AdHoc::try_on_ignite("Run migrations", migration::migrate))
+        .attach(AdHoc::try_on_ignite(
+            "Load booking defaults",
+            booking::load_defaults,
+        ))
+        .attach(AdHoc::try_on_ignite("Load mailer", mail::load))
+        .attach(api::deprecate_v1())
+        .attach(AdHoc::on_liftoff("Start background tasks", |rocket| {
+            Box::pin(tasks::start(rocket))
+        }))
+        .register("/", error::catchers())
+        .register("/caldav", caldav::catchers())
         .mount("/", routes![status])
         .mount("/epaper", epaper::routes())
         .mount("/user", user::routes())
         .mount("/room", room::routes())
         .mount("/image", image::routes())
+        .mount("/caldav", caldav::routes())
+        .mount("/booking", booking::routes())
+        .mount("/notification", notification::routes())
+        .mount("/attendee", attendee::routes())
+        .mount("/checkin", checkin::routes())
+        .mount("/waitlist", waitlist::routes())
+        .mount("/multiroom", multiroom::routes())
+        .mount("/api/v2", api::routes())
 }
 
 /// used to look up whether the given IP is a server
@@ -46,13 +113,6 @@ async fn status() -> Option<&'static str> {
     Some("ok")
 }
 
-/// As it is an API, it can just send an empty str
-/// if the request fails.
-#[catch(404)]
-fn not_found() -> &'static str {
-    ""
-}
-
 /// just a wrapper around println! which looks up, wether it is in DEBUG mode
 #[macro_export]
 macro_rules! debug_println {
@@ -62,3 +122,387 @@ macro_rules! debug_println {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabaseConnection, DatabaseUtils, Storage};
+    use rocket::figment::Figment;
+    use rocket::http::uri::Host;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+
+    /// the server with the in-memory storage and the rest of the config
+    async fn test_client(figment: Figment) -> Client {
+        Client::tracked(build(rocket::custom(figment.merge(("storage", "memory")))))
+            .await
+            .expect("server could not be started")
+    }
+
+    /// stores the user Mustermann.Max with the password 1234
+    async fn test_user(storage: &Storage) -> user::User {
+        let user = user::User::new(
+            String::from("Max"),
+            String::from("Mustermann"),
+            String::from("1234"),
+        )
+        .unwrap();
+        user.insert(storage).await.unwrap();
+        user
+    }
+
+    /// changes a field of the stored user, e.g. to one the routes cannot set
+    async fn set_user_field(
+        user: &user::User,
+        field: &str,
+        value: impl Into<bson::Bson>,
+        storage: &Storage,
+    ) {
+        storage
+            .get_collection::<bson::Document>("users")
+            .update_one(
+                bson::doc! {"_id": user.id()},
+                bson::doc! {"$set": {field: value.into()}},
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    /// the whole server runs with the in-memory storage, without a MongoDB
+    #[tokio::test]
+    async fn memory_storage() {
+        let client = test_client(rocket::Config::figment()).await;
+        test_user(client.rocket().state::<Storage>().unwrap()).await;
+
+        let response = client.get("/status").dispatch().await;
+        assert_eq!(response.into_string().await.as_deref(), Some("ok"));
+        let response = client
+            .post("/user/isvalid")
+            .header(ContentType::Form)
+            .body("username=Mustermann.Max&password=1234")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post("/user/isvalid")
+            .header(ContentType::Form)
+            .body("username=Mustermann.Max&password=wrong")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""code":"unauthorized""#), "{}", body);
+
+        // the same data can be sent as json, and lists are answered as json
+        let response = client
+            .post("/user/get_rooms")
+            .header(ContentType::JSON)
+            .body(r#"{"username":"Mustermann.Max","password":"1234"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(response.into_string().await.as_deref(), Some("[]"));
+        let response = client
+            .post("/user/isvalid")
+            .header(ContentType::JSON)
+            .body(r#"{"username":"Mustermann.Max"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""code":"invalid""#), "{}", body);
+        assert!(body.contains("password"), "{}", body);
+
+        // failures before reaching a route are answered in the same format
+        let response = client.get("/nothing").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""code":"not_found""#), "{}", body);
+    }
+
+    /// a broken stored user does not panic, it is answered with an id to look up in the log
+    #[tokio::test]
+    async fn internal_error_has_correlation_id() {
+        let client = test_client(rocket::Config::figment()).await;
+        let storage = client.rocket().state::<Storage>().unwrap();
+        let user = test_user(storage).await;
+        set_user_field(&user, "password_hash", "not a hash", storage).await;
+
+        let response = client
+            .post("/user/isvalid")
+            .header(ContentType::Form)
+            .body("username=Mustermann.Max&password=1234")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::InternalServerError);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""code":"internal""#), "{}", body);
+        assert!(body.contains(r#""correlation_id":"#), "{}", body);
+        assert!(!body.contains("not a hash"), "{}", body);
+    }
+
+    /// the users of one tenant cannot log in at another one
+    #[tokio::test]
+    async fn tenants_are_separated() {
+        let figment = rocket::Config::figment()
+            .merge(("tenancy.header", "X-Tenant"))
+            .merge(("tenancy.tenants.gymnasium.hosts", ["gymnasium.schule.de"]))
+            .merge(("tenancy.tenants.grundschule.database", "gs"));
+        let client = test_client(figment).await;
+        let storage = client.rocket().state::<Storage>().unwrap();
+        test_user(&storage.with_database("gymnasium")).await;
+
+        for (tenant, status) in [
+            ("gymnasium", Status::Ok),
+            ("grundschule", Status::Unauthorized),
+            ("realschule", Status::NotFound),
+        ] {
+            let response = client
+                .post("/user/isvalid")
+                .header(ContentType::Form)
+                .header(Header::new("X-Tenant", tenant))
+                .body("username=Mustermann.Max&password=1234")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), status, "tenant {}", tenant);
+        }
+        for (host, status) in [
+            ("gymnasium.schule.de", Status::Ok),
+            ("realschule.schule.de", Status::NotFound),
+        ] {
+            let mut request = client
+                .post("/user/isvalid")
+                .header(ContentType::Form)
+                .body("username=Mustermann.Max&password=1234");
+            request.inner_mut().set_host(Host::parse(host).unwrap());
+            let response = request.dispatch().await;
+            assert_eq!(response.status(), status, "host {}", host);
+        }
+    }
+
+    /// a room is booked, read and cancelled with the v2 routes, v1 is marked as deprecated
+    #[tokio::test]
+    async fn api_v2() {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+        let client = test_client(rocket::Config::figment()).await;
+        let storage = client.rocket().state::<Storage>().unwrap();
+        let user = test_user(storage).await;
+        set_user_field(&user, "is_admin", true, storage).await;
+        let room = room::Room::create(String::from("A101"));
+        room.insert(storage).await.unwrap();
+        let auth = || {
+            let credentials = STANDARD.encode("Mustermann.Max:1234");
+            Header::new("Authorization", format!("Basic {}", credentials))
+        };
+
+        let response = client.get("/api/v2/rooms").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client.get("/api/v2/rooms").header(auth()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Deprecation").is_none());
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""name":"A101""#), "{}", body);
+
+        let start = chrono::Local::now() + chrono::Duration::days(1);
+        let stop = start + chrono::Duration::hours(1);
+        let response = client
+            .post(format!("/api/v2/rooms/{}/events", room.id().to_hex()))
+            .header(auth())
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{"headline":"Konferenz","start":"{}","stop":"{}"}}"#,
+                start.to_rfc3339(),
+                stop.to_rfc3339()
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+        let location = response.headers().get_one("Location").unwrap().to_string();
+
+        let events = |from: String, to: String| {
+            format!(
+                "/api/v2/rooms/{}/events?from={}&to={}",
+                room.id().to_hex(),
+                from,
+                to
+            )
+        };
+        let response = client
+            .get(events(
+                start.timestamp().to_string(),
+                stop.timestamp().to_string(),
+            ))
+            .header(auth())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let utc = |time: chrono::DateTime<chrono::Local>| {
+            time.with_timezone(&chrono::Utc)
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        };
+        let response = client
+            .get(events(utc(start - chrono::Duration::hours(1)), utc(stop)))
+            .header(auth())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains("Konferenz"), "{}", body);
+        let response = client
+            .delete(location.as_str())
+            .header(auth())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
+        let response = client
+            .get(location.as_str())
+            .header(auth())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .post("/user/isvalid")
+            .header(ContentType::Form)
+            .body("username=Mustermann.Max&password=1234")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Deprecation"), Some("true"));
+    }
+
+    /// users that can book a room can change its description, but not its booking rules
+    #[tokio::test]
+    async fn booking_rules_need_owner() {
+        let client = test_client(rocket::Config::figment()).await;
+        let storage = client.rocket().state::<Storage>().unwrap();
+        let user = test_user(storage).await;
+        let room = room::Room::create(String::from("A101"));
+        room.insert(storage).await.unwrap();
+        set_user_field(&user, "editable_rooms", vec![room.id()], storage).await;
+        let change = |fields: &str| {
+            client
+                .post("/room/change")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{"userdata":{{"username":"Mustermann.Max","password":"1234"}},"room_name":"A101",{}}}"#,
+                    fields
+                ))
+        };
+        let response = change(r#""description":"Chemie""#).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        for fields in [
+            r#""managers":"Mustermann.Max""#,
+            r#""requires_approval":false"#,
+            r#""setup_minutes":0"#,
+        ] {
+            let response = change(fields).dispatch().await;
+            assert_eq!(response.status(), Status::Forbidden, "{}", fields);
+        }
+    }
+
+    /// a rejected request stays in the history of the room, cancelled by the approver
+    #[tokio::test]
+    async fn rejected_requests_are_cancelled() {
+        let client = test_client(rocket::Config::figment()).await;
+        let storage = client.rocket().state::<Storage>().unwrap();
+        let user = test_user(storage).await;
+        set_user_field(&user, "is_admin", true, storage).await;
+        let mut room = room::Room::create(String::from("A101"));
+        room.insert(storage).await.unwrap();
+        let start = chrono::Local::now() + chrono::Duration::days(1);
+        let stop = start + chrono::Duration::hours(1);
+        let event = event::Event::create(
+            String::from("booker"),
+            String::from("Elternabend"),
+            None,
+            start,
+            stop,
+        )
+        .unwrap()
+        .with_status(event::EventStatus::Pending);
+        room.book_event(event.clone(), &booking::BookingContext::default(), storage)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let response = client
+            .post("/room/decide")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{"userdata":{{"username":"Mustermann.Max","password":"1234"}},"room_name":"A101","uid":"{}","approve":false,"comment":"belegt"}}"#,
+                event.uid()
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let mut room = room::Room::getfromdb_id(&room.id(), storage).await.unwrap();
+        room.load_events(start, stop, true, storage).await.unwrap();
+        assert_eq!(room.events().count(), 0);
+        let cancellation = room.cancelled_events()[0].cancellation().unwrap();
+        assert_eq!(cancellation.by, user.id().to_hex());
+        assert_eq!(cancellation.reason.as_deref(), Some("belegt"));
+    }
+
+    /// opening the link of an invitation only shows a form, the response is stored by posting it
+    #[tokio::test]
+    async fn reply_needs_confirmation() {
+        let client = test_client(rocket::Config::figment()).await;
+        let storage = client.rocket().state::<Storage>().unwrap();
+        let mut room = room::Room::create(String::from("A101"));
+        room.insert(storage).await.unwrap();
+        let start = chrono::Local::now() + chrono::Duration::days(1);
+        let mut event = event::Event::create(
+            String::from("booker"),
+            String::from("Konferenz"),
+            None,
+            start,
+            start + chrono::Duration::hours(1),
+        )
+        .unwrap();
+        let attendee = event::Attendee::create(None, Some(String::from("max@example.com")), None);
+        event.add_attendee(attendee.clone());
+        room.book_event(event.clone(), &booking::BookingContext::default(), storage)
+            .await
+            .unwrap()
+            .unwrap();
+        async fn response(room: &room::Room, uid: &str, storage: &Storage) -> event::Rsvp {
+            let mut room = room::Room::getfromdb_id(&room.id(), storage).await.unwrap();
+            room.load_event_uid(uid, storage).await.unwrap();
+            room.get_event_uid(uid).unwrap().attendees()[0].response
+        }
+        let fields = format!(
+            "room={}&uid={}&token={}&response=accepted",
+            room.id().to_hex(),
+            event.uid(),
+            attendee.token
+        );
+
+        let page = client
+            .get(format!("/attendee/reply?{}", fields))
+            .dispatch()
+            .await;
+        assert_eq!(page.status(), Status::Ok);
+        assert_eq!(page.content_type(), Some(ContentType::HTML));
+        let body = page.into_string().await.unwrap();
+        assert!(body.contains(r#"method="post""#), "{}", body);
+        assert_eq!(
+            response(&room, &event.uid(), storage).await,
+            event::Rsvp::NeedsAction
+        );
+        let confirmed = client
+            .post("/attendee/reply")
+            .header(ContentType::Form)
+            .body(fields)
+            .dispatch()
+            .await;
+        assert_eq!(confirmed.status(), Status::Ok);
+        assert_eq!(
+            response(&room, &event.uid(), storage).await,
+            event::Rsvp::Accepted
+        );
+    }
+}
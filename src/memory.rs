@@ -0,0 +1,480 @@
+//! memory.rs - storage backend that keeps all documents in memory
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! used with `storage = "memory"` in the config, e.g. by the tests, so that they
+//! do not need a running MongoDB. Only the part of the MongoDB query language
+//! used by the routes is understood: equality (also with the elements of arrays),
+//! `$eq`, `$ne`, `$lt`, `$lte`, `$gt`, `$gte`, `$in`, `$nin`, `$exists`, `$or` and `$and`
+//! in filters, `$set`, `$unset`, `$inc`, `$push`, `$addToSet`, `$pull` and
+//! `$setOnInsert` in updates. Indices are ignored.
+use bson::oid::ObjectId;
+use bson::{Bson, Document};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// all collections of the in-memory database
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    collections: Mutex<HashMap<String, Vec<Document>>>,
+}
+impl MemoryStore {
+    pub fn collection(self: &Arc<Self>, name: &str) -> Collection {
+        Collection {
+            store: self.clone(),
+            name: String::from(name),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<Document>>> {
+        // a panic while holding the lock does not leave the documents half written
+        self.collections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// the number of matched and changed documents of an update
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UpdateCount {
+    pub matched: u64,
+    pub modified: u64,
+}
+
+/// a collection of the [MemoryStore]
+#[derive(Debug, Clone)]
+pub struct Collection {
+    store: Arc<MemoryStore>,
+    name: String,
+}
+impl Collection {
+    /// the documents matching the filter, in the order of the sort document
+    pub fn find(&self, filter: &Document, sort: Option<&Document>) -> Vec<Document> {
+        let collections = self.store.lock();
+        let mut found: Vec<Document> = collections
+            .get(&self.name)
+            .into_iter()
+            .flatten()
+            .filter(|document| matches(document, filter))
+            .cloned()
+            .collect();
+        if let Some(sort) = sort {
+            found.sort_by(|a, b| compare_sorted(a, b, sort));
+        }
+        found
+    }
+    /// inserts the document, an `_id` is added if it has none
+    pub fn insert(&self, mut document: Document) {
+        if !document.contains_key("_id") {
+            document.insert("_id", ObjectId::new());
+        }
+        self.store
+            .lock()
+            .entry(self.name.clone())
+            .or_default()
+            .push(document);
+    }
+    /// applies the update to the first or all matching documents.
+    /// With upsert a document is inserted if none matches
+    pub fn update(
+        &self,
+        filter: &Document,
+        update: &Document,
+        many: bool,
+        upsert: bool,
+    ) -> UpdateCount {
+        let mut count = UpdateCount::default();
+        {
+            let mut collections = self.store.lock();
+            let documents = collections.entry(self.name.clone()).or_default();
+            for document in documents
+                .iter_mut()
+                .filter(|document| matches(document, filter))
+            {
+                count.matched += 1;
+                let before = document.clone();
+                apply_update(document, update, false);
+                if *document != before {
+                    count.modified += 1;
+                }
+                if !many {
+                    break;
+                }
+            }
+        }
+        if count.matched == 0 && upsert {
+            let mut document = Document::new();
+            for (key, value) in filter {
+                if !key.starts_with('$') && !is_operator_document(value) {
+                    set_path(&mut document, key, value.clone());
+                }
+            }
+            apply_update(&mut document, update, true);
+            self.insert(document);
+        }
+        count
+    }
+    /// deletes the first or all matching documents and returns how many were deleted
+    pub fn delete(&self, filter: &Document, many: bool) -> u64 {
+        let mut collections = self.store.lock();
+        let Some(documents) = collections.get_mut(&self.name) else {
+            return 0;
+        };
+        let mut deleted = 0;
+        documents.retain(|document| {
+            if (many || deleted == 0) && matches(document, filter) {
+                deleted += 1;
+                return false;
+            }
+            true
+        });
+        deleted
+    }
+    /// the different values of the field in the matching documents.
+    /// The elements of arrays count as single values
+    pub fn distinct(&self, field: &str, filter: &Document) -> Vec<Bson> {
+        let mut values: Vec<Bson> = Vec::new();
+        for document in self.find(filter, None) {
+            let found = match get_path(&document, field) {
+                Some(Bson::Array(items)) => items.clone(),
+                Some(value) => vec![value.clone()],
+                None => Vec::new(),
+            };
+            for value in found {
+                if !values.iter().any(|known| equals(known, &value)) {
+                    values.push(value);
+                }
+            }
+        }
+        values
+    }
+}
+
+/// whether the document matches all conditions of the filter
+pub fn matches(document: &Document, filter: &Document) -> bool {
+    filter.iter().all(|(key, condition)| match key.as_str() {
+        "$or" => conditions(condition).any(|filter| matches(document, filter)),
+        "$and" => conditions(condition).all(|filter| matches(document, filter)),
+        _ => value_matches(get_path(document, key), condition),
+    })
+}
+
+fn conditions(condition: &Bson) -> impl Iterator<Item = &Document> {
+    let items = match condition {
+        Bson::Array(items) => items.as_slice(),
+        _ => &[],
+    };
+    items.iter().filter_map(Bson::as_document)
+}
+
+/// a document like `{"$gt": 0}` instead of a value
+fn is_operator_document(value: &Bson) -> bool {
+    match value {
+        Bson::Document(document) => document
+            .keys()
+            .next()
+            .map_or(false, |key| key.starts_with('$')),
+        _ => false,
+    }
+}
+
+fn value_matches(value: Option<&Bson>, condition: &Bson) -> bool {
+    let Bson::Document(operators) = condition else {
+        return value_equals(value, condition);
+    };
+    if !is_operator_document(condition) {
+        return value_equals(value, condition);
+    }
+    operators
+        .iter()
+        .all(|(operator, operand)| match operator.as_str() {
+            "$eq" => value_equals(value, operand),
+            "$ne" => !value_equals(value, operand),
+            "$lt" => value_compares(value, operand, |order| order == Ordering::Less),
+            "$lte" => value_compares(value, operand, |order| order != Ordering::Greater),
+            "$gt" => value_compares(value, operand, |order| order == Ordering::Greater),
+            "$gte" => value_compares(value, operand, |order| order != Ordering::Less),
+            "$in" => candidates(operand).any(|candidate| value_equals(value, candidate)),
+            "$nin" => !candidates(operand).any(|candidate| value_equals(value, candidate)),
+            "$exists" => operand.as_bool().unwrap_or(true) == value.is_some(),
+            _ => false,
+        })
+}
+
+fn candidates(operand: &Bson) -> impl Iterator<Item = &Bson> {
+    match operand {
+        Bson::Array(items) => items.iter(),
+        _ => [].iter(),
+    }
+}
+
+/// like MongoDB, null also matches missing fields and
+/// a value matches an array containing it
+fn value_equals(value: Option<&Bson>, expected: &Bson) -> bool {
+    match (value, expected) {
+        (None, Bson::Null) => true,
+        (None, _) => false,
+        (Some(Bson::Array(items)), expected) if !matches!(expected, Bson::Array(_)) => {
+            items.iter().any(|item| equals(item, expected))
+        }
+        (Some(value), expected) => equals(value, expected),
+    }
+}
+
+fn value_compares(value: Option<&Bson>, operand: &Bson, accept: impl Fn(Ordering) -> bool) -> bool {
+    let Some(value) = value else {
+        return false;
+    };
+    // only values of the same kind are compared, as in MongoDB
+    if type_rank(value) != type_rank(operand) {
+        return false;
+    }
+    accept(compare(value, operand))
+}
+
+fn equals(a: &Bson, b: &Bson) -> bool {
+    if let (Some(a), Some(b)) = (as_number(a), as_number(b)) {
+        return a == b;
+    }
+    a == b
+}
+
+fn as_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(number) => Some(*number as f64),
+        Bson::Int64(number) => Some(*number as f64),
+        Bson::Double(number) => Some(*number),
+        _ => None,
+    }
+}
+
+/// the order of the kinds of values, numbers of different types are compared by value
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::Null | Bson::Undefined => 0,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => 1,
+        Bson::String(_) | Bson::Symbol(_) => 2,
+        Bson::Document(_) => 3,
+        Bson::Array(_) => 4,
+        Bson::ObjectId(_) => 5,
+        Bson::Boolean(_) => 6,
+        Bson::DateTime(_) => 7,
+        _ => 8,
+    }
+}
+
+fn compare(a: &Bson, b: &Bson) -> Ordering {
+    match (a, b) {
+        _ if type_rank(a) != type_rank(b) => type_rank(a).cmp(&type_rank(b)),
+        (Bson::String(a), Bson::String(b)) => a.cmp(b),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.cmp(b),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        _ => match (as_number(a), as_number(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            _ => Ordering::Equal,
+        },
+    }
+}
+
+/// compares the documents by the fields of the sort document, 1 ascending and -1 descending
+fn compare_sorted(a: &Document, b: &Document, sort: &Document) -> Ordering {
+    for (field, direction) in sort {
+        let order = match (get_path(a, field), get_path(b, field)) {
+            (Some(a), Some(b)) => compare(a, b),
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        let order = if as_number(direction).map_or(false, |direction| direction < 0.0) {
+            order.reverse()
+        } else {
+            order
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    Ordering::Equal
+}
+
+/// looks up a field like "event.uid" in the nested documents
+pub fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let (first, rest) = match path.split_once('.') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+    let value = document.get(first)?;
+    match rest {
+        None => Some(value),
+        Some(rest) => get_path(value.as_document()?, rest),
+    }
+}
+
+fn set_path(document: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        None => {
+            document.insert(path, value);
+        }
+        Some((first, rest)) => {
+            if !matches!(document.get(first), Some(Bson::Document(_))) {
+                document.insert(first, Document::new());
+            }
+            if let Some(Bson::Document(inner)) = document.get_mut(first) {
+                set_path(inner, rest, value);
+            }
+        }
+    }
+}
+
+fn remove_path(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            document.remove(path);
+        }
+        Some((first, rest)) => {
+            if let Some(Bson::Document(inner)) = document.get_mut(first) {
+                remove_path(inner, rest);
+            }
+        }
+    }
+}
+
+/// the array at the path, which is created if the field is missing
+fn array_at<'a>(document: &'a mut Document, path: &str) -> Option<&'a mut Vec<Bson>> {
+    if get_path(document, path).is_none() {
+        set_path(document, path, Bson::Array(Vec::new()));
+    }
+    let (parent, field) = match path.rsplit_once('.') {
+        Some((parent, field)) => (parent_document(document, parent)?, field),
+        None => (document, path),
+    };
+    match parent.get_mut(field) {
+        Some(Bson::Array(items)) => Some(items),
+        _ => None,
+    }
+}
+
+fn parent_document<'a>(document: &'a mut Document, path: &str) -> Option<&'a mut Document> {
+    let mut current = document;
+    for field in path.split('.') {
+        current = match current.get_mut(field) {
+            Some(Bson::Document(inner)) => inner,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+fn apply_update(document: &mut Document, update: &Document, inserting: bool) {
+    for (operator, fields) in update {
+        let Bson::Document(fields) = fields else {
+            continue;
+        };
+        for (path, value) in fields {
+            match operator.as_str() {
+                "$set" => set_path(document, path, value.clone()),
+                "$setOnInsert" if inserting => set_path(document, path, value.clone()),
+                "$unset" => remove_path(document, path),
+                "$inc" => {
+                    let sum = match get_path(document, path) {
+                        Some(Bson::Int32(old)) => match value {
+                            Bson::Int32(value) => Bson::Int32(old + value),
+                            _ => Bson::Int64(*old as i64 + as_number(value).unwrap_or(0.0) as i64),
+                        },
+                        Some(Bson::Int64(old)) => {
+                            Bson::Int64(old + as_number(value).unwrap_or(0.0) as i64)
+                        }
+                        Some(Bson::Double(old)) => {
+                            Bson::Double(old + as_number(value).unwrap_or(0.0))
+                        }
+                        _ => value.clone(),
+                    };
+                    set_path(document, path, sum);
+                }
+                "$push" => {
+                    if let Some(items) = array_at(document, path) {
+                        items.push(value.clone());
+                    }
+                }
+                "$addToSet" => {
+                    if let Some(items) = array_at(document, path)
+                        && !items.iter().any(|item| equals(item, value))
+                    {
+                        items.push(value.clone());
+                    }
+                }
+                "$pull" => {
+                    if let Some(items) = array_at(document, path) {
+                        items.retain(|item| match (item, value) {
+                            (Bson::Document(item), Bson::Document(condition)) => {
+                                !matches(item, condition)
+                            }
+                            _ => !value_matches(Some(item), value),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn query_and_update() {
+        let store = Arc::new(MemoryStore::default());
+        let users = store.collection("users");
+        let id = ObjectId::new();
+        users.insert(doc! {"_id": id, "username": "a", "delegates": [], "version": 0_i64});
+        users.insert(doc! {"username": "b", "event": {"uid": "x", "cancellation": null}});
+
+        assert_eq!(users.find(&doc! {"username": "a"}, None).len(), 1);
+        assert_eq!(
+            users
+                .find(&doc! {"version": {"$in": [0_i64, null]}}, None)
+                .len(),
+            2
+        );
+        assert_eq!(
+            users.find(&doc! {"event.cancellation": null}, None).len(),
+            2
+        );
+        assert_eq!(
+            users
+                .find(&doc! {"event.uid": {"$exists": true}}, None)
+                .len(),
+            1
+        );
+        let sorted = users.find(&doc! {}, Some(&doc! {"username": -1}));
+        assert_eq!(sorted[0].get_str("username").unwrap(), "b");
+
+        let delegate = ObjectId::new();
+        let update = doc! {"$addToSet": {"delegates": delegate}, "$inc": {"version": 1_i64}};
+        let count = users.update(&doc! {"_id": id}, &update, false, false);
+        assert_eq!(
+            count,
+            UpdateCount {
+                matched: 1,
+                modified: 1
+            }
+        );
+        users.update(&doc! {"_id": id}, &update, false, false);
+        let user = &users.find(&doc! {"delegates": delegate}, None)[0];
+        assert_eq!(user.get_array("delegates").unwrap().len(), 1);
+        assert_eq!(user.get_i64("version").unwrap(), 2);
+
+        users.update(
+            &doc! {"_id": id},
+            &doc! {"$pull": {"delegates": delegate}},
+            false,
+            false,
+        );
+        assert!(users.find(&doc! {"delegates": delegate}, None).is_empty());
+        assert_eq!(users.delete(&doc! {}, true), 2);
+    }
+}
@@ -0,0 +1,417 @@
+//! migration.rs - versioned changes of the stored documents
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! documents written by older versions miss fields that were added later and
+//! cannot be deserialized anymore. Every change of the schema gets a [Migration]
+//! in [MIGRATIONS] with the next version. The pending ones are applied at ignite
+//! by [migrate], in the database of every tenant, and recorded in the migrations
+//! collection. A migration can be interrupted, so it has to be idempotent.
+//!
+//! The migrations can also be checked without starting the server:
+//! ```text
+//! usermanagement migrate status   # which migrations were applied
+//! usermanagement migrate dry-run  # how many documents the pending ones would change
+//! usermanagement migrate up       # applies the pending migrations
+//! ```
+use crate::database::{self, Collection, DatabaseUtils, Storage};
+use crate::event::{Event, StoredEvent};
+use crate::tenant;
+use bson::oid::ObjectId;
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Local};
+use rocket::{fairing, Build, Phase, Rocket};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+
+type MigrationFuture<'a> = Pin<Box<dyn Future<Output = Result<u64, database::Error>> + Send + 'a>>;
+
+/// a change of the stored documents
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    /// applies the change and returns the number of changed documents.
+    /// With dry_run nothing is written and the number of documents that
+    /// would be changed is returned
+    up: for<'a> fn(&'a Storage, bool) -> MigrationFuture<'a>,
+}
+
+/// all migrations, ordered by their version
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "add user permissions",
+        up: add_user_permissions,
+    },
+    Migration {
+        version: 2,
+        name: "add room layouts and owner",
+        up: add_room_layouts,
+    },
+    Migration {
+        version: 3,
+        name: "move embedded events into the events collection",
+        up: move_embedded_events,
+    },
+    Migration {
+        version: 4,
+        name: "store the creation of notifications as dates",
+        up: notification_dates,
+    },
+];
+
+/// the record of an applied [Migration]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    #[serde(rename = "_id")]
+    version: i64,
+    name: String,
+    applied: DateTime<Local>,
+    changed: i64,
+}
+
+/// the migrations that were not applied to the database yet
+pub async fn pending(db: &Storage) -> Result<Vec<&'static Migration>, database::Error> {
+    let applied = applied(db).await?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.iter().any(|a| a.version == migration.version))
+        .collect())
+}
+
+/// the migrations recorded in the database
+pub async fn applied(db: &Storage) -> Result<Vec<AppliedMigration>, database::Error> {
+    let mut applied = Vec::new();
+    let mut cursor = db.migration_collection().find(None, None).await?;
+    while cursor.advance().await? {
+        applied.push(cursor.deserialize_current()?);
+    }
+    applied.sort_by_key(|migration| migration.version);
+    Ok(applied)
+}
+
+/// applies the pending migrations in order and returns them with the number of
+/// changed documents. With dry_run nothing is written or recorded
+pub async fn run(
+    db: &Storage,
+    dry_run: bool,
+) -> Result<Vec<(&'static Migration, u64)>, database::Error> {
+    let mut report = Vec::new();
+    for migration in pending(db).await? {
+        let changed = (migration.up)(db, dry_run).await?;
+        if !dry_run {
+            let applied = AppliedMigration {
+                version: migration.version,
+                name: String::from(migration.name),
+                applied: Local::now(),
+                changed: changed as i64,
+            };
+            db.migration_collection().insert_one(&applied, None).await?;
+        }
+        report.push((migration, changed));
+    }
+    Ok(report)
+}
+
+/// A fairing that applies the pending migrations to the database of every tenant
+pub async fn migrate(rocket: Rocket<Build>) -> fairing::Result {
+    let storages = tenant::storages(&rocket);
+    if storages.is_empty() {
+        return Err(rocket);
+    }
+    for db in &storages {
+        match run(db, false).await {
+            Ok(report) => {
+                for (migration, changed) in report {
+                    println!(
+                        "applied migration {} \"{}\" to {}, {} changes",
+                        migration.version,
+                        migration.name,
+                        db.database_name(),
+                        changed
+                    );
+                }
+            }
+            Err(err) => {
+                println!("migration of {} failed: {:?}", db.database_name(), err);
+                return Err(rocket);
+            }
+        }
+    }
+    Ok(rocket)
+}
+
+/// the `migrate` command, the rocket needs the storage and the tenants attached
+pub async fn cli<P: Phase>(rocket: &Rocket<P>, command: Option<&str>) -> Result<(), String> {
+    let storages = tenant::storages(rocket);
+    if storages.is_empty() {
+        return Err(String::from("the storage is not attached"));
+    }
+    for db in &storages {
+        println!("{}:", db.database_name());
+        match command {
+            Some("status") => {
+                let applied = applied(db).await.map_err(|err| format!("{:?}", err))?;
+                for migration in MIGRATIONS {
+                    match applied.iter().find(|a| a.version == migration.version) {
+                        Some(a) => println!(
+                            "  {} {}: applied {}, {} changes",
+                            migration.version, migration.name, a.applied, a.changed
+                        ),
+                        None => println!("  {} {}: pending", migration.version, migration.name),
+                    }
+                }
+            }
+            Some(command @ ("dry-run" | "up")) => {
+                let dry_run = command == "dry-run";
+                let report = run(db, dry_run).await.map_err(|err| format!("{:?}", err))?;
+                if report.is_empty() {
+                    println!("  nothing to do");
+                }
+                for (migration, changed) in report {
+                    let verb = if dry_run { "would change" } else { "changed" };
+                    println!(
+                        "  {} {}: {} {} documents",
+                        migration.version, migration.name, verb, changed
+                    );
+                }
+            }
+            _ => return Err(String::from("usage: migrate status|dry-run|up")),
+        }
+    }
+    Ok(())
+}
+
+/// sets the fields that are missing in the documents of the collection to their default
+async fn set_missing<T>(
+    collection: &Collection<T>,
+    defaults: Document,
+    dry_run: bool,
+) -> Result<u64, database::Error> {
+    let mut changed = 0;
+    for (field, value) in defaults {
+        let filter = doc! {&field: {"$exists": false}};
+        changed += if dry_run {
+            collection.count_documents(filter, None).await?
+        } else {
+            let update = doc! {"$set": {field: value}};
+            collection
+                .update_many(filter, update, None)
+                .await?
+                .modified_count
+        };
+    }
+    Ok(changed)
+}
+
+fn add_user_permissions(db: &Storage, dry_run: bool) -> MigrationFuture<'_> {
+    Box::pin(async move {
+        let defaults = doc! {
+            "is_active": true,
+            "is_admin": false,
+            "can_create_users": false,
+            "editable_rooms": [],
+        };
+        set_missing(&db.user_collection(), defaults, dry_run).await
+    })
+}
+
+/// the epapers and layouts are stored as rooms as well
+fn add_room_layouts(db: &Storage, dry_run: bool) -> MigrationFuture<'_> {
+    Box::pin(async move {
+        let defaults = doc! {
+            "layouts": [],
+            "layout_values": {},
+            "owner": Bson::Null,
+        };
+        let mut changed = 0;
+        for collection in [
+            db.room_collection(),
+            db.epaper_collection(),
+            db.layout_collection(),
+        ] {
+            changed += set_missing(&collection, defaults.clone(), dry_run).await?;
+        }
+        Ok(changed)
+    })
+}
+
+/// the fields of a room document that were moved to the events collection
+#[derive(Deserialize)]
+struct EmbeddedEvents {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    #[serde(default)]
+    events: Vec<Event>,
+    #[serde(default)]
+    cancelled_events: Vec<Event>,
+}
+/// events that were already moved are not inserted again, so it can be rerun after a failure
+fn move_embedded_events(db: &Storage, dry_run: bool) -> MigrationFuture<'_> {
+    Box::pin(async move {
+        let rooms = db.get_collection::<EmbeddedEvents>("rooms");
+        let filter = doc! {"$or": [
+            {"events": {"$exists": true}},
+            {"cancelled_events": {"$exists": true}},
+        ]};
+        let upsert = mongodb::options::UpdateOptions::builder()
+            .upsert(true)
+            .build();
+        // like the real run, the dry run counts the events and not the rooms
+        let mut count = 0;
+        let mut cursor = rooms.find(filter, None).await?;
+        while cursor.advance().await? {
+            let room = cursor.deserialize_current()?;
+            if dry_run {
+                count += (room.events.len() + room.cancelled_events.len()) as u64;
+                continue;
+            }
+            for event in room.events.into_iter().chain(room.cancelled_events) {
+                let stored = StoredEvent::create(room.id, event);
+                db.event_collection()
+                    .update_one(
+                        doc! {"room_id": room.id, "event.uid": &stored.event.uid()},
+                        doc! {"$setOnInsert": bson::to_document(&stored)?},
+                        upsert.clone(),
+                    )
+                    .await?;
+                count += 1;
+            }
+            rooms
+                .update_one(
+                    doc! {"_id": room.id},
+                    doc! {"$unset": {"events": "", "cancelled_events": ""}},
+                    None,
+                )
+                .await?;
+        }
+        Ok(count)
+    })
+}
+
+/// notifications were created with the time as RFC 3339 string, which is not sorted by time
+fn notification_dates(db: &Storage, dry_run: bool) -> MigrationFuture<'_> {
+    Box::pin(async move {
+        let notifications = db.get_collection::<Document>("notifications");
+        let mut changed = 0;
+        let mut cursor = notifications.find(doc! {}, None).await?;
+        while cursor.advance().await? {
+            let notification = cursor.deserialize_current()?;
+            let (Ok(id), Ok(created)) = (
+                notification.get_object_id("_id"),
+                notification.get_str("created"),
+            ) else {
+                continue;
+            };
+            let Ok(created) = DateTime::parse_from_rfc3339(created) else {
+                continue;
+            };
+            changed += 1;
+            if dry_run {
+                continue;
+            }
+            let created = bson::DateTime::from_chrono(created.with_timezone(&chrono::Utc));
+            notifications
+                .update_one(doc! {"_id": id}, doc! {"$set": {"created": created}}, None)
+                .await?;
+        }
+        Ok(changed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseConnection;
+    use crate::user::User;
+
+    #[test]
+    fn versions_are_ordered() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+    }
+
+    #[tokio::test]
+    async fn migrate_old_users() {
+        let db = Storage::memory();
+        db.get_collection::<Document>("users")
+            .insert_one(
+                doc! {
+                    "username": "Mustermann.Max",
+                    "password_hash": "",
+                    "firstname": "Max",
+                    "surname": "Mustermann",
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(User::getfromdb_name("Mustermann.Max", &db).await.is_err());
+
+        let report = run(&db, true).await.unwrap();
+        assert_eq!(report[0].1, 4);
+        assert_eq!(pending(&db).await.unwrap().len(), MIGRATIONS.len());
+
+        run(&db, false).await.unwrap();
+        let user = User::getfromdb_name("Mustermann.Max", &db).await.unwrap();
+        assert!(!user.is_admin());
+        assert!(pending(&db).await.unwrap().is_empty());
+        assert!(run(&db, false).await.unwrap().is_empty());
+    }
+    #[tokio::test]
+    async fn dry_run_counts_moved_events() {
+        let db = Storage::memory();
+        let start = Local::now();
+        let event = |hours| {
+            Event::create(
+                String::from("booker"),
+                String::from("headline"),
+                None,
+                start + chrono::Duration::hours(hours),
+                start + chrono::Duration::hours(hours + 1),
+            )
+            .unwrap()
+        };
+        let events = bson::to_bson(&vec![event(1), event(3)]).unwrap();
+        db.get_collection::<Document>("rooms")
+            .insert_one(doc! {"name": "A101", "events": events}, None)
+            .await
+            .unwrap();
+        assert_eq!(move_embedded_events(&db, true).await.unwrap(), 2);
+        assert_eq!(move_embedded_events(&db, false).await.unwrap(), 2);
+        assert_eq!(move_embedded_events(&db, true).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn notification_dates_are_converted() {
+        let db = Storage::memory();
+        db.get_collection::<Document>("notifications")
+            .insert_one(
+                doc! {
+                    "user_id": ObjectId::new(),
+                    "created": "2023-05-01T12:00:00+02:00",
+                    "message": "Hallo",
+                    "read": false,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(db
+            .notification_collection()
+            .find_one(doc! {}, None)
+            .await
+            .is_err());
+        assert_eq!(notification_dates(&db, true).await.unwrap(), 1);
+        assert_eq!(notification_dates(&db, false).await.unwrap(), 1);
+        assert!(db
+            .notification_collection()
+            .find_one(doc! {}, None)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(notification_dates(&db, true).await.unwrap(), 0);
+    }
+}
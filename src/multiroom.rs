@@ -0,0 +1,236 @@
+//! multiroom.rs - one event booked in several rooms at once, e.g. for exams
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! the events of such a booking share their [Event::link], so they can be
+//! edited and cancelled together. As the database does not necessarily run as a
+//! replica set, no transaction is used: the events are inserted one after another
+//! and already inserted events are rolled back if one of them fails.
+use crate::booking::{BookingContext, BookingDefaults};
+use crate::database::{self, Connection, DatabaseConnection, DatabaseUtils, Storage};
+use crate::error::{self, Error};
+use crate::event::{self, Cancellation, Event};
+use crate::input::Input;
+use crate::room::{notify_cancelled, split_list, Room};
+use crate::{user, waitlist, MainDatabase};
+use bson::doc;
+use bson::oid::ObjectId;
+use chrono::Local;
+use rocket::{Route, State};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, FromForm)]
+struct BookForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    /// comma separated room names
+    rooms: String,
+    headline: String,
+    description: Option<String>,
+    start: String,
+    stop: String,
+    /// username of the organizer, see /room/add_event
+    on_behalf_of: Option<String>,
+}
+/// books the event in all rooms or in none of them. Returns the link of the events,
+/// or 409 Conflict with the violated rule and the room in the details
+#[post("/book", data = "<form>")]
+async fn book(
+    form: Input<BookForm<'_>>,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<String, Error> {
+    let user = form.userdata.login(&db).await?;
+    let on_behalf_of = match &form.on_behalf_of {
+        Some(username) => Some(user::User::getfromdb_name(username, &db).await?),
+        None => None,
+    };
+    let organizer = on_behalf_of.as_ref().unwrap_or(&user);
+    if !user.can_act_for(organizer) {
+        return Err(Error::Forbidden("you cannot book on behalf of this user"));
+    }
+    let start = error::parse_time("start", &form.start)?;
+    let stop = error::parse_time("stop", &form.stop)?;
+    let mut names = split_list(&form.rooms);
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+        return Err(Error::Invalid(String::from("rooms cannot be empty")));
+    }
+
+    let link = ObjectId::new().to_hex();
+    let mut rooms = Vec::new();
+    // every room is checked before anything is written
+    for name in &names {
+        let mut room = Room::getfromdb_name(name, &db).await?;
+        let status = room
+            .booking_status(organizer)
+            .ok_or(Error::Forbidden("you cannot book one of the rooms"))?;
+        let event = Event::create(
+            organizer.id().to_hex(),
+            form.headline.clone(),
+            form.description.clone(),
+            start,
+            stop,
+        )
+        .ok_or_else(|| {
+            Error::Invalid(String::from(
+                "the headline must be a single line and the event needs to end after its start",
+            ))
+        })?
+        .with_creator(user.id().to_hex())
+        .with_link(link.clone())
+        .with_status(status);
+        let context = BookingContext::load(&room, &organizer.id().to_hex(), defaults, &db).await?;
+        room.load_events(start, stop, false, &db).await?;
+        if let Err(err) = room.check_event(&event, &context) {
+            return Err(Error::booking_in(err, name));
+        }
+        rooms.push((room, event, context));
+    }
+    for (mut room, event, context) in rooms {
+        let booked = room.book_event(event, &context, &db).await;
+        if !matches!(booked, Ok(Ok(()))) {
+            rollback(&link, &db).await;
+        }
+        match booked {
+            Ok(Ok(())) => {}
+            // booked by someone else in the meantime
+            Ok(Err(err)) => return Err(Error::booking_in(err, room.name())),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(link)
+}
+
+/// removes the linked events that were already inserted
+async fn rollback(link: &str, db: &Storage) {
+    if let Err(err) = db
+        .event_collection()
+        .delete_many(doc! {"event.link": link}, None)
+        .await
+    {
+        println!("could not roll back {}: {:?}", link, err);
+    }
+}
+
+/// all rooms containing events with the link, with these events loaded
+async fn linked_rooms(link: &str, db: &Storage) -> Result<Vec<Room>, database::Error> {
+    let room_ids = db
+        .event_collection()
+        .distinct("room_id", doc! {"event.link": link}, None)
+        .await?;
+    let mut rooms = Vec::new();
+    for room_id in room_ids {
+        let Some(room_id) = room_id.as_object_id() else {
+            continue;
+        };
+        let mut room = Room::getfromdb_id(&room_id, db).await?;
+        room.load_events_where(doc! {"event.link": link, "event.cancellation": null}, db)
+            .await?;
+        rooms.push(room);
+    }
+    Ok(rooms)
+}
+
+/// whether the user can change the events with the link, i.e. can act for the booker
+async fn can_change(user: &user::User, rooms: &[Room], link: &str, db: &Storage) -> bool {
+    let Some(booker_id) = rooms
+        .iter()
+        .flat_map(|room| room.events())
+        .find(|event| event.link() == Some(link))
+        .map(|event| event.booker_id().to_string())
+    else {
+        return false;
+    };
+    user.can_act_for_id(&booker_id, db).await
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct EditForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    link: String,
+    headline: Option<String>,
+    description: Option<String>,
+}
+/// changes the headline and the description of all linked events
+#[post("/edit", data = "<form>")]
+async fn edit(form: Input<EditForm<'_>>, db: Connection<MainDatabase>) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    if let Some(headline) = &form.headline
+        && !event::is_valid_headline(headline)
+    {
+        return Err(Error::Invalid(String::from(
+            "the headline must be a non-empty single line",
+        )));
+    }
+    let mut rooms = linked_rooms(&form.link, &db).await?;
+    if rooms.is_empty() {
+        return Err(Error::NotFound("no events with the link"));
+    }
+    if !can_change(&user, &rooms, &form.link, &db).await {
+        return Err(Error::Forbidden("you cannot change these events"));
+    }
+    for room in &mut rooms {
+        for uid in room.linked_uids(&form.link) {
+            room.update_event(&uid, &db, |event| {
+                if let Some(headline) = &form.headline {
+                    event.set_headline(headline.clone());
+                }
+                if form.description.is_some() {
+                    event.set_description(form.description.clone());
+                }
+                Some(())
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct CancelForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    link: String,
+    reason: Option<String>,
+}
+/// cancels all linked events, their time is offered to the waitlists
+#[post("/cancel", data = "<form>")]
+async fn cancel(
+    form: Input<CancelForm<'_>>,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut rooms = linked_rooms(&form.link, &db).await?;
+    if rooms.is_empty() {
+        return Err(Error::NotFound("no events with the link"));
+    }
+    if !can_change(&user, &rooms, &form.link, &db).await {
+        return Err(Error::Forbidden("you cannot cancel these events"));
+    }
+    let cancellation = Cancellation {
+        by: user.id().to_hex(),
+        at: Local::now(),
+        reason: form.reason.clone(),
+    };
+    for room in &mut rooms {
+        let cancelled = room.cancel_linked(&form.link, &cancellation);
+        for event in &cancelled {
+            room.save_event(event, &db).await?;
+        }
+        for event in &cancelled {
+            notify_cancelled(room, event, &db).await;
+            if let Err(err) = waitlist::offer_slot(room, event, defaults, &db).await {
+                println!("could not offer the slot to the waitlist: {:?}", err);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![book, edit, cancel]
+}
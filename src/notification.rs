@@ -0,0 +1,113 @@
+//! notification.rs - messages to users, e.g. about the decision on a booking request
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+use crate::database::{self, Connection, DatabaseConnection, DatabaseUtils, Storage};
+use crate::error::Error;
+use crate::input::Input;
+use crate::{user, MainDatabase};
+use bson::doc;
+use bson::oid::ObjectId;
+use chrono::{DateTime, Local};
+use rocket::serde::json::Json;
+use rocket::Route;
+use serde::*;
+
+/// created is stored as BSON date, so that the notifications are sorted by time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Notification {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    user_id: ObjectId,
+    created: bson::DateTime,
+    message: String,
+    read: bool,
+}
+
+/// a [Notification] as it is sent to the user
+#[derive(Debug, Serialize)]
+pub struct NotificationInfo {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    user_id: ObjectId,
+    created: DateTime<Local>,
+    message: String,
+    read: bool,
+}
+impl From<Notification> for NotificationInfo {
+    fn from(notification: Notification) -> Self {
+        NotificationInfo {
+            id: notification.id,
+            user_id: notification.user_id,
+            created: notification.created.to_chrono().with_timezone(&Local),
+            message: notification.message,
+            read: notification.read,
+        }
+    }
+}
+
+/// stores a new unread notification for the user.
+/// user_id is the hex representation used by [crate::event::Event::booker_id]
+pub async fn notify(user_id: &str, message: String, db: &Storage) -> Result<(), database::Error> {
+    let Ok(user_id) = ObjectId::parse_str(user_id) else {
+        // e.g. events created by tests
+        return Err(database::Error::NotFound);
+    };
+    let notification = Notification {
+        id: ObjectId::new(),
+        user_id,
+        created: bson::DateTime::now(),
+        message,
+        read: false,
+    };
+    db.notification_collection()
+        .insert_one(notification, None)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct ListForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    /// also list notifications that were already read
+    all: Option<bool>,
+}
+/// lists the notifications of the user as json, newest first,
+/// and marks the listed ones as read
+#[post("/list", data = "<form>")]
+async fn list(
+    form: Input<ListForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<NotificationInfo>>, Error> {
+    let user = form.userdata.login(&db).await?;
+    let filter = if form.all == Some(true) {
+        doc! {"user_id": user.id()}
+    } else {
+        doc! {"user_id": user.id(), "read": false}
+    };
+    let options = mongodb::options::FindOptions::builder()
+        .sort(doc! {"created": -1})
+        .build();
+    let mut notifications = Vec::new();
+    let mut cursor = db.notification_collection().find(filter, options).await?;
+    while cursor.advance().await? {
+        notifications.push(NotificationInfo::from(cursor.deserialize_current()?));
+    }
+    // notifications created while listing are left unread
+    let ids: Vec<ObjectId> = notifications
+        .iter()
+        .map(|notification| notification.id)
+        .collect();
+    db.notification_collection()
+        .update_many(
+            doc! {"_id": {"$in": ids}},
+            doc! {"$set": {"read": true}},
+            None,
+        )
+        .await?;
+    Ok(Json(notifications))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list]
+}
@@ -1,35 +1,115 @@
 use crate::{
+    booking::{
+        self, Blackout, BookingContext, BookingDefaults, BookingError, BookingPolicy,
+        HolidayCalendar, OpeningHours,
+    },
     database::{self, DatabaseUtils},
     debug_println,
-    event::{self, Event},
-    user, MainDatabase,
+    error::{self, Error},
+    event::{self, Cancellation, Event, EventStatus, StoredEvent},
+    image,
+    input::Input,
+    notification, user, waitlist, MainDatabase,
 };
 use bson::doc;
 use bson::oid::ObjectId;
 
-use crate::database::DatabaseConnection;
+use crate::database::{Collection, Connection, DatabaseConnection, Storage};
 use chrono::{DateTime, Local, TimeZone, Timelike};
-use rocket::form::Form;
-use rocket::Route;
-use rocket_db_pools::Connection;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
 use serde::*;
 use std::collections::BTreeSet;
 
 use std::collections::HashMap;
 
+/// how many minutes before its start an event can be checked in to
+pub const CHECK_IN_EARLY_MINUTES: i64 = 15;
+/// the field of the room documents that counts the committed bookings, see
+/// [Room::commit_booking]. It is not part of [Room], so that [DatabaseConnection::update]
+/// does not overwrite it with the value the room was loaded with
+const BOOKING_SEQUENCE: &str = "booking_sequence";
+/// the longest time a slot can be held, a day
+const MAX_HOLD_MINUTES: i64 = 24 * 60;
+/// the latest an event can be released after its start, a day
+const MAX_RELEASE_MINUTES: i64 = 24 * 60;
+/// the longest setup and teardown time of a room, a day
+const MAX_TURNOVER_MINUTES: i64 = 24 * 60;
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 //todo: use a BTreeMap instead of a LinkedList or a BTreeSet
 pub struct Room {
     #[serde(rename = "_id")]
     id: ObjectId,
     name: String,
-    //TODO: use btreemap instead
-    //events: LinkedList<Event>,
+    // the events are stored in the events collection, this only holds
+    // the ones loaded with e.g. [Room::load_events]
+    #[serde(skip)]
     events: BTreeSet<Event>,
     layouts: Vec<ObjectId>,
     layout_values: HashMap<String, String>,
     owner: Option<ObjectId>,
     description: Option<String>,
+    // attributes used to search for rooms
+    #[serde(default)]
+    capacity: Option<u32>,
+    #[serde(default)]
+    building: Option<String>,
+    #[serde(default)]
+    floor: Option<i32>,
+    #[serde(default)]
+    equipment: Vec<String>, // e.g. "projector", "whiteboard"
+    #[serde(default)]
+    accessibility: Accessibility,
+    #[serde(default)]
+    photos: Vec<String>, // file names in the images of the tenant
+    // when the room can be booked
+    #[serde(default)]
+    opening_hours: Option<OpeningHours>, // None uses the global default
+    #[serde(default)]
+    blackouts: Vec<Blackout>,
+    #[serde(default)]
+    holiday_calendars: Vec<ObjectId>,
+    // who can book the room for how long
+    #[serde(default)]
+    policy: Option<BookingPolicy>, // None uses the policy of the group
+    #[serde(default)]
+    group: Option<ObjectId>,
+    // time needed to prepare the room before and clean it up after every event
+    #[serde(default)]
+    setup_minutes: i64,
+    #[serde(default)]
+    teardown_minutes: i64,
+    // bookings of users that are not owner or manager need to be approved
+    #[serde(default)]
+    requires_approval: bool,
+    #[serde(default)]
+    managers: Vec<ObjectId>,
+    // events nobody checked in to are released this many minutes after their start
+    #[serde(default)]
+    release_after_minutes: Option<i64>,
+    // cancelled events, they do not block the room anymore
+    #[serde(skip)]
+    cancelled_events: Vec<Event>,
+    #[serde(default)]
+    version: i64,
+}
+
+/// accessibility flags of a [Room]
+#[derive(Default, Debug, Clone, Serialize, Deserialize, FromForm)]
+#[serde(default)]
+pub struct Accessibility {
+    pub step_free: bool,
+    pub accessible_toilet: bool,
+    pub hearing_loop: bool,
+}
+impl Accessibility {
+    /// every flag set in required also needs to be set in self
+    pub fn fulfills(&self, required: &Accessibility) -> bool {
+        (self.step_free || !required.step_free)
+            && (self.accessible_toilet || !required.accessible_toilet)
+            && (self.hearing_loop || !required.hearing_loop)
+    }
 }
 impl database::DatabaseConnection for Room {
     #[inline]
@@ -37,7 +117,15 @@ impl database::DatabaseConnection for Room {
         self.id
     }
     #[inline]
-    fn collection(db: &mongodb::Client) -> mongodb::Collection<Self> {
+    fn version(&self) -> i64 {
+        self.version
+    }
+    #[inline]
+    fn set_version(&mut self, version: i64) {
+        self.version = version;
+    }
+    #[inline]
+    fn collection(db: &Storage) -> Collection<Self> {
         db.room_collection()
     }
     #[inline]
@@ -61,122 +149,720 @@ impl Room {
             layout_values: HashMap::new(),
             owner: None,
             description: None,
+            capacity: None,
+            building: None,
+            floor: None,
+            equipment: Vec::new(),
+            accessibility: Accessibility::default(),
+            photos: Vec::new(),
+            opening_hours: None,
+            blackouts: Vec::new(),
+            holiday_calendars: Vec::new(),
+            policy: None,
+            group: None,
+            setup_minutes: 0,
+            teardown_minutes: 0,
+            requires_approval: false,
+            managers: Vec::new(),
+            release_after_minutes: None,
+            cancelled_events: Vec::new(),
+            version: 0,
+        }
+    }
+
+    /// admins, the owner and the managers of the room can approve bookings
+    pub fn can_approve(&self, user: &user::User) -> bool {
+        user.is_admin() || self.owner == Some(user.id()) || self.managers.contains(&user.id())
+    }
+    /// the booking rules, i.e. the policy, opening hours, blackouts, turnover, approval,
+    /// release and managers, are only changed by admins and the owner of the room,
+    /// users that can just book the room cannot lift its limits
+    pub fn can_change_rules(&self, user: &user::User) -> bool {
+        user.is_admin() || self.owner == Some(user.id())
+    }
+    /// the status of events booked by the user, or [None] if the user cannot book the room.
+    /// Rooms that require approval can be requested by every user.
+    pub fn booking_status(&self, user: &user::User) -> Option<EventStatus> {
+        if self.requires_approval {
+            if self.can_approve(user) {
+                return Some(EventStatus::Confirmed);
+            }
+            return Some(EventStatus::Pending);
+        }
+        if user.can_edit_room(&self.id) {
+            return Some(EventStatus::Confirmed);
+        }
+        None
+    }
+    /// changes the status of the event with the uid
+    pub fn set_event_status(&mut self, uid: &str, status: EventStatus) -> Option<()> {
+        let event = self.remove_event_uid(uid)?;
+        self.events.insert(event.with_status(status));
+        Some(())
+    }
+    /// changes the event with the uid in place.
+    /// The start must not be changed, as the events are ordered by it.
+    pub fn modify_event<T>(&mut self, uid: &str, f: impl FnOnce(&mut Event) -> T) -> Option<T> {
+        let mut event = self.remove_event_uid(uid)?;
+        let ret = f(&mut event);
+        self.events.insert(event);
+        Some(ret)
+    }
+    /// removes the event with the uid and returns it
+    pub fn remove_event_uid(&mut self, uid: &str) -> Option<Event> {
+        let event = self.get_event_uid(uid)?.clone();
+        self.events.take(&event)
+    }
+
+    pub fn capacity(&self) -> Option<u32> {
+        self.capacity
+    }
+    pub fn holiday_calendars(&self) -> &Vec<ObjectId> {
+        &self.holiday_calendars
+    }
+    pub fn policy(&self) -> Option<&BookingPolicy> {
+        self.policy.as_ref()
+    }
+    pub fn set_policy(&mut self, policy: Option<BookingPolicy>) {
+        self.policy = policy;
+    }
+    pub fn group(&self) -> Option<ObjectId> {
+        self.group
+    }
+    pub fn set_group(&mut self, group: Option<ObjectId>) {
+        self.group = group;
+    }
+    /// values stored before they were limited are clamped, so they cannot overflow
+    pub fn setup(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.setup_minutes.clamp(0, MAX_TURNOVER_MINUTES))
+    }
+    pub fn teardown(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.teardown_minutes.clamp(0, MAX_TURNOVER_MINUTES))
+    }
+    /// the minimal time between the stop of one event and the start of the next one
+    pub fn turnover(&self) -> chrono::Duration {
+        self.setup() + self.teardown()
+    }
+
+    /// looks up, wether the room has all attributes required by the filter.
+    /// Rooms without a capacity do not match a minimal capacity.
+    pub fn matches(&self, filter: &RoomFilter) -> bool {
+        if let Some(min_capacity) = filter.min_capacity {
+            if self.capacity.unwrap_or(0) < min_capacity {
+                return false;
+            }
+        }
+        if let Some(building) = &filter.building {
+            if self.building.as_ref() != Some(building) {
+                return false;
+            }
+        }
+        if filter.floor.is_some() && self.floor != filter.floor {
+            return false;
+        }
+        if !self.accessibility.fulfills(&filter.accessibility) {
+            return false;
+        }
+        filter
+            .equipment
+            .iter()
+            .all(|item| self.equipment.contains(item))
+    }
+
+    /// returns all intervals between start and stop in which no event takes place
+    /// and that are at least as long as duration
+    pub fn free_intervals(
+        &self,
+        start: DateTime<Local>,
+        stop: DateTime<Local>,
+        duration: chrono::Duration,
+    ) -> Vec<Interval> {
+        let mut ret = Vec::new();
+        let mut free_since = start;
+        // an event needs the turnover time to the events before and after it
+        let turnover = self.turnover();
+        // the ordering of the events makes it possible to walk through the gaps
+        for event in self.events.range(..Event::create_dummy(stop + turnover)) {
+            if event.stop() + turnover <= free_since {
+                continue;
+            }
+            if event.start() - turnover > free_since {
+                ret.push(Interval {
+                    start: free_since,
+                    stop: event.start() - turnover,
+                });
+            }
+            free_since = event.stop() + turnover;
+        }
+        if free_since < stop {
+            ret.push(Interval {
+                start: free_since,
+                stop,
+            });
         }
+        ret.retain(|interval| interval.stop - interval.start >= duration);
+        ret
     }
 
     /// looks up, wether [Room] could accomodate the given event
     /// accomodate means in this context that there is no overlaping event in
-    /// the time slot. The event can parital overlap with another event, e.g. one event can end at 12:00 while the other starts at 12:00
+    /// the time slot, including the setup and teardown time of both events.
+    /// The event can parital overlap with another event, e.g. without setup and teardown time
+    /// one event can end at 12:00 while the other starts at 12:00
     pub fn could_accomodate(&self, event: &Event) -> bool {
         if event.isdummy() {
             return false;
         }
+        let turnover = self.turnover();
         for currentevent in &self.events {
-            if !(currentevent.start() >= event.stop() || currentevent.stop() <= event.start()) {
-                return false;
-            }
-            if currentevent.stop() <= event.start() {
+            // all following events start even later
+            if currentevent.start() - turnover >= event.stop() {
                 return true;
             }
+            if currentevent.stop() + turnover > event.start() {
+                return false;
+            }
         }
         true
     }
-    /// add an event to the room. returns [None] if the Event is already present or
-    /// it would overlap with any event. Panics if the event is a dummy
-    pub fn add_event(&mut self, event: Event) -> Option<()> {
-        if event.isdummy() {
-            // as this should never happen, it not just returns None
-            panic!("tried to add an event that is a dummy")
+    /// checks the event against the opening hours and blackouts of the room
+    /// and the context, the policy of the context, and whether the room could accomodate it.
+    /// The opening hours of the room take precedence over the ones of the context.
+    pub fn check_event(&self, event: &Event, context: &BookingContext) -> Result<(), BookingError> {
+        if let Some(opening_hours) = self
+            .opening_hours
+            .as_ref()
+            .or(context.opening_hours.as_ref())
+        {
+            opening_hours.check(event)?;
+        }
+        for blackout in self.blackouts.iter().chain(&context.blackouts) {
+            blackout.check(event)?;
         }
-        if !self.could_accomodate(&event) {
-            return None;
+        context.check_policy(event)?;
+        if !self.could_accomodate(event) {
+            return Err(BookingError::Overlap);
+        }
+        Ok(())
+    }
+    /// add an event to the room. returns the violated rule if the Event is already present,
+    /// it would overlap with any event or is not allowed by [Room::check_event].
+    /// Dummy events are only used for comparisons and cannot be added
+    pub fn add_event(
+        &mut self,
+        event: Event,
+        context: &BookingContext,
+    ) -> Result<(), BookingError> {
+        if event.isdummy() {
+            return Err(BookingError::Dummy);
         }
+        // expired holds do not block the slot anymore
+        self.remove_expired_holds(context.now);
+        self.check_event(&event, context)?;
 
         if self.events.insert(event) {
-            return Some(());
+            return Ok(());
         }
-        None
+        Err(BookingError::Overlap)
+    }
+    /// cancels the confirmed events that are running since the release time of the room
+    /// without a check in, like [Room::cancel_event_uid], and returns them to be saved
+    pub fn release_no_shows(&mut self, now: DateTime<Local>) -> Vec<Event> {
+        let Some(minutes) = self.release_after_minutes else {
+            return Vec::new();
+        };
+        let no_shows: Vec<String> = self
+            .events
+            .iter()
+            .filter(|event| {
+                let release = booking::checked_minutes(minutes)
+                    .and_then(|minutes| event.start().checked_add_signed(minutes));
+                event.status() == EventStatus::Confirmed
+                    && event.checked_in().is_none()
+                    && release.map_or(false, |release| release <= now)
+                    && event.stop() > now
+            })
+            .map(|event| event.uid())
+            .collect();
+        let cancellation = Cancellation {
+            by: String::from(Cancellation::SYSTEM),
+            at: now,
+            reason: Some(String::from("no check-in")),
+        };
+        no_shows
+            .iter()
+            .filter_map(|uid| self.cancel_event_uid(uid, cancellation.clone()))
+            .collect()
+    }
+    /// the event that can be checked in to at now, i.e. which runs or starts
+    /// within [CHECK_IN_EARLY_MINUTES]
+    pub fn current_event(&self, now: DateTime<Local>) -> Option<&Event> {
+        let early = chrono::Duration::minutes(CHECK_IN_EARLY_MINUTES);
+        self.events
+            .range(..Event::create_dummy(now + early))
+            .filter(|event| event.stop() > now && event.status() == EventStatus::Confirmed)
+            .next_back()
+    }
+    /// removes all holds that expired before now, returns how many were removed
+    pub fn remove_expired_holds(&mut self, now: DateTime<Local>) -> usize {
+        let count = self.events.len();
+        self.events.retain(|event| !event.is_expired_hold(now));
+        count - self.events.len()
     }
     /// Removes the Event of the room if
     /// 1. The [DateTime] overlaps with an Event
     /// 2. The start of an Event is the [DateTime]
-    pub fn remove_event_datetime(&mut self, datetime: DateTime<Local>) -> Option<()> {
+    ///
+    /// and returns it
+    pub fn remove_event_datetime(&mut self, datetime: DateTime<Local>) -> Option<Event> {
         let event = Event::create_dummy(datetime);
-        if self.events.remove(&event) {
-            return Some(());
+        if let Some(removed) = self.events.take(&event) {
+            return Some(removed);
         }
         let smaller = self.events.range(..&event).next_back()?.clone();
-        if event.overlaps_with(&smaller) {
-            self.events.remove(&smaller);
-            return Some(());
+        if smaller.datetime_is_in(&datetime) {
+            return self.events.take(&smaller);
         }
         debug_println!("room not found");
         None
     }
 
-    async fn get_event_range(&self, start: DateTime<Local>, stop: DateTime<Local>) -> Vec<&Event> {
-        let mut ret = Vec::new();
+    /// moves the event with the uid to the history of cancelled events and returns it
+    pub fn cancel_event_uid(&mut self, uid: &str, cancellation: Cancellation) -> Option<Event> {
+        let event = self.remove_event_uid(uid)?.with_cancellation(cancellation);
+        self.cancelled_events.push(event.clone());
+        Some(event)
+    }
+    /// like [Room::cancel_event_uid] for the event found by [Room::remove_event_datetime]
+    pub fn cancel_event_datetime(
+        &mut self,
+        datetime: DateTime<Local>,
+        cancellation: Cancellation,
+    ) -> Option<Event> {
+        let event = self
+            .remove_event_datetime(datetime)?
+            .with_cancellation(cancellation);
+        self.cancelled_events.push(event.clone());
+        Some(event)
+    }
+    /// cancels all events with the [Event::link] and returns them
+    pub fn cancel_linked(&mut self, link: &str, cancellation: &Cancellation) -> Vec<Event> {
+        self.linked_uids(link)
+            .iter()
+            .filter_map(|uid| self.cancel_event_uid(uid, cancellation.clone()))
+            .collect()
+    }
+    pub fn cancelled_events(&self) -> &Vec<Event> {
+        &self.cancelled_events
+    }
+    /// the uids of the events with the [Event::link]
+    pub fn linked_uids(&self, link: &str) -> Vec<String> {
+        self.events
+            .iter()
+            .filter(|event| event.link() == Some(link))
+            .map(Event::uid)
+            .collect()
+    }
+
+    /// looks up the event by its [Event::uid]
+    pub fn get_event_uid(&self, uid: &str) -> Option<&Event> {
+        self.events.iter().find(|event| event.uid() == uid)
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
 
-        for event in self
+    /// the events starting between start and stop, without the cancelled ones
+    pub async fn get_event_range(
+        &self,
+        start: DateTime<Local>,
+        stop: DateTime<Local>,
+    ) -> Vec<&Event> {
+        self.events_in_range(start, stop, false)
+    }
+    /// the events starting between start and stop, ordered by their start.
+    /// Cancelled events are marked by their [Event::cancellation]
+    pub fn events_in_range(
+        &self,
+        start: DateTime<Local>,
+        stop: DateTime<Local>,
+        include_cancelled: bool,
+    ) -> Vec<&Event> {
+        let mut ret: Vec<&Event> = self
             .events
             .range(Event::create_dummy(start)..Event::create_dummy(stop))
-            .into_iter()
-        {
-            ret.push(event)
+            .collect();
+        if include_cancelled {
+            ret.extend(
+                self.cancelled_events
+                    .iter()
+                    .filter(|event| start <= event.start() && event.start() < stop),
+            );
+            ret.sort_by_key(|event| event.start());
         }
         ret
     }
 }
-#[derive(Debug, FromForm)]
+/// storage of the events in the events collection
+impl Room {
+    /// loads the events with the filter, which is restricted to the events of the room.
+    /// Cancelled events are loaded into [Room::cancelled_events]
+    pub async fn load_events_where(
+        &mut self,
+        mut filter: bson::Document,
+        db: &Storage,
+    ) -> Result<(), database::Error> {
+        filter.insert("room_id", self.id);
+        let mut cursor = db.event_collection().find(filter, None).await?;
+        while cursor.advance().await? {
+            let event = cursor.deserialize_current()?.event;
+            if event.cancellation().is_some() {
+                self.cancelled_events.push(event);
+            } else {
+                self.events.replace(event);
+            }
+        }
+        Ok(())
+    }
+    /// loads the events between start and stop, including the turnover before and after
+    pub async fn load_events(
+        &mut self,
+        start: DateTime<Local>,
+        stop: DateTime<Local>,
+        include_cancelled: bool,
+        db: &Storage,
+    ) -> Result<(), database::Error> {
+        let mut filter = doc! {
+            "start": {"$lt": bson::DateTime::from_chrono(stop + self.turnover())},
+            "stop": {"$gt": bson::DateTime::from_chrono(start - self.turnover())},
+        };
+        if !include_cancelled {
+            filter.insert("event.cancellation", bson::Bson::Null);
+        }
+        self.load_events_where(filter, db).await
+    }
+    /// loads all events of the room that are not cancelled
+    pub async fn load_all_events(&mut self, db: &Storage) -> Result<(), database::Error> {
+        self.load_events_where(doc! {"event.cancellation": null}, db)
+            .await
+    }
+    /// loads the event with the uid, returns [database::Error::NotFound] if there is none
+    pub async fn load_event_uid(&mut self, uid: &str, db: &Storage) -> Result<(), database::Error> {
+        self.load_events_where(doc! {"event.uid": uid, "event.cancellation": null}, db)
+            .await?;
+        if self.get_event_uid(uid).is_none() {
+            return Err(database::Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// checks the event like [Room::add_event] against the events stored at the same time
+    /// and inserts it. If another booking of the room was committed in the meantime,
+    /// the own one is removed and checked again against the new events,
+    /// see [Room::check_booking] and [Room::commit_booking]
+    pub async fn book_event(
+        &mut self,
+        event: Event,
+        context: &BookingContext,
+        db: &Storage,
+    ) -> Result<Result<(), BookingError>, database::Error> {
+        for _ in 0..database::UPDATE_RETRIES {
+            let sequence = match self.check_booking(&event, context, db).await? {
+                Ok(sequence) => sequence,
+                Err(err) => return Ok(Err(err)),
+            };
+            if self.commit_booking(&event, sequence, db).await? {
+                return Ok(Ok(()));
+            }
+        }
+        Err(database::Error::Conflict)
+    }
+    /// moves the stored event with the uid of the changed event to its new time,
+    /// checked and committed like [Room::book_event]. The changed event is inserted as a
+    /// new document and the stored one is only deleted afterwards, so the slot cannot be
+    /// booked by someone else in between. If the stored event was saved by someone else
+    /// since it was loaded, the move is undone and [database::Error::Conflict] is returned
+    pub async fn move_event(
+        &mut self,
+        event: Event,
+        context: &BookingContext,
+        db: &Storage,
+    ) -> Result<Result<(), BookingError>, database::Error> {
+        let version = event.version();
+        let moved = StoredEvent::create(self.id, event.with_version(version + 1));
+        for _ in 0..database::UPDATE_RETRIES {
+            let sequence = match self.check_booking(&moved.event, context, db).await? {
+                Ok(sequence) => sequence,
+                Err(err) => return Ok(Err(err)),
+            };
+            if !self.commit_stored(&moved, sequence, db).await? {
+                continue;
+            }
+            let previous = doc! {
+                "room_id": self.id,
+                "event.uid": moved.event.uid(),
+                "event.cancellation": null,
+                "event.version": database::version_filter(version),
+            };
+            let deleted = db.event_collection().delete_one(previous, None).await?;
+            if deleted.deleted_count > 0 {
+                return Ok(Ok(()));
+            }
+            // removing the moved event only frees its slot, it needs no booking sequence
+            db.event_collection()
+                .delete_one(doc! {"_id": moved.id}, None)
+                .await?;
+            self.events.remove(&moved.event);
+            return Err(database::Error::Conflict);
+        }
+        Err(database::Error::Conflict)
+    }
+    /// the number of bookings committed in the room, see [BOOKING_SEQUENCE]
+    async fn booking_sequence(&self, db: &Storage) -> Result<i64, database::Error> {
+        let room = db
+            .get_collection::<bson::Document>("rooms")
+            .find_one(doc! {"_id": self.id}, None)
+            .await?
+            .ok_or(database::Error::NotFound)?;
+        Ok(room.get_i64(BOOKING_SEQUENCE).unwrap_or(0))
+    }
+    /// loads the events at the time of the event and adds it like [Room::add_event].
+    /// Returns the booking sequence the events were loaded at, for [Room::commit_booking]
+    pub async fn check_booking(
+        &mut self,
+        event: &Event,
+        context: &BookingContext,
+        db: &Storage,
+    ) -> Result<Result<i64, BookingError>, database::Error> {
+        // read before the events, so every booking committed after it is noticed
+        let sequence = self.booking_sequence(db).await?;
+        self.load_events(event.start(), event.stop(), false, db)
+            .await?;
+        // a moved event replaces its stored version, see [Room::move_event]
+        self.remove_event_uid(&event.uid());
+        Ok(self.add_event(event.clone(), context).map(|()| sequence))
+    }
+    /// inserts the event checked by [Room::check_booking] and counts up the booking sequence,
+    /// if it is still the one the events were loaded at. Otherwise another booking was
+    /// committed in the meantime, the event is removed again and false is returned.
+    /// As the event is inserted before the sequence is counted up, a booking that
+    /// loads the events after reading the new sequence always sees it
+    pub async fn commit_booking(
+        &mut self,
+        event: &Event,
+        sequence: i64,
+        db: &Storage,
+    ) -> Result<bool, database::Error> {
+        let stored = StoredEvent::create(self.id, event.clone());
+        self.commit_stored(&stored, sequence, db).await
+    }
+    /// [Room::commit_booking] with the document already created, to know its id
+    async fn commit_stored(
+        &mut self,
+        stored: &StoredEvent,
+        sequence: i64,
+        db: &Storage,
+    ) -> Result<bool, database::Error> {
+        db.event_collection().insert_one(stored, None).await?;
+        let result = db
+            .get_collection::<bson::Document>("rooms")
+            .update_one(
+                doc! {"_id": self.id, BOOKING_SEQUENCE: database::version_filter(sequence)},
+                doc! {"$inc": {BOOKING_SEQUENCE: 1_i64}},
+                None,
+            )
+            .await?;
+        if result.matched_count > 0 {
+            return Ok(true);
+        }
+        db.event_collection()
+            .delete_one(doc! {"_id": stored.id}, None)
+            .await?;
+        self.events.remove(&stored.event);
+        Ok(false)
+    }
+    /// writes the event, e.g. after it was changed with [Room::modify_event] or cancelled.
+    /// Like [DatabaseConnection::update] it throws [database::Error::Conflict] if the event
+    /// was saved by someone else since it was loaded, see [Room::update_event].
+    /// Only the event that is not cancelled yet is written, the uid can also be in the history
+    pub async fn save_event(&self, event: &Event, db: &Storage) -> Result<(), database::Error> {
+        let version = event.version();
+        let stored = StoredEvent::create(self.id, event.clone().with_version(version + 1));
+        let update = doc! {"$set": {
+            "start": stored.start,
+            "stop": stored.stop,
+            "event": bson::to_bson(&stored.event)?,
+        }};
+        let filter = doc! {
+            "room_id": self.id,
+            "event.uid": event.uid(),
+            "event.cancellation": null,
+        };
+        let mut versioned = filter.clone();
+        versioned.insert("event.version", database::version_filter(version));
+        let result = db
+            .event_collection()
+            .update_one(versioned, update, None)
+            .await?;
+        if result.matched_count > 0 {
+            return Ok(());
+        }
+        match db.event_collection().find_one(filter, None).await? {
+            Some(_) => Err(database::Error::Conflict),
+            None => Err(database::Error::NotFound),
+        }
+    }
+    /// loads the event, applies the change and saves it. If the event was saved by
+    /// someone else in between, the change is applied again to the new version,
+    /// like [DatabaseConnection::modify]. Returns `Ok(None)` if the change returns None
+    pub async fn update_event<T>(
+        &mut self,
+        uid: &str,
+        db: &Storage,
+        mut change: impl FnMut(&mut Event) -> Option<T> + Send,
+    ) -> Result<Option<T>, database::Error> {
+        for _ in 0..database::UPDATE_RETRIES {
+            self.load_event_uid(uid, db).await?;
+            let Some(ret) = self
+                .modify_event(uid, &mut change)
+                .ok_or(database::Error::NotFound)?
+            else {
+                return Ok(None);
+            };
+            let event = self.get_event_uid(uid).ok_or(database::Error::NotFound)?;
+            match self.save_event(event, db).await {
+                Ok(()) => return Ok(Some(ret)),
+                Err(database::Error::Conflict) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(database::Error::Conflict)
+    }
+    /// deletes the event that is not cancelled from the database,
+    /// use [Room::cancel_event_uid] to keep it in the history
+    pub async fn delete_event(&self, event: &Event, db: &Storage) -> Result<(), database::Error> {
+        let filter = doc! {
+            "room_id": self.id,
+            "event.uid": event.uid(),
+            "event.cancellation": null,
+        };
+        db.event_collection().delete_one(filter, None).await?;
+        Ok(())
+    }
+}
+/// a time span, e.g. in which a room is free
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Interval {
+    pub start: DateTime<Local>,
+    pub stop: DateTime<Local>,
+}
+
+/// filters rooms by their attributes, all set fields need to match
+#[derive(Debug, Default, Deserialize, FromForm)]
+#[serde(default)]
+pub struct RoomFilter {
+    pub min_capacity: Option<u32>,
+    pub building: Option<String>,
+    pub floor: Option<i32>,
+    pub equipment: Vec<String>,
+    pub accessibility: Accessibility,
+}
+
+/// splits a comma separated list, e.g. "projector, whiteboard"
+pub(crate) fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// looks up the room by id, or by name if no id is given
+pub(crate) async fn find_room(
+    room_id: &Option<String>,
+    room_name: &Option<String>,
+    db: &Storage,
+) -> Result<Room, Error> {
+    if let Some(room_id) = room_id {
+        let id = rocket::serde::json::from_str::<ObjectId>(room_id)
+            .map_err(|_| Error::Invalid(String::from("room_id is not a valid id")))?;
+        Ok(Room::getfromdb_id(&id, db).await?)
+    } else {
+        let room_name = room_name
+            .as_ref()
+            .ok_or_else(|| Error::Invalid(String::from("room_id or room_name is needed")))?;
+        Ok(Room::getfromdb_name(room_name, db).await?)
+    }
+}
+
+/// tells the booker that someone else cancelled their event
+pub(crate) async fn notify_cancelled(room: &Room, event: &Event, db: &Storage) {
+    let Some(cancellation) = event.cancellation() else {
+        return;
+    };
+    if cancellation.by == event.booker_id() {
+        return;
+    }
+    let mut message = format!(
+        "\"{}\" in {} on {} was cancelled",
+        event.headline(),
+        room.name,
+        event.start().format("%d.%m.%Y %H:%M")
+    );
+    if let Some(reason) = &cancellation.reason {
+        message.push_str(&format!(": {}", reason));
+    }
+    if let Err(err) = notification::notify(event.booker_id(), message, db).await {
+        debug_println!("could not notify booker: {:?}", err);
+    }
+}
+
+#[derive(Debug, Deserialize, FromForm)]
 struct CreateDeleteForm<'r> {
+    #[serde(borrow)]
     userdata: user::UserData<'r>,
-    name: &'r str,
+    name: String,
 }
 #[post("/create", data = "<form>")]
-async fn create(form: Form<CreateDeleteForm<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    println!("get user");
-    let user = user::User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    println!("got user");
-    if !user.can_create_rooms() || Room::getfromdb_name(form.name, &db).await.is_ok() {
-        println!("user cannot create rooms");
-        return None;
-    }
-    let room = Room::create(String::from(form.name));
-    println!("insert user");
-    room.insert(&db).await.ok()?;
-    Some(())
+async fn create(
+    form: Input<CreateDeleteForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    if !user.can_create_rooms() {
+        debug_println!("user cannot create rooms");
+        return Err(Error::Forbidden("you cannot create rooms"));
+    }
+    if Room::getfromdb_name(&form.name, &db).await.is_ok() {
+        return Err(database::Error::AlreadyInDB.into());
+    }
+    let room = Room::create(form.name.clone());
+    room.insert(&db).await?;
+    Ok(())
 }
 #[post("/delete", data = "<form>")]
-async fn delete(form: Form<CreateDeleteForm<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    println!("get user");
-    let user = user::User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    println!("got user");
-    let room = Room::getfromdb_name(form.name, &db).await.ok()?;
+async fn delete(
+    form: Input<CreateDeleteForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let room = Room::getfromdb_name(&form.name, &db).await?;
     if !user.can_edit_room(&room.id) {
-        println!("cannot delete rooms");
-        return None;
+        debug_println!("cannot delete rooms");
+        return Err(Error::Forbidden("you cannot delete the room"));
     }
     db.room_collection()
         .delete_one(doc! {"_id": room.id}, None)
-        .await
-        .ok()?;
-    println!("insert user");
-    Some(())
+        .await?;
+    db.event_collection()
+        .delete_many(doc! {"room_id": room.id}, None)
+        .await?;
+    Ok(())
 }
-#[derive(Debug, FromForm)]
+#[derive(Debug, Deserialize, FromForm)]
 struct CreateEventForm<'a> {
+    #[serde(borrow)]
     userdata: user::UserData<'a>,
     room_name: Option<String>,
     room_id: Option<String>,
@@ -184,100 +870,703 @@ struct CreateEventForm<'a> {
     description: Option<String>,
     start: String,
     stop: String,
+    /// only holds the slot for the given minutes, see /room/confirm_hold
+    hold_minutes: Option<i64>,
+    /// username of the organizer, who needs to have delegated booking to the logged in user
+    on_behalf_of: Option<String>,
+}
+/// an event to book with [book], the fields are the ones of /room/add_event
+#[derive(Debug, Deserialize, FromForm)]
+pub(crate) struct NewEvent {
+    pub headline: String,
+    pub description: Option<String>,
+    pub start: String,
+    pub stop: String,
+    pub hold_minutes: Option<i64>,
+    pub on_behalf_of: Option<String>,
 }
 
 #[post("/get?<name>", data = "<form>")]
 async fn get(
     name: &str,
-    form: Form<user::UserData<'_>>,
+    form: Input<user::UserData<'_>>,
     db: Connection<MainDatabase>,
-) -> Option<String> {
+) -> Result<Json<Room>, Error> {
     println!("room:{}", name);
-    let user = &user::User::login(form.username, String::from(form.password), &*db)
-        .await
-        .ok()?;
+    let user = form.login(&db).await?;
     println!("search for room");
-    let room = Room::getfromdb_name(name, &*db).await.ok()?;
+    let room = Room::getfromdb_name(name, &*db).await?;
     if !user.can_edit_room(&room.id) {
         println!("cannot edit rooms");
-        return None;
+        return Err(Error::Forbidden("you cannot edit the room"));
     }
-    Some(rocket::serde::json::to_string(&room).ok()?)
+    Ok(Json(room))
 }
 
-#[post("/add_event", data = "<form>")]
-async fn add_event(form: Form<CreateEventForm<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    let user = user::User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    println!("1");
-    let mut room = if let Some(room_id) = &form.room_id {
-        let id = rocket::serde::json::from_str::<ObjectId>(&room_id).ok()?;
-        Room::getfromdb_id(&id, &db).await.ok()?
-    } else {
-        Room::getfromdb_name(&form.room_name.clone()?, &db)
-            .await
-            .ok()?
+/// books the event for the user, or for the organizer the user books on behalf of,
+/// and returns it. A violated booking rule is returned as [Error::Booking]
+pub(crate) async fn book(
+    user: &user::User,
+    room: &mut Room,
+    new: NewEvent,
+    defaults: &BookingDefaults,
+    db: &Storage,
+) -> Result<Event, Error> {
+    let on_behalf_of = match &new.on_behalf_of {
+        Some(username) => Some(user::User::getfromdb_name(username, db).await?),
+        None => None,
     };
-    let start = DateTime::parse_from_rfc3339(&form.start).ok()?.into();
-    let stop = DateTime::parse_from_rfc3339(&form.stop).ok()?.into();
+    let organizer = on_behalf_of.as_ref().unwrap_or(user);
+    if !user.can_act_for(organizer) {
+        return Err(Error::Forbidden("you cannot book on behalf of this user"));
+    }
+    let start = error::parse_time("start", &new.start)?;
+    let stop = error::parse_time("stop", &new.stop)?;
 
     let event = event::Event::create(
-        user.id().to_string(),
-        form.headline.clone(),
-        form.description.clone(),
+        organizer.id().to_string(),
+        new.headline,
+        new.description,
         start,
         stop,
-    )?;
-    println!("4");
-    if !user.can_edit_room(&room.id) {
-        return None;
+    )
+    .ok_or_else(|| {
+        Error::Invalid(String::from(
+            "the headline must be a single line and the event needs to end after its start",
+        ))
+    })?
+    .with_creator(user.id().to_string());
+    // the event is booked with the rights and the policy of the organizer
+    let status = room
+        .booking_status(organizer)
+        .ok_or(Error::Forbidden("you cannot book the room"))?;
+    let event = match new.hold_minutes {
+        Some(minutes) => {
+            let minutes = error::check_range("hold_minutes", minutes, 1..=MAX_HOLD_MINUTES)?;
+            event.with_status(EventStatus::Held {
+                expires: Local::now() + chrono::Duration::minutes(minutes),
+            })
+        }
+        None => event.with_status(status),
+    };
+    let context = BookingContext::load(room, &organizer.id().to_string(), defaults, db).await?;
+    room.book_event(event.clone(), &context, db).await??;
+    Ok(event)
+}
+/// books an event. If a booking rule is violated, the rule is returned in the
+/// details of the 409 Conflict, see [BookingError]
+#[post("/add_event", data = "<form>")]
+async fn add_event(
+    form: Input<CreateEventForm<'_>>,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    let form = form.into_inner();
+    let new = NewEvent {
+        headline: form.headline,
+        description: form.description,
+        start: form.start,
+        stop: form.stop,
+        hold_minutes: form.hold_minutes,
+        on_behalf_of: form.on_behalf_of,
+    };
+    book(&user, &mut room, new, defaults, &db).await?;
+    Ok(())
+}
+#[derive(Debug, Deserialize, FromForm)]
+struct EventForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    uid: String,
+}
+/// turns a hold of the user into a normal booking, which still needs
+/// to be approved if the room requires it
+#[post("/confirm_hold", data = "<form>")]
+async fn confirm_hold(
+    form: Input<EventForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    room.load_event_uid(&form.uid, &db).await?;
+    let event = room
+        .get_event_uid(&form.uid)
+        .ok_or(Error::NotFound("event not found"))?;
+    if !matches!(event.status(), EventStatus::Held { .. }) || event.is_expired_hold(Local::now()) {
+        return Err(Error::Conflict(String::from("the event is not held")));
+    }
+    if !user.can_act_for_id(event.booker_id(), &db).await && !room.can_approve(&user) {
+        return Err(Error::Forbidden("you cannot confirm this hold"));
+    }
+    let status = room
+        .booking_status(&user)
+        .ok_or(Error::Forbidden("you cannot book the room"))?;
+    room.set_event_status(&form.uid, status)
+        .ok_or(Error::NotFound("event not found"))?;
+    let event = room
+        .get_event_uid(&form.uid)
+        .ok_or(Error::NotFound("event not found"))?;
+    room.save_event(event, &db).await?;
+    Ok(())
+}
+
+/// saves the event cancelled by [Room::cancel_event_uid] or [Room::cancel_event_datetime]
+/// if the user may cancel it, tells the booker and offers its time to the waitlist.
+/// Only the booker, users they delegated to and the managers of the room can cancel it
+pub(crate) async fn cancel(
+    user: &user::User,
+    room: &mut Room,
+    cancelled: &Event,
+    defaults: &BookingDefaults,
+    db: &Storage,
+) -> Result<(), Error> {
+    if !room.can_approve(user) && !user.can_act_for_id(cancelled.booker_id(), db).await {
+        return Err(Error::Forbidden("you cannot cancel this event"));
+    }
+    room.save_event(cancelled, db).await?;
+    notify_cancelled(room, cancelled, db).await;
+    if let Err(err) = waitlist::offer_slot(room, cancelled, defaults, db).await {
+        println!("could not offer the slot to the waitlist: {:?}", err);
     }
-    println!("5");
-    room.add_event(event)?;
-    println!("6");
-    room.update(&db).await.ok()?;
-    Some(())
+    Ok(())
 }
-#[derive(Debug, FromForm)]
+
+#[derive(Debug, Deserialize, FromForm)]
 struct RemoveEventForm<'r> {
+    #[serde(borrow)]
     userdata: user::UserData<'r>,
     room_name: Option<String>,
     room_id: Option<String>,
     remove_datetime: String,
+    /// shown to the booker if someone else cancels the event
+    reason: Option<String>,
 }
+/// cancels the event, it is kept in the history of the room and its time
+/// is offered to the waitlist. Only the booker, users they delegated to and the
+/// managers of the room can cancel it, the booker is notified about the latter
 #[post("/remove_event", data = "<form>")]
-async fn remove_event(form: Form<RemoveEventForm<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    let _user = user::User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    let mut room = if let Some(room_id) = &form.room_id {
-        let id = rocket::serde::json::from_str::<ObjectId>(&room_id).ok()?;
-        Room::getfromdb_id(&id, &db).await.ok()?
-    } else {
-        Room::getfromdb_name(&form.room_name.clone()?, &db)
-            .await
-            .ok()?
-    };
-    let datetime = DateTime::parse_from_rfc3339(&form.remove_datetime)
-        .ok()?
-        .into();
+async fn remove_event(
+    form: Input<RemoveEventForm<'_>>,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    let datetime = error::parse_time("remove_datetime", &form.remove_datetime)?;
     println!("remove event");
-    room.remove_event_datetime(datetime)?;
-    room.update(&db).await.ok()?;
-    Some(())
+    room.load_events(datetime, datetime, false, &db).await?;
+    let cancellation = Cancellation {
+        by: user.id().to_hex(),
+        at: Local::now(),
+        reason: form.reason.clone(),
+    };
+    let cancelled = room
+        .cancel_event_datetime(datetime, cancellation)
+        .ok_or(Error::NotFound("no event at that time"))?;
+    cancel(&user, &mut room, &cancelled, defaults, &db).await
 }
 
-#[derive(Debug, FromForm)]
+/// every set field is changed, lists are given comma separated.
+/// An empty list removes all entries. The booking rules can only be changed by admins
+/// and the owner, see [Room::can_change_rules]
+#[derive(Debug, Deserialize, FromForm)]
+struct ChangeRoomForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    description: Option<String>,
+    capacity: Option<u32>,
+    building: Option<String>,
+    floor: Option<i32>,
+    equipment: Option<String>,
+    photos: Option<String>,
+    step_free: Option<bool>,
+    accessible_toilet: Option<bool>,
+    hearing_loop: Option<bool>,
+    /// e.g. "Mon-Fri 07:30-18:00; Sat 08:00-12:00", empty uses the global default
+    opening_hours: Option<String>,
+    /// names of holiday calendars
+    holiday_calendars: Option<String>,
+    setup_minutes: Option<i64>,
+    teardown_minutes: Option<i64>,
+    requires_approval: Option<bool>,
+    /// minutes after the start of an event without check in until it is released, 0 disables it
+    release_after_minutes: Option<i64>,
+    /// usernames of the users that can approve bookings
+    managers: Option<String>,
+}
+impl ChangeRoomForm<'_> {
+    /// wether a field restricted to [Room::can_change_rules] is set
+    fn changes_rules(&self) -> bool {
+        self.opening_hours.is_some()
+            || self.holiday_calendars.is_some()
+            || self.setup_minutes.is_some()
+            || self.teardown_minutes.is_some()
+            || self.requires_approval.is_some()
+            || self.release_after_minutes.is_some()
+            || self.managers.is_some()
+    }
+}
+/// changes the description and the attributes of a room.
+/// photos need to be uploaded with /image/upload first.
+#[post("/change", data = "<form>")]
+async fn change(
+    form: Input<ChangeRoomForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    if !user.can_edit_room(&room.id) {
+        return Err(Error::Forbidden("you cannot edit the room"));
+    }
+    if form.changes_rules() && !room.can_change_rules(&user) {
+        return Err(Error::Forbidden(
+            "only admins and the owner can change the booking rules of the room",
+        ));
+    }
+    if let Some(description) = form.description.clone() {
+        room.description = Some(description);
+    }
+    if let Some(capacity) = form.capacity {
+        room.capacity = Some(capacity);
+    }
+    if let Some(building) = form.building.clone() {
+        room.building = Some(building);
+    }
+    if let Some(floor) = form.floor {
+        room.floor = Some(floor);
+    }
+    if let Some(equipment) = &form.equipment {
+        room.equipment = split_list(equipment);
+    }
+    if let Some(photos) = &form.photos {
+        let photos = split_list(photos);
+        // only names listed in the images of the tenant, so they cannot leave its directory
+        let uploaded = image::list_names(db.tenant())?;
+        if let Some(photo) = photos.iter().find(|photo| !uploaded.contains(photo)) {
+            return Err(Error::Invalid(format!("there is no image {}", photo)));
+        }
+        room.photos = photos;
+    }
+    if let Some(step_free) = form.step_free {
+        room.accessibility.step_free = step_free;
+    }
+    if let Some(accessible_toilet) = form.accessible_toilet {
+        room.accessibility.accessible_toilet = accessible_toilet;
+    }
+    if let Some(hearing_loop) = form.hearing_loop {
+        room.accessibility.hearing_loop = hearing_loop;
+    }
+    if let Some(opening_hours) = &form.opening_hours {
+        room.opening_hours = if opening_hours.trim().is_empty() {
+            None
+        } else {
+            Some(OpeningHours::try_from(opening_hours.clone()).map_err(Error::Invalid)?)
+        };
+    }
+    if let Some(setup_minutes) = form.setup_minutes {
+        room.setup_minutes =
+            error::check_range("setup_minutes", setup_minutes, 0..=MAX_TURNOVER_MINUTES)?;
+    }
+    if let Some(teardown_minutes) = form.teardown_minutes {
+        room.teardown_minutes = error::check_range(
+            "teardown_minutes",
+            teardown_minutes,
+            0..=MAX_TURNOVER_MINUTES,
+        )?;
+    }
+    if let Some(requires_approval) = form.requires_approval {
+        room.requires_approval = requires_approval;
+    }
+    if let Some(release_after_minutes) = form.release_after_minutes {
+        // 0 disables the automatic release
+        room.release_after_minutes = match error::check_range(
+            "release_after_minutes",
+            release_after_minutes,
+            0..=MAX_RELEASE_MINUTES,
+        )? {
+            0 => None,
+            minutes => Some(minutes),
+        };
+    }
+    if let Some(names) = &form.managers {
+        room.managers.clear();
+        for name in split_list(names) {
+            let manager = user::User::getfromdb_name(&name, &db).await?;
+            room.managers.push(manager.id());
+        }
+    }
+    if let Some(names) = &form.holiday_calendars {
+        room.holiday_calendars.clear();
+        for name in split_list(names) {
+            let calendar = HolidayCalendar::getfromdb_name(&name, &db).await?;
+            room.holiday_calendars.push(calendar.id());
+        }
+    }
+    room.update(&db).await?;
+    Ok(())
+}
+
+/// the public part of a [Room], without its events and layouts
+#[derive(Debug, Serialize)]
+pub(crate) struct RoomSummary {
+    id: ObjectId,
+    name: String,
+    description: Option<String>,
+    capacity: Option<u32>,
+    building: Option<String>,
+    floor: Option<i32>,
+    equipment: Vec<String>,
+    accessibility: Accessibility,
+    photos: Vec<String>,
+    /// whether the requesting user may book the room
+    bookable: bool,
+}
+impl RoomSummary {
+    pub fn new(room: Room, bookable: bool) -> Self {
+        RoomSummary {
+            id: room.id,
+            name: room.name,
+            description: room.description,
+            capacity: room.capacity,
+            building: room.building,
+            floor: room.floor,
+            equipment: room.equipment,
+            accessibility: room.accessibility,
+            photos: room.photos,
+            bookable,
+        }
+    }
+}
+#[derive(Debug, Serialize)]
+pub(crate) struct RoomList {
+    total: usize,
+    page: usize,
+    per_page: usize,
+    rooms: Vec<RoomSummary>,
+}
+/// how [list_rooms] sorts and pages the rooms
+#[derive(Debug, Default)]
+pub(crate) struct ListOptions<'a> {
+    pub sort: Option<&'a str>,
+    pub descending: Option<bool>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    pub bookable: Option<bool>,
+}
+/// lists all rooms matching the filter.
+///
+/// sort can be "name" (default), "capacity" or "building",
+/// pages start at 0 and contain up to per_page (default 50, at most 200) rooms.
+/// With bookable=true only rooms the user may book are listed.
+pub(crate) async fn list_rooms(
+    user: &user::User,
+    options: ListOptions<'_>,
+    filter: &RoomFilter,
+    db: &Storage,
+) -> Result<RoomList, Error> {
+    let mut rooms = Vec::new();
+    let mut cursor = Room::get_all_from_db(db).await?;
+    while cursor.advance().await? {
+        let room = cursor.deserialize_current()?;
+        let may_book = user.can_edit_room(&room.id);
+        if room.matches(filter) && (may_book || options.bookable != Some(true)) {
+            rooms.push(RoomSummary::new(room, may_book));
+        }
+    }
+    match options.sort.unwrap_or("name") {
+        "name" => rooms.sort_by(|a, b| a.name.cmp(&b.name)),
+        "capacity" => rooms.sort_by(|a, b| a.capacity.cmp(&b.capacity)),
+        "building" => rooms.sort_by(|a, b| (&a.building, a.floor).cmp(&(&b.building, b.floor))),
+        _ => {
+            return Err(Error::Invalid(String::from(
+                "sort needs to be name, capacity or building",
+            )))
+        }
+    }
+    if options.descending == Some(true) {
+        rooms.reverse();
+    }
+    let total = rooms.len();
+    let page = options.page.unwrap_or(0);
+    let per_page = options.per_page.unwrap_or(50).clamp(1, 200);
+    let rooms = rooms
+        .into_iter()
+        .skip(page.saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    Ok(RoomList {
+        total,
+        page,
+        per_page,
+        rooms,
+    })
+}
+/// lists all rooms matching the filter as json, see [list_rooms]
+#[post(
+    "/list?<sort>&<descending>&<page>&<per_page>&<bookable>&<filter..>",
+    data = "<form>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn list(
+    sort: Option<&str>,
+    descending: Option<bool>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    bookable: Option<bool>,
+    filter: RoomFilter,
+    form: Input<user::UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<RoomList>, Error> {
+    let user = form.login(&db).await?;
+    let options = ListOptions {
+        sort,
+        descending,
+        page,
+        per_page,
+        bookable,
+    };
+    Ok(Json(list_rooms(&user, options, &filter, &db).await?))
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct BlackoutForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    start: String,
+    stop: Option<String>,
+    reason: Option<String>,
+}
+/// adds a period in which the room cannot be booked
+#[post("/add_blackout", data = "<form>")]
+async fn add_blackout(
+    form: Input<BlackoutForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let room = find_room(&form.room_id, &form.room_name, &db).await?;
+    if !room.can_change_rules(&user) {
+        return Err(Error::Forbidden(
+            "only admins and the owner can change the blackouts of the room",
+        ));
+    }
+    let start = error::parse_time("start", &form.start)?;
+    let stop = form
+        .stop
+        .as_ref()
+        .ok_or_else(|| Error::Invalid(String::from("stop is needed")))?;
+    let stop = error::parse_time("stop", stop)?;
+    if start >= stop {
+        return Err(Error::Invalid(String::from(
+            "the blackout needs to end after its start",
+        )));
+    }
+    let blackout = Blackout {
+        start,
+        stop,
+        reason: form.reason.clone().unwrap_or_default(),
+    };
+    Room::modify(&room.id, &db, |room| {
+        room.blackouts.push(blackout.clone());
+        Some(())
+    })
+    .await?;
+    Ok(())
+}
+/// removes the blackout starting at start
+#[post("/remove_blackout", data = "<form>")]
+async fn remove_blackout(
+    form: Input<BlackoutForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let room = find_room(&form.room_id, &form.room_name, &db).await?;
+    if !room.can_change_rules(&user) {
+        return Err(Error::Forbidden(
+            "only admins and the owner can change the blackouts of the room",
+        ));
+    }
+    let start = error::parse_time("start", &form.start)?;
+    Room::modify(&room.id, &db, |room| {
+        let count = room.blackouts.len();
+        room.blackouts.retain(|blackout| blackout.start != start);
+        (count != room.blackouts.len()).then_some(())
+    })
+    .await?
+    .ok_or(Error::NotFound("no blackout starts at that time"))
+}
+
+#[derive(Debug, Serialize)]
+struct PendingEvent {
+    room_id: ObjectId,
+    room_name: String,
+    event: Event,
+}
+/// lists all pending events of the rooms the user can approve, as json
+#[post("/approval_queue", data = "<form>")]
+async fn approval_queue(
+    form: Input<user::UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<PendingEvent>>, Error> {
+    let user = form.login(&db).await?;
+    let mut rooms = Vec::new();
+    let mut cursor = Room::get_all_from_db(&db).await?;
+    while cursor.advance().await? {
+        let mut room: Room = cursor.deserialize_current()?;
+        if room.can_approve(&user) {
+            room.load_events_where(doc! {"event.status": "pending"}, &db)
+                .await?;
+            rooms.push(room);
+        }
+    }
+    let mut pending = Vec::new();
+    for room in &rooms {
+        for event in room.events() {
+            if event.status() == EventStatus::Pending {
+                pending.push(PendingEvent {
+                    room_id: room.id,
+                    room_name: room.name.clone(),
+                    event: event.clone(),
+                });
+            }
+        }
+    }
+    Ok(Json(pending))
+}
+#[derive(Debug, Deserialize, FromForm)]
+struct DecideForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    uid: String,
+    #[serde(default)]
+    approve: bool,
+    comment: Option<String>,
+}
+/// approves or rejects a pending event. Rejected events are cancelled by the approver
+/// with the comment as reason, and their time is offered to the waitlist.
+/// The booker gets a notification with the decision and the comment.
+#[post("/decide", data = "<form>")]
+async fn decide(
+    form: Input<DecideForm<'_>>,
+    defaults: &State<BookingDefaults>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    if !room.can_approve(&user) {
+        return Err(Error::Forbidden("you cannot approve bookings of the room"));
+    }
+    room.load_event_uid(&form.uid, &db).await?;
+    let event = room
+        .get_event_uid(&form.uid)
+        .ok_or(Error::NotFound("event not found"))?
+        .clone();
+    if event.status() != EventStatus::Pending {
+        return Err(Error::Conflict(String::from("the event is not pending")));
+    }
+    if form.approve {
+        room.set_event_status(&form.uid, EventStatus::Confirmed)
+            .ok_or(Error::NotFound("event not found"))?;
+        let confirmed = room
+            .get_event_uid(&form.uid)
+            .ok_or(Error::NotFound("event not found"))?;
+        room.save_event(confirmed, &db).await?;
+    } else {
+        let cancellation = Cancellation {
+            by: user.id().to_hex(),
+            at: Local::now(),
+            reason: form.comment.clone(),
+        };
+        let rejected = room
+            .cancel_event_uid(&form.uid, cancellation)
+            .ok_or(Error::NotFound("event not found"))?;
+        room.save_event(&rejected, &db).await?;
+        if let Err(err) = waitlist::offer_slot(&mut room, &rejected, defaults, &db).await {
+            println!("could not offer the slot to the waitlist: {:?}", err);
+        }
+    }
+    let mut message = format!(
+        "your request for \"{}\" in {} on {} was {}",
+        event.headline(),
+        room.name,
+        event.start().format("%d.%m.%Y %H:%M"),
+        if form.approve { "approved" } else { "rejected" }
+    );
+    if let Some(comment) = &form.comment {
+        message.push_str(&format!(": {}", comment));
+    }
+    if let Err(err) = notification::notify(event.booker_id(), message, &db).await {
+        debug_println!("could not notify booker: {:?}", err);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct FindFreeForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    start: String,
+    stop: String,
+    /// in minutes
+    duration: i64,
+    #[serde(default)]
+    filter: RoomFilter,
+}
+#[derive(Debug, Serialize)]
+struct FreeRoom {
+    id: ObjectId,
+    name: String,
+    free: Vec<Interval>,
+}
+/// searches all rooms matching the filter for free intervals between start and stop.
+/// Only rooms with at least one interval long enough for the duration are returned.
+#[post("/find_free", data = "<form>")]
+async fn find_free(
+    form: Input<FindFreeForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<FreeRoom>>, Error> {
+    form.userdata.login(&db).await?;
+    let start = error::parse_time("start", &form.start)?;
+    let stop = error::parse_time("stop", &form.stop)?;
+    if start >= stop {
+        return Err(Error::Invalid(String::from("stop needs to be after start")));
+    }
+    // longer ones could not fit in between anyway
+    let window = (stop - start).num_minutes();
+    let duration = error::check_range("duration", form.duration, 1..=window)?;
+    let duration = chrono::Duration::minutes(duration);
+    let mut ret = Vec::new();
+    let mut rooms = Room::get_all_from_db(&db).await?;
+    while rooms.advance().await? {
+        let mut room: Room = rooms.deserialize_current()?;
+        if !room.matches(&form.filter) {
+            continue;
+        }
+        room.load_events(start, stop, false, &db).await?;
+        let free = room.free_intervals(start, stop, duration);
+        if !free.is_empty() {
+            ret.push(FreeRoom {
+                id: room.id,
+                name: room.name,
+                free,
+            });
+        }
+    }
+    Ok(Json(ret))
+}
+
+#[derive(Debug, Deserialize, FromForm)]
 struct GetEventRangeForm<'a> {
+    #[serde(borrow)]
     userdata: user::UserData<'a>,
     room_name: Option<String>,
     room_id: Option<String>,
@@ -286,47 +1575,48 @@ struct GetEventRangeForm<'a> {
     get_day_from_sec_since_utc: Option<i64>,
     get_current_day: Option<bool>,
     get_current_day_offset_in_days: Option<i32>,
+    /// also returns the cancelled events, marked by their "cancellation"
+    include_cancelled: Option<bool>,
 }
 //todo: check whether the user has access to the room
 //todo: use match statement to reduce boilerplate
 #[post("/get_event_range", data = "<form>")]
 async fn get_event_range(
-    form: Form<GetEventRangeForm<'_>>,
+    form: Input<GetEventRangeForm<'_>>,
     db: Connection<MainDatabase>,
-) -> Option<String> {
-    let _user = user::User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    let room = if let Some(room_id) = &form.room_id {
-        let id = rocket::serde::json::from_str::<ObjectId>(&room_id).ok()?;
-        Room::getfromdb_id(&id, &db).await.ok()?
-    } else {
-        Room::getfromdb_name(&form.room_name.clone()?, &db)
-            .await
-            .ok()?
-    };
+) -> Result<Json<Vec<Event>>, Error> {
+    form.userdata.login(&db).await?;
+    let mut room = find_room(&form.room_id, &form.room_name, &db).await?;
+    let out_of_range = || Error::Invalid(String::from("the day is out of range"));
+    let include_cancelled = form.include_cancelled == Some(true);
     //if let syntax would be used, the Strings would be needed to be cloned
     if let Some(form_start)=&form.start && let Some(form_stop)=&form.stop {
         //todo: does not work currently
-    let start = rocket::serde::json::from_str::<DateTime<Local>>(form_start).ok()?;
+    let start = rocket::serde::json::from_str::<DateTime<Local>>(form_start)
+        .map_err(|_| Error::Invalid(String::from("start is not a json time")))?;
 
-    let stop = rocket::serde::json::from_str::<DateTime<Local>>(form_stop).ok()?;
+    let stop = rocket::serde::json::from_str::<DateTime<Local>>(form_stop)
+        .map_err(|_| Error::Invalid(String::from("stop is not a json time")))?;
 
-    let events = room.get_event_range(start, stop).await;
-    return rocket::serde::json::to_string(&events).ok()
+    room.load_events(start, stop, include_cancelled, &db).await?;
+    let events = room.events_in_range(start, stop, include_cancelled);
+    return Ok(Json(events.into_iter().cloned().collect()))
     }
 
     if let Some(timestamp) = form.get_day_from_sec_since_utc {
-        let start = Local.timestamp_opt(timestamp, 0).earliest()?;
-        let stop = start.checked_add_days(chrono::Days::new(1))?;
+        let start = Local
+            .timestamp_opt(timestamp, 0)
+            .earliest()
+            .ok_or_else(out_of_range)?;
+        let stop = start
+            .checked_add_days(chrono::Days::new(1))
+            .ok_or_else(out_of_range)?;
         //let stop = start.offset()
 
-        let events = room.get_event_range(start, stop).await;
-        return rocket::serde::json::to_string(&events).ok();
+        room.load_events(start, stop, include_cancelled, &db)
+            .await?;
+        let events = room.events_in_range(start, stop, include_cancelled);
+        return Ok(Json(events.into_iter().cloned().collect()));
     }
 
     if form.get_current_day == Some(true) {
@@ -335,26 +1625,38 @@ async fn get_event_range(
         //println!{"duration:{}",duration};
         //start = start - duration;
         start = start
-            .with_hour(0)?
-            .with_minute(0)?
-            .with_second(0)?
-            .with_nanosecond(0)?;
+            .with_hour(0)
+            .and_then(|start| start.with_minute(0))
+            .and_then(|start| start.with_second(0))
+            .and_then(|start| start.with_nanosecond(0))
+            .ok_or_else(out_of_range)?;
         if let Some(days) = form.get_current_day_offset_in_days {
             if days >= 0 {
-                start = start.checked_add_days(chrono::Days::new(days as u64))?;
+                start = start
+                    .checked_add_days(chrono::Days::new(days as u64))
+                    .ok_or_else(out_of_range)?;
             } else {
-                start = start.checked_sub_days(chrono::Days::new((days * -1) as u64))?;
+                start = start
+                    .checked_sub_days(chrono::Days::new(u64::from(days.unsigned_abs())))
+                    .ok_or_else(out_of_range)?;
             }
         }
         println!("startdate:{:?}", start);
-        let mut stop = start.clone().checked_add_days(chrono::Days::new(1))?;
+        let mut stop = start
+            .clone()
+            .checked_add_days(chrono::Days::new(1))
+            .ok_or_else(out_of_range)?;
         //todo: send bug report to chrono, as the +2 is a bug with crono. checked_add_days disregards the Local time zone, leading to a -2 hour gap.
         //stop = stop.with_hour(stop.hour() + 2)?;
         println!("stoptdate:{:?}", stop);
-        let events = room.get_event_range(start, stop).await;
-        return rocket::serde::json::to_string(&events).ok();
+        room.load_events(start, stop, include_cancelled, &db)
+            .await?;
+        let events = room.events_in_range(start, stop, include_cancelled);
+        return Ok(Json(events.into_iter().cloned().collect()));
     }
-    return None;
+    return Err(Error::Invalid(String::from(
+        "start and stop, get_day_from_sec_since_utc or get_current_day is needed",
+    )));
 }
 
 pub fn routes() -> Vec<Route> {
@@ -364,19 +1666,319 @@ pub fn routes() -> Vec<Route> {
         get,
         add_event,
         remove_event,
-        get_event_range
+        get_event_range,
+        find_free,
+        change,
+        list,
+        add_blackout,
+        remove_blackout,
+        approval_queue,
+        decide,
+        confirm_hold
     ]
 }
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use chrono::{Local, Timelike};
-    use mongodb::Client;
+    use crate::database::Update;
+    use chrono::{Duration, Local, Timelike};
+
+    /// an event of "booker" from start + from to start + to, used by the tests of other modules too
+    pub(crate) fn event(start: DateTime<Local>, from: Duration, to: Duration) -> Event {
+        Event::create(
+            String::from("booker"),
+            String::from("headline"),
+            None,
+            start + from,
+            start + to,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn filter_by_attributes() {
+        let mut room = Room::create(String::from("A101"));
+        room.capacity = Some(30);
+        room.building = Some(String::from("A"));
+        room.equipment = split_list("projector, whiteboard,");
+        room.accessibility.step_free = true;
+        assert!(room.matches(&RoomFilter::default()));
+        let mut filter = RoomFilter {
+            min_capacity: Some(25),
+            building: Some(String::from("A")),
+            equipment: vec![String::from("projector")],
+            ..Default::default()
+        };
+        filter.accessibility.step_free = true;
+        assert!(room.matches(&filter));
+        filter.accessibility.hearing_loop = true;
+        assert!(!room.matches(&filter));
+        filter.accessibility.hearing_loop = false;
+        filter.equipment.push(String::from("pcs"));
+        assert!(!room.matches(&filter));
+        filter.equipment.clear();
+        filter.min_capacity = Some(31);
+        assert!(!room.matches(&filter));
+    }
+    #[test]
+    fn rules_are_changed_by_owner() {
+        let new_user = |firstname: &str| {
+            user::User::new(
+                String::from(firstname),
+                String::from("Mustermann"),
+                String::from("1234"),
+            )
+            .unwrap()
+        };
+        let (owner, manager) = (new_user("Max"), new_user("Erika"));
+        let mut room = Room::create(String::from("A101"));
+        room.owner = Some(owner.id());
+        room.managers.push(manager.id());
+        assert!(room.can_change_rules(&owner));
+        assert!(room.can_approve(&manager));
+        assert!(!room.can_change_rules(&manager));
+    }
+    #[test]
+    fn turnover_between_events() {
+        let mut room = Room::default();
+        room.setup_minutes = 10;
+        room.teardown_minutes = 5;
+        let context = BookingContext::default();
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let minutes = Duration::minutes;
+        room.add_event(event(start, minutes(60), minutes(120)), &context)
+            .unwrap();
+        assert_eq!(
+            room.add_event(event(start, minutes(0), minutes(50)), &context),
+            Err(BookingError::Overlap)
+        );
+        assert_eq!(
+            room.add_event(event(start, minutes(125), minutes(180)), &context),
+            Err(BookingError::Overlap)
+        );
+        room.add_event(event(start, minutes(0), minutes(45)), &context)
+            .unwrap();
+        room.add_event(event(start, minutes(135), minutes(180)), &context)
+            .unwrap();
+        assert_eq!(
+            room.free_intervals(start, start + Duration::minutes(240), Duration::minutes(1)),
+            vec![Interval {
+                start: start + Duration::minutes(195),
+                stop: start + Duration::minutes(240)
+            }]
+        );
+        // stored before the limit, it must not overflow the overlap checks
+        room.setup_minutes = i64::MAX;
+        assert_eq!(room.turnover(), Duration::days(1) + Duration::minutes(5));
+    }
+    #[test]
+    fn approve_pending_event() {
+        let mut room = Room::default();
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let pending =
+            event(start, Duration::zero(), Duration::hours(1)).with_status(EventStatus::Pending);
+        let uid = pending.uid();
+        room.add_event(pending, &BookingContext::default()).unwrap();
+        room.set_event_status(&uid, EventStatus::Confirmed).unwrap();
+        assert_eq!(
+            room.get_event_uid(&uid).unwrap().status(),
+            EventStatus::Confirmed
+        );
+        assert!(room.remove_event_uid(&uid).is_some());
+        assert!(room.get_event_uid(&uid).is_none());
+    }
+    #[test]
+    fn dummy_is_rejected() {
+        let mut room = Room::default();
+        let dummy = Event::create_dummy(Local::now());
+        assert_eq!(
+            room.add_event(dummy, &BookingContext::default()),
+            Err(BookingError::Dummy)
+        );
+        assert_eq!(room.events().count(), 0);
+    }
+    #[test]
+    fn expired_hold_frees_slot() {
+        let mut room = Room::default();
+        let now = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let slot = || event(now, Duration::hours(1), Duration::hours(2));
+        let context = BookingContext {
+            now,
+            ..Default::default()
+        };
+        let hold = EventStatus::Held {
+            expires: now + Duration::minutes(30),
+        };
+        room.add_event(slot().with_status(hold), &context).unwrap();
+        assert_eq!(room.add_event(slot(), &context), Err(BookingError::Overlap));
+        let later = BookingContext {
+            now: now + Duration::minutes(30),
+            ..Default::default()
+        };
+        room.add_event(slot(), &later).unwrap();
+        assert_eq!(room.remove_expired_holds(later.now), 0);
+    }
+    #[test]
+    fn cancelled_events_free_the_slot() {
+        let mut room = Room::default();
+        let context = BookingContext::default();
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let slot = || event(start, Duration::zero(), Duration::hours(1));
+        room.add_event(slot(), &context).unwrap();
+        let cancellation = Cancellation {
+            by: String::from("manager"),
+            at: start,
+            reason: Some(String::from("Elternabend")),
+        };
+        let cancelled = room.cancel_event_datetime(start, cancellation).unwrap();
+        assert!(cancelled.cancellation().is_some());
+        room.add_event(slot(), &context).unwrap();
+        let stop = start + Duration::hours(1);
+        assert_eq!(room.events_in_range(start, stop, false).len(), 1);
+        let all = room.events_in_range(start, stop, true);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all.iter().filter(|e| e.cancellation().is_some()).count(), 1);
+    }
+    #[test]
+    fn cancel_during_the_event() {
+        let mut room = Room::default();
+        let context = BookingContext::default();
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let booked = event(start, Duration::zero(), Duration::hours(1));
+        room.add_event(booked.clone(), &context).unwrap();
+        let cancellation = Cancellation {
+            by: String::from("manager"),
+            at: start,
+            reason: None,
+        };
+        let after_the_end = start + Duration::hours(2);
+        assert!(room
+            .cancel_event_datetime(after_the_end, cancellation.clone())
+            .is_none());
+        let during = start + Duration::minutes(30);
+        let cancelled = room.cancel_event_datetime(during, cancellation).unwrap();
+        assert_eq!(cancelled.uid(), booked.uid());
+        assert!(room.events_in_range(start, after_the_end, false).is_empty());
+    }
+    #[test]
+    fn cancel_linked_events() {
+        let mut room = Room::default();
+        let context = BookingContext::default();
+        let start = Local::now();
+        for hours in 0..3 {
+            let from = Duration::hours(hours);
+            let booked = event(start, from, from + Duration::minutes(30));
+            let booked = if hours == 1 {
+                booked
+            } else {
+                booked.with_link(String::from("exam"))
+            };
+            room.add_event(booked, &context).unwrap();
+        }
+        assert_eq!(room.linked_uids("exam").len(), 2);
+        let cancellation = Cancellation {
+            by: String::from("booker"),
+            at: start,
+            reason: None,
+        };
+        assert_eq!(room.cancel_linked("exam", &cancellation).len(), 2);
+        assert_eq!(room.events().count(), 1);
+        assert_eq!(room.cancelled_events().len(), 2);
+    }
+    #[test]
+    fn release_events_without_check_in() {
+        let mut room = Room::default();
+        room.release_after_minutes = Some(15);
+        let now = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let context = BookingContext::default();
+        let hours = Duration::hours;
+        room.add_event(event(now, hours(0), hours(1)), &context)
+            .unwrap();
+        room.add_event(event(now, hours(2), hours(3)), &context)
+            .unwrap();
+        assert!(room
+            .release_no_shows(now + Duration::minutes(10))
+            .is_empty());
+        let checked_in = room.current_event(now + Duration::hours(2)).unwrap().uid();
+        room.modify_event(&checked_in, |event| {
+            event.check_in(now + Duration::hours(2))
+        })
+        .unwrap();
+        let released = room.release_no_shows(now + Duration::minutes(15));
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].start(), now);
+        let cancellation = released[0].cancellation().unwrap();
+        assert_eq!(cancellation.by, Cancellation::SYSTEM);
+        assert_eq!(cancellation.reason.as_deref(), Some("no check-in"));
+        assert!(room
+            .release_no_shows(now + Duration::minutes(135))
+            .is_empty());
+        assert_eq!(room.events().count(), 1);
+        // stored before it was limited, it is never reached instead of overflowing
+        room.release_after_minutes = Some(i64::MAX);
+        assert!(room
+            .release_no_shows(now + Duration::minutes(150))
+            .is_empty());
+    }
+    #[test]
+    fn free_intervals_between_events() {
+        let mut room = Room::default();
+        let context = BookingContext::default();
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let hours = Duration::hours;
+        for (from, to) in [(-1, 1), (2, 3), (3, 4), (5, 8)] {
+            room.add_event(event(start, hours(from), hours(to)), &context)
+                .unwrap();
+        }
+        let free = room.free_intervals(start, start + Duration::hours(6), Duration::minutes(30));
+        assert_eq!(
+            free,
+            vec![
+                Interval {
+                    start: start + Duration::hours(1),
+                    stop: start + Duration::hours(2)
+                },
+                Interval {
+                    start: start + Duration::hours(4),
+                    stop: start + Duration::hours(5)
+                }
+            ]
+        );
+        assert!(room
+            .free_intervals(start, start + Duration::hours(6), Duration::hours(2))
+            .is_empty());
+    }
     #[tokio::test]
     async fn event_creation() {
-        let db = Client::with_uri_str("mongodb://localhost:27017")
-            .await
-            .expect("Connection to mongodb could not be established");
+        let db = Storage::memory();
         let room_opt = Room::getfromdb_name("test-room", &db).await;
         let mut room = if let Ok(room) = room_opt {
             room
@@ -386,22 +1988,224 @@ mod tests {
             room.insert(&db).await.expect("insertion failed");
             room
         };
-        //let start= Local.timestamp_nanos(0);
-        let start = Local::now();
-        let stop = start
-            .with_minute(start.minute() + 1)
-            .expect("failed just because it is HH:59, just rerun the test later");
-        room.add_event(
-            Event::create(
-                String::from("fake-id"),
-                String::from("headline"),
-                None,
-                start,
-                stop,
+        room.book_event(
+            event(Local::now(), Duration::zero(), Duration::minutes(1)),
+            &BookingContext::default(),
+            &db,
+        )
+        .await
+        .expect("event could not be stored")
+        .expect("failed to add event");
+    }
+    #[tokio::test]
+    async fn concurrent_update_conflicts() {
+        let db = Storage::memory();
+        let room = Room::create(String::from("test-room-versions"));
+        room.insert(&db).await.expect("insertion failed");
+        let mut first = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        let mut second = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        first.description = Some(String::from("first"));
+        first.update(&db).await.expect("first update failed");
+        second.description = Some(String::from("second"));
+        assert!(matches!(
+            second.update(&db).await,
+            Err(database::Error::Conflict)
+        ));
+        let changed = Room::modify(&room.id, &db, |room| {
+            room.description = Some(String::from("second"));
+            Some(room.version)
+        })
+        .await
+        .unwrap();
+        assert_eq!(changed, Some(1));
+    }
+
+    #[tokio::test]
+    async fn only_the_booker_cancels() {
+        let db = Storage::memory();
+        let room = Room::create(String::from("test-room-cancel"));
+        room.insert(&db).await.expect("insertion failed");
+        let mut bookers = Vec::new();
+        for firstname in ["First", "Second"] {
+            let booker = user::User::new(
+                String::from(firstname),
+                String::from("Booker"),
+                String::from("1234"),
             )
-            .expect("could not generate event"),
+            .unwrap();
+            booker.insert(&db).await.unwrap();
+            let update = Update::new()
+                .add_to_set(user::User::EDITABLE_ROOMS, &room.id)
+                .unwrap();
+            user::User::update_fields(&booker.id(), update, &db)
+                .await
+                .unwrap();
+            bookers.push(user::User::getfromdb_id(&booker.id(), &db).await.unwrap());
+        }
+        let (first, second) = (&bookers[0], &bookers[1]);
+        assert!(second.can_edit_room(&room.id));
+        let mut room = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        let start = Local::now() + Duration::days(1);
+        let event = Event::create(
+            first.id().to_hex(),
+            String::from("headline"),
+            None,
+            start,
+            start + Duration::hours(1),
         )
-        .expect("failed to add event. Note this test can just run once every minute as it adds an Event that lasts one minute");
-        room.update(&db).await.expect("room could not be updated");
+        .unwrap();
+        room.book_event(event.clone(), &BookingContext::default(), &db)
+            .await
+            .unwrap()
+            .unwrap();
+        let cancellation = Cancellation {
+            by: second.id().to_hex(),
+            at: Local::now(),
+            reason: None,
+        };
+        let cancelled = room.cancel_event_uid(&event.uid(), cancellation).unwrap();
+        let defaults = BookingDefaults::default();
+        let denied = cancel(second, &mut room, &cancelled, &defaults, &db).await;
+        assert!(matches!(denied, Err(Error::Forbidden(_))));
+        cancel(first, &mut room, &cancelled, &defaults, &db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn interleaved_bookings_conflict() {
+        let db = Storage::memory();
+        let room = Room::create(String::from("test-room-interleaved"));
+        room.insert(&db).await.expect("insertion failed");
+        let mut first = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        let mut second = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        let start = Local::now() + Duration::days(1);
+        let hours = Duration::hours;
+        let (a, b) = (
+            event(start, hours(1), hours(3)),
+            event(start, hours(2), hours(4)),
+        );
+        let context = BookingContext::default();
+        let seq_a = first
+            .check_booking(&a, &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_b = second
+            .check_booking(&b, &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(first.commit_booking(&a, seq_a, &db).await.unwrap());
+        assert!(!second.commit_booking(&b, seq_b, &db).await.unwrap());
+        assert!(second.get_event_uid(&b.uid()).is_none());
+        assert!(matches!(
+            second.book_event(b, &context, &db).await,
+            Ok(Err(BookingError::Overlap))
+        ));
+        let stored = db
+            .event_collection()
+            .count_documents(doc! {"room_id": room.id}, None)
+            .await
+            .unwrap();
+        assert_eq!(stored, 1);
+    }
+    #[tokio::test]
+    async fn moves_are_committed_like_bookings() {
+        let db = Storage::memory();
+        let room = Room::create(String::from("test-room-move"));
+        room.insert(&db).await.expect("insertion failed");
+        let mut first = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        let mut second = Room::getfromdb_id(&room.id, &db).await.unwrap();
+        let start = (Local::now() + Duration::days(1))
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let hours = Duration::hours;
+        let context = BookingContext::default();
+        let booked = event(start, hours(1), hours(2));
+        first
+            .book_event(booked.clone(), &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        let other = event(start, hours(3), hours(4));
+        let seq_other = second
+            .check_booking(&other, &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        let moved = booked
+            .clone()
+            .with_times(start + hours(3), start + hours(4));
+        first
+            .move_event(moved.unwrap(), &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        // the move was committed in between, so the other booking sees it
+        assert!(!second.commit_booking(&other, seq_other, &db).await.unwrap());
+        assert!(matches!(
+            second.book_event(other, &context, &db).await,
+            Ok(Err(BookingError::Overlap))
+        ));
+        let stored = db
+            .event_collection()
+            .find_one(doc! {"room_id": room.id}, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.event.uid(), booked.uid());
+        assert_eq!(stored.event.start(), start + hours(3));
+        let count = db
+            .event_collection()
+            .count_documents(doc! {"room_id": room.id}, None)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        // moving the stale version again fails
+        let moved = booked.with_times(start + hours(5), start + hours(6));
+        assert!(matches!(
+            first.move_event(moved.unwrap(), &context, &db).await,
+            Err(database::Error::Conflict)
+        ));
+    }
+    #[tokio::test]
+    async fn history_keeps_cancelled_uid() {
+        let db = Storage::memory();
+        let mut room = Room::create(String::from("test-room-history"));
+        room.insert(&db).await.expect("insertion failed");
+        let start = Local::now() + Duration::days(1);
+        let booked = event(start, Duration::zero(), Duration::hours(1));
+        let context = BookingContext::default();
+        room.book_event(booked.clone(), &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        let cancellation = Cancellation {
+            by: String::from("booker"),
+            at: start,
+            reason: None,
+        };
+        let cancelled = room.cancel_event_uid(&booked.uid(), cancellation).unwrap();
+        room.save_event(&cancelled, &db).await.unwrap();
+        // booked again with the same uid, e.g. by a CalDAV client
+        room.book_event(booked.clone(), &context, &db)
+            .await
+            .unwrap()
+            .unwrap();
+        room.delete_event(&booked, &db).await.unwrap();
+        let history = db
+            .event_collection()
+            .find_one(doc! {"room_id": room.id}, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(history.event.cancellation().is_some());
+        assert!(matches!(
+            room.save_event(&booked, &db).await,
+            Err(database::Error::NotFound)
+        ));
     }
 }
@@ -0,0 +1,151 @@
+//! tasks.rs - background tasks that run while the server is online
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+use crate::booking::BookingDefaults;
+use crate::checkin::NoShow;
+use crate::database::{self, DatabaseConnection, DatabaseUtils, Storage};
+use crate::event::Event;
+use crate::room::Room;
+use crate::{debug_println, notification, tenant, waitlist};
+use bson::oid::ObjectId;
+use bson::{doc, Document};
+use chrono::{DateTime, Local};
+use rocket::{Orbit, Rocket};
+use std::time::Duration;
+
+/// how often the tasks run
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// A liftoff fairing that spawns a loop running all tasks for every tenant every [INTERVAL].
+/// Errors are logged per room, so that one broken room does not stop the others
+pub async fn start(rocket: &Rocket<Orbit>) {
+    let storages = tenant::storages(rocket);
+    if storages.is_empty() {
+        println!("background tasks not started, as the storage is not attached");
+        return;
+    }
+    let defaults = rocket
+        .state::<BookingDefaults>()
+        .cloned()
+        .unwrap_or_default();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(INTERVAL);
+        loop {
+            interval.tick().await;
+            for db in &storages {
+                if let Err(err) = remove_expired_holds(&defaults, db).await {
+                    println!("could not remove expired holds: {:?}", err);
+                }
+                if let Err(err) = release_no_shows(&defaults, db).await {
+                    println!("could not release no-shows: {:?}", err);
+                }
+            }
+        }
+    });
+}
+
+/// removes the holds that were not confirmed in time and offers their time to the waitlist
+async fn remove_expired_holds(
+    defaults: &BookingDefaults,
+    db: &Storage,
+) -> Result<(), database::Error> {
+    let now = Local::now();
+    let held = doc! {"event.status.held": {"$exists": true}};
+    let room_ids = db
+        .event_collection()
+        .distinct("room_id", held.clone(), None)
+        .await?;
+    for room_id in room_ids {
+        let Some(room_id) = room_id.as_object_id() else {
+            continue;
+        };
+        if let Err(err) = remove_expired_holds_in(&room_id, held.clone(), now, defaults, db).await {
+            println!(
+                "could not remove expired holds of room {}: {:?}",
+                room_id, err
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn remove_expired_holds_in(
+    room_id: &ObjectId,
+    held: Document,
+    now: DateTime<Local>,
+    defaults: &BookingDefaults,
+    db: &Storage,
+) -> Result<(), database::Error> {
+    let mut room = Room::getfromdb_id(room_id, db).await?;
+    room.load_events_where(held, db).await?;
+    let expired: Vec<Event> = room
+        .events()
+        .filter(|event| event.is_expired_hold(now))
+        .cloned()
+        .collect();
+    room.remove_expired_holds(now);
+    for event in &expired {
+        room.delete_event(event, db).await?;
+    }
+    for event in &expired {
+        waitlist::offer_slot(&mut room, event, defaults, db).await?;
+    }
+    Ok(())
+}
+
+/// releases the events nobody checked in to by cancelling them, records them as [NoShow],
+/// notifies the bookers and offers the rest of the time to the waitlist
+async fn release_no_shows(defaults: &BookingDefaults, db: &Storage) -> Result<(), database::Error> {
+    let now = Local::now();
+    let mut rooms = db
+        .room_collection()
+        .find(doc! {"release_after_minutes": {"$gt": 0}}, None)
+        .await?;
+    while rooms.advance().await? {
+        let room: Room = match rooms.deserialize_current() {
+            Ok(room) => room,
+            Err(err) => {
+                println!("could not read a room to release no-shows: {:?}", err);
+                continue;
+            }
+        };
+        let room_id = room.id();
+        if let Err(err) = release_no_shows_in(room, now, defaults, db).await {
+            println!("could not release no-shows of room {}: {:?}", room_id, err);
+        }
+    }
+    Ok(())
+}
+
+async fn release_no_shows_in(
+    mut room: Room,
+    now: DateTime<Local>,
+    defaults: &BookingDefaults,
+    db: &Storage,
+) -> Result<(), database::Error> {
+    room.load_events(now, now, false, db).await?;
+    let mut released = Vec::new();
+    for event in room.release_no_shows(now) {
+        match room.save_event(&event, db).await {
+            Ok(()) => released.push(event),
+            // e.g. checked in meanwhile, it is released in the next run if it still has to be
+            Err(database::Error::Conflict) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    for event in released {
+        let message = format!(
+            "\"{}\" in {} was released, as nobody checked in",
+            event.headline(),
+            room.name()
+        );
+        if let Err(err) = notification::notify(event.booker_id(), message, db).await {
+            debug_println!("could not notify booker: {:?}", err);
+        }
+        waitlist::offer_slot(&mut room, &event, defaults, db).await?;
+        db.no_show_collection()
+            .insert_one(NoShow::create(&room, event, now), None)
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! tenant.rs - several schools served by one server
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! every tenant has its own database, so users, rooms, e-papers etc. are separate,
+//! and its own image directories. The tenant of a request is named by the configured
+//! header, or else looked up by the host of the request:
+//! ```toml
+//! [default.tenancy]
+//! header = "X-Tenant"
+//! [default.tenancy.tenants.gymnasium]
+//! database = "gymnasium" # defaults to the name of the tenant
+//! hosts = ["gymnasium.schule.de"]
+//! ```
+//! Without tenants every request uses the database from `database_name`.
+use crate::database::Storage;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::{fairing, Build, Phase, Rocket};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// the `tenancy` config
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Tenancy {
+    /// the header naming the tenant, it is checked before the host
+    header: Option<String>,
+    #[serde(default)]
+    tenants: HashMap<String, TenantConfig>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct TenantConfig {
+    /// the name of the database, defaults to the name of the tenant
+    database: Option<String>,
+    /// the hosts under which the tenant is served
+    #[serde(default)]
+    hosts: Vec<String>,
+}
+
+impl Tenancy {
+    pub fn is_enabled(&self) -> bool {
+        !self.tenants.is_empty()
+    }
+    /// the tenant named by the header, or else the one served under the host.
+    /// None if there are no tenants
+    pub fn resolve(
+        &self,
+        header: Option<&str>,
+        host: Option<&str>,
+    ) -> Result<Option<&str>, &'static str> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        if let Some(name) = header {
+            return match self.tenants.get_key_value(name) {
+                Some((name, _)) => Ok(Some(name)),
+                None => Err("unknown tenant"),
+            };
+        }
+        let host = host.ok_or("no tenant given")?;
+        self.tenants
+            .iter()
+            .find(|(_, config)| config.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .map(|(name, _)| Some(name.as_str()))
+            .ok_or("no tenant for the host")
+    }
+    /// the name of the database of the tenant
+    pub fn database<'a>(&'a self, tenant: &'a str) -> &'a str {
+        self.tenants
+            .get(tenant)
+            .and_then(|config| config.database.as_deref())
+            .unwrap_or(tenant)
+    }
+    /// the tenant names are used as directory names, see [directory]
+    fn validate(&self) -> Result<(), String> {
+        for name in self.tenants.keys() {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!("invalid tenant name {:?}", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A fairing that reads the [Tenancy] and manages it.
+/// A missing `tenancy` section serves a single school
+pub async fn load(rocket: Rocket<Build>) -> fairing::Result {
+    let tenancy = match rocket.figment().extract_inner::<Tenancy>("tenancy") {
+        Ok(tenancy) => tenancy,
+        Err(err) if err.missing() => Tenancy::default(),
+        Err(err) => {
+            println!("invalid tenancy config: {}", err);
+            return Err(rocket);
+        }
+    };
+    if let Err(err) = tenancy.validate() {
+        println!("invalid tenancy config: {}", err);
+        return Err(rocket);
+    }
+    Ok(rocket.manage(tenancy))
+}
+
+/// the storage with the database of the tenant of the request, and the name of the tenant
+pub fn storage_for(
+    request: &Request<'_>,
+) -> Result<(Storage, Option<String>), (Status, &'static str)> {
+    let Some(storage) = request.rocket().state::<Storage>() else {
+        return Err((Status::InternalServerError, "storage not attached"));
+    };
+    let Some(tenancy) = request.rocket().state::<Tenancy>() else {
+        return Ok((storage.clone(), None));
+    };
+    let header = tenancy
+        .header
+        .as_deref()
+        .and_then(|header| request.headers().get_one(header));
+    let host = request.host().map(|host| host.domain().as_str());
+    match tenancy.resolve(header, host) {
+        Ok(Some(tenant)) => Ok((
+            storage.with_database(tenancy.database(tenant)),
+            Some(String::from(tenant)),
+        )),
+        Ok(None) => Ok((storage.clone(), None)),
+        Err(err) => Err((Status::NotFound, err)),
+    }
+}
+
+/// the storage of every tenant, e.g. for the background tasks.
+/// Without tenants only the configured one
+pub fn storages<P: Phase>(rocket: &Rocket<P>) -> Vec<Storage> {
+    let Some(storage) = rocket.state::<Storage>() else {
+        return Vec::new();
+    };
+    match rocket.state::<Tenancy>() {
+        Some(tenancy) if tenancy.is_enabled() => tenancy
+            .tenants
+            .keys()
+            .map(|tenant| storage.with_database(tenancy.database(tenant)))
+            .collect(),
+        _ => vec![storage.clone()],
+    }
+}
+
+/// the directory of the tenant inside of base, e.g. "./images/gymnasium"
+pub fn directory(base: &str, tenant: Option<&str>) -> PathBuf {
+    match tenant {
+        Some(tenant) => Path::new(base).join(tenant),
+        None => PathBuf::from(base),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_tenant() {
+        let mut tenancy = Tenancy {
+            header: Some(String::from("X-Tenant")),
+            tenants: HashMap::new(),
+        };
+        assert_eq!(tenancy.resolve(Some("a"), None), Ok(None));
+        tenancy.tenants.insert(
+            String::from("gymnasium"),
+            TenantConfig {
+                database: None,
+                hosts: vec![String::from("gymnasium.schule.de")],
+            },
+        );
+        tenancy.tenants.insert(
+            String::from("grundschule"),
+            TenantConfig {
+                database: Some(String::from("gs")),
+                hosts: Vec::new(),
+            },
+        );
+        assert_eq!(
+            tenancy.resolve(None, Some("Gymnasium.schule.de")),
+            Ok(Some("gymnasium"))
+        );
+        assert_eq!(
+            tenancy.resolve(Some("grundschule"), Some("gymnasium.schule.de")),
+            Ok(Some("grundschule"))
+        );
+        assert!(tenancy.resolve(Some("realschule"), None).is_err());
+        assert!(tenancy.resolve(None, Some("localhost")).is_err());
+        assert_eq!(tenancy.database("gymnasium"), "gymnasium");
+        assert_eq!(tenancy.database("grundschule"), "gs");
+        assert!(tenancy.validate().is_ok());
+        tenancy
+            .tenants
+            .insert(String::from("../x"), TenantConfig::default());
+        assert!(tenancy.validate().is_err());
+    }
+}
@@ -1,13 +1,25 @@
-use crate::database::{self, Connection, DatabaseConnection, DatabaseUtils, MainDatabase};
-use crate::room::Room;
+use crate::database::{
+    self, Collection, Connection, DatabaseConnection, DatabaseUtils, Field, MainDatabase, Storage,
+    Update,
+};
+use crate::error::Error;
+use crate::input::Input;
+use crate::mail;
+use crate::room::{Room, RoomFilter};
+use crate::tenant;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use bson::oid::ObjectId;
 use mongodb::bson::doc;
-use rocket::{form::Form, Route};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket::Route;
 use serde::*;
+use std::borrow::Cow;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     #[serde(rename = "_id")]
@@ -26,12 +38,23 @@ pub struct User {
     surname: String,
     email: Option<String>,
     phone_number: Option<String>, // String for setting prefix in brackets or +49
+    // users that can book and cancel events in the name of this user, e.g. a secretary
+    #[serde(default)]
+    delegates: Vec<bson::oid::ObjectId>,
+    #[serde(default)]
+    version: i64,
 }
 impl database::DatabaseConnection for User {
     fn id(&self) -> ObjectId {
         self.id
     }
-    fn collection(db: &mongodb::Client) -> mongodb::Collection<Self> {
+    fn version(&self) -> i64 {
+        self.version
+    }
+    fn set_version(&mut self, version: i64) {
+        self.version = version;
+    }
+    fn collection(db: &Storage) -> Collection<Self> {
         db.user_collection()
     }
     #[inline]
@@ -43,18 +66,25 @@ impl database::DatabaseConnection for User {
     }
 }
 
-impl<'a> User {
+/// the fields that are changed with [DatabaseConnection::update_fields]
+impl User {
+    pub const PASSWORD_HASH: Field<User, String> = Field::new("password_hash");
+    pub const EDITABLE_ROOMS: Field<User, Vec<ObjectId>> = Field::new("editable_rooms");
+    pub const FIRSTNAME: Field<User, String> = Field::new("firstname");
+    pub const SURNAME: Field<User, String> = Field::new("surname");
+    pub const EMAIL: Field<User, Option<String>> = Field::new("email");
+    pub const PHONE_NUMBER: Field<User, Option<String>> = Field::new("phone_number");
+    pub const DELEGATES: Field<User, Vec<ObjectId>> = Field::new("delegates");
+}
+
+impl User {
     // why does this function take so long to execute? is it the SaltString?
-    pub fn new(firstname: String, surname: String, password: String) -> Result<Self, &'a str> {
+    pub fn new(firstname: String, surname: String, password: String) -> Result<Self, Error> {
         if password == " " {
-            return Err("Password cannot be empty");
+            return Err(Error::Invalid(String::from("Password cannot be empty")));
         }
-        let salt = SaltString::generate(&mut OsRng);
-        let password_hash = Argon2::default()
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
-        let user = Ok(User {
+        let password_hash = hash_password(&password)?;
+        let user: Result<User, &str> = Ok(User {
             id: bson::oid::ObjectId::new(),
             username: format!("{}.{}", surname, firstname),
             password_hash,
@@ -66,31 +96,32 @@ impl<'a> User {
             surname,
             email: None,
             phone_number: None,
+            delegates: Vec::new(),
+            version: 0,
         });
-        user.validate_first_name().validate_surname()
+        user.validate_first_name()
+            .validate_surname()
+            .map_err(|err| Error::Invalid(String::from(err)))
     }
 
     // TODO: remove timing attack as the time if the user is right is significantly longer
-    pub async fn login(
-        username: &str,
-        password: String,
-        db: &mongodb::Client,
-    ) -> Result<Self, &'a str> {
+    pub async fn login(username: &str, password: String, db: &Storage) -> Result<Self, Error> {
         let user = db
             .user_collection()
             .find_one(doc! {"username": username}, None)
-            .await
-            .unwrap();
+            .await?;
         if let Some(user) = user {
-            let passwordhash = PasswordHash::new(&user.password_hash).unwrap();
+            let passwordhash = PasswordHash::new(&user.password_hash).map_err(|err| {
+                Error::Internal(format!("invalid password hash of {}: {}", username, err))
+            })?;
 
             if let Ok(_) = Argon2::default().verify_password(password.as_bytes(), &passwordhash) {
                 return Ok(user);
             } else {
-                return Err("password wrong");
+                return Err(Error::Unauthorized("password wrong"));
             }
         } else {
-            return Err("user not known");
+            return Err(Error::Unauthorized("user not known"));
         }
     }
     pub fn can_edit_room(&self, room_id: &ObjectId) -> bool {
@@ -102,6 +133,41 @@ impl<'a> User {
     pub fn can_create_rooms(&self) -> bool {
         self.is_admin
     }
+    pub fn is_admin(&self) -> bool {
+        self.is_admin
+    }
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+    pub fn full_name(&self) -> String {
+        format!("{} {}", self.firstname, self.surname)
+    }
+    /// whether the user can book and cancel events in the name of the organizer
+    pub fn can_act_for(&self, organizer: &User) -> bool {
+        self.is_admin || self.id == organizer.id || organizer.delegates.contains(&self.id)
+    }
+    /// like [User::can_act_for], with the hex id used by [crate::event::Event::booker_id]
+    pub async fn can_act_for_id(&self, organizer_id: &str, db: &Storage) -> bool {
+        if self.is_admin || self.id.to_hex() == organizer_id {
+            return true;
+        }
+        let Ok(organizer_id) = ObjectId::parse_str(organizer_id) else {
+            return false;
+        };
+        match User::getfromdb_id(&organizer_id, db).await {
+            Ok(organizer) => self.can_act_for(&organizer),
+            Err(_) => false,
+        }
+    }
+}
+
+/// hashes the password with a new salt
+fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| Error::Internal(format!("could not hash the password: {}", err)))
 }
 
 trait UserValidate<'a>
@@ -133,17 +199,64 @@ impl<'a> UserValidate<'a> for Result<User, &'a str> {
     }
 }
 
-#[derive(Debug, FromForm)]
+#[derive(Debug, Deserialize, FromForm)]
 pub struct UserData<'r> {
-    pub username: &'r str,
-    pub password: &'r str,
+    // borrowed if possible, json strings with escapes need to be unescaped
+    #[serde(borrow)]
+    pub username: Cow<'r, str>,
+    #[serde(borrow)]
+    pub password: Cow<'r, str>,
 }
-#[derive(Debug, FromForm)]
+impl UserData<'_> {
+    /// logs in the user, like [User::login]
+    pub async fn login(&self, db: &Storage) -> Result<User, Error> {
+        User::login(&self.username, self.password.to_string(), db).await
+    }
+}
+/// Request guard for clients that cannot send [UserData] as a form,
+/// e.g. calendar clients. It logs in the User given by the
+/// `Authorization: Basic` header.
+pub struct BasicAuth(pub User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicAuth {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(credentials) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        else {
+            return Outcome::Failure((Status::Unauthorized, "no basic authorization"));
+        };
+        let Some((username, password)) = credentials.split_once(':') else {
+            return Outcome::Failure((Status::Unauthorized, "malformed credentials"));
+        };
+        let db = match tenant::storage_for(request) {
+            Ok((db, _)) => db,
+            Err(failure) => return Outcome::Failure(failure),
+        };
+        match User::login(username, String::from(password), &db).await {
+            Ok(user) => Outcome::Success(BasicAuth(user)),
+            Err(Error::Unauthorized(err)) => Outcome::Failure((Status::Unauthorized, err)),
+            Err(err) => {
+                println!("login failed: {:?}", err);
+                Outcome::Failure((err.status(), "login failed"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, FromForm)]
 struct CreateUserForm<'r> {
+    #[serde(borrow)]
     userdata: UserData<'r>,
-    firstname: &'r str,
-    surname: &'r str,
-    password: &'r str,
+    firstname: String,
+    surname: String,
+    password: String,
 }
 /// Creates a new User with given Credentials
 ///
@@ -151,78 +264,61 @@ struct CreateUserForm<'r> {
 /// or has the can_create_users field set
 ///
 #[post("/create", data = "<form>")]
-async fn create(form: Form<CreateUserForm<'_>>, db: Connection<MainDatabase>) -> String {
-    println!("{:?}", form);
-    let logged_in_user = User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await;
-    if logged_in_user.is_ok() {
-        let logged_in_user = logged_in_user.unwrap();
-        if !(logged_in_user.is_admin) && !(logged_in_user.can_create_users) {
-            return String::from("you need to be admin or be able to create users");
-        }
-        let new_user = User::new(
-            String::from(form.firstname),
-            String::from(form.surname),
-            String::from(form.password),
-        );
-        if let Ok(new_user) = new_user {
-            if new_user.insert(&*db).await.is_err() {
-                return "user could not be inserted into DB".to_string();
-            }
-            return rocket::serde::json::to_string(&new_user).unwrap();
-        } else {
-            return String::from(new_user.err().unwrap());
-        }
-    } else {
-        return String::from(logged_in_user.err().unwrap());
+async fn create(
+    form: Input<CreateUserForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<User>, Error> {
+    let logged_in_user = form.userdata.login(&db).await?;
+    if !(logged_in_user.is_admin) && !(logged_in_user.can_create_users) {
+        return Err(Error::Forbidden(
+            "you need to be admin or be able to create users",
+        ));
     }
+    let new_user = User::new(
+        form.firstname.clone(),
+        form.surname.clone(),
+        form.password.clone(),
+    )?;
+    new_user.insert(&*db).await?;
+    Ok(Json(new_user))
 }
-#[derive(Debug, FromForm)]
+#[derive(Debug, Deserialize, FromForm)]
 struct DeleteUserForm<'r> {
+    #[serde(borrow)]
     userdata: UserData<'r>,
-    name: &'r str,
+    name: String,
 }
 #[post("/delete", data = "<form>")]
-async fn delete(form: Form<DeleteUserForm<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    let user = User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
+async fn delete(
+    form: Input<DeleteUserForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
     if !user.is_admin {
-        return None;
+        return Err(Error::Forbidden("only admins can delete users"));
+    }
+    let result = db
+        .user_collection()
+        .delete_one(doc! {"username": &form.name}, None)
+        .await?;
+    if result.deleted_count == 0 {
+        return Err(Error::NotFound("user not found"));
     }
-    db.user_collection()
-        .delete_one(doc! {"_id": form.name}, None)
-        .await
-        .ok()?;
-    Some(())
+    Ok(())
 }
 
 #[post("/isvalid", data = "<form>")]
-async fn isvalid(form: Form<UserData<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    let _user = User::login(form.username, String::from(form.password), &*db)
-        .await
-        .ok()?;
-    Some(())
+async fn isvalid(form: Input<UserData<'_>>, db: Connection<MainDatabase>) -> Result<(), Error> {
+    form.login(&db).await?;
+    Ok(())
 }
 #[post("/get", data = "<form>")]
-async fn get(form: Form<UserData<'_>>, db: Connection<MainDatabase>) -> Option<String> {
-    rocket::serde::json::to_string(
-        &User::login(form.username, String::from(form.password), &*db)
-            .await
-            .ok()?,
-    )
-    .ok()
+async fn get(form: Input<UserData<'_>>, db: Connection<MainDatabase>) -> Result<Json<User>, Error> {
+    Ok(Json(form.login(&db).await?))
 }
-#[derive(Debug, FromForm)]
+#[derive(Debug, Deserialize, FromForm)]
 struct ChangeUserForm<'r> {
+    #[serde(borrow)]
     userdata: UserData<'r>,
     email: Option<String>,
     firstname: Option<String>,
@@ -230,77 +326,184 @@ struct ChangeUserForm<'r> {
     phone_number: Option<String>,
 }
 #[post("/change", data = "<form>")]
-async fn change(form: Form<ChangeUserForm<'_>>, db: Connection<MainDatabase>) -> Option<()> {
-    let mut user = User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    if let Some(firstname) = form.firstname.clone() {
-        user.firstname = firstname;
-    }
-    if let Some(surname) = form.surname.clone() {
-        user.surname = surname;
-    }
-    user.email = form.email.clone();
-    user.phone_number = form.phone_number.clone();
-    user.update(&db).await.ok()?;
-    Some(())
+async fn change(
+    form: Input<ChangeUserForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    if let Some(email) = &form.email
+        && !mail::is_valid_address(email)
+    {
+        return Err(Error::Invalid(format!("{} is not an email address", email)));
+    }
+    let mut update = Update::new()
+        .set(User::EMAIL, &form.email)?
+        .set(User::PHONE_NUMBER, &form.phone_number)?;
+    if let Some(firstname) = &form.firstname {
+        update = update.set(User::FIRSTNAME, firstname)?;
+    }
+    if let Some(surname) = &form.surname {
+        update = update.set(User::SURNAME, surname)?;
+    }
+    User::update_fields(&user.id, update, &db).await?;
+    Ok(())
 }
-#[post("/get_rooms", data = "<form>")]
-async fn get_rooms(form: Form<UserData<'_>>, db: Connection<MainDatabase>) -> Option<String> {
-    let user = User::login(form.username, String::from(form.password), &*db)
-        .await
-        .ok()?;
-    let mut ret = String::new();
+/// the names of all rooms the user can edit that match the filter
+pub(crate) async fn editable_rooms(
+    user: &User,
+    filter: &RoomFilter,
+    db: &Storage,
+) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
     if user.is_admin {
-        let mut rooms = Room::get_all_from_db(&db).await?;
-        while rooms.advance().await.ok()? {
-            let room = rooms.deserialize_current().ok()?;
-            ret.push_str(room.name());
-            ret.push_str("\n");
+        let mut rooms = Room::get_all_from_db(db).await?;
+        while rooms.advance().await? {
+            let room = rooms.deserialize_current()?;
+            if room.matches(filter) {
+                ret.push(room.name().to_string());
+            }
         }
     } else {
-        for room_id in user.editable_rooms {
-            let room = Room::getfromdb_id(&room_id, &db).await.ok()?;
-            ret.push_str(room.name());
-            ret.push_str("\n");
+        for room_id in &user.editable_rooms {
+            // rooms can be deleted without updating every user
+            let room = match Room::getfromdb_id(room_id, db).await {
+                Ok(room) => room,
+                Err(database::Error::NotFound) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if room.matches(filter) {
+                ret.push(room.name().to_string());
+            }
         }
     }
-    Some(ret)
+    Ok(ret)
+}
+/// lists the names of all rooms the user can edit.
+/// The rooms can be filtered by their attributes with query parameters,
+/// e.g. `/user/get_rooms?min_capacity=20&equipment=projector`
+#[post("/get_rooms?<filter..>", data = "<form>")]
+async fn get_rooms(
+    filter: RoomFilter,
+    form: Input<UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<String>>, Error> {
+    let user = form.login(&db).await?;
+    Ok(Json(editable_rooms(&user, &filter, &db).await?))
 }
 
-#[derive(Debug, FromForm)]
+#[derive(Debug, Deserialize, FromForm)]
 struct UpdatePasswordForm<'a> {
+    #[serde(borrow)]
     userdata: UserData<'a>,
     new_password: String,
 }
 #[post("/update_password", data = "<form>")]
 async fn update_password(
-    form: Form<UpdatePasswordForm<'_>>,
+    form: Input<UpdatePasswordForm<'_>>,
     db: Connection<MainDatabase>,
-) -> Option<()> {
+) -> Result<(), Error> {
     if form.new_password == "" {
-        return None;
+        return Err(Error::Invalid(String::from("the password cannot be empty")));
     }
 
-    let mut user = User::login(
-        form.userdata.username,
-        String::from(form.userdata.password),
-        &*db,
-    )
-    .await
-    .ok()?;
-    let salt = SaltString::generate(&mut OsRng);
-    let password_hash = Argon2::default()
-        .hash_password(form.new_password.as_bytes(), &salt)
-        .unwrap()
-        .to_string();
-    user.password_hash = password_hash;
-    user.update(&*db).await.ok()?;
-    Some(())
+    let user = form.userdata.login(&db).await?;
+    let password_hash = hash_password(&form.new_password)?;
+    let update = Update::new().set(User::PASSWORD_HASH, &password_hash)?;
+    User::update_fields(&user.id, update, &db).await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct DelegateForm<'r> {
+    #[serde(borrow)]
+    userdata: UserData<'r>,
+    /// the user that can book in the name of the logged in user
+    delegate: String,
+}
+/// allows the delegate to book and cancel events in the name of the logged in user.
+/// Adding a delegate twice is not an error
+#[post("/add_delegate", data = "<form>")]
+async fn add_delegate(
+    form: Input<DelegateForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let delegate = User::getfromdb_name(&form.delegate, &db).await?;
+    if delegate.id == user.id {
+        return Err(Error::Invalid(String::from(
+            "you cannot be your own delegate",
+        )));
+    }
+    let update = Update::new().add_to_set(User::DELEGATES, &delegate.id)?;
+    User::update_fields(&user.id, update, &db).await?;
+    Ok(())
+}
+/// removing a user that is not a delegate is not an error
+#[post("/remove_delegate", data = "<form>")]
+async fn remove_delegate(
+    form: Input<DelegateForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let delegate = User::getfromdb_name(&form.delegate, &db).await?;
+    let update = Update::new().pull(User::DELEGATES, &delegate.id)?;
+    User::update_fields(&user.id, update, &db).await?;
+    Ok(())
+}
+#[derive(Debug, Deserialize, FromForm)]
+struct EditableRoomForm<'r> {
+    #[serde(borrow)]
+    userdata: UserData<'r>,
+    username: String,
+    room_name: String,
+}
+/// allows the user to edit and book the room, only admins can grant this
+#[post("/add_editable_room", data = "<form>")]
+async fn add_editable_room(
+    form: Input<EditableRoomForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let admin = form.userdata.login(&db).await?;
+    if !admin.is_admin {
+        return Err(Error::Forbidden("only admins can grant rooms"));
+    }
+    let user = User::getfromdb_name(&form.username, &db).await?;
+    let room = Room::getfromdb_name(&form.room_name, &db).await?;
+    let update = Update::new().add_to_set(User::EDITABLE_ROOMS, &room.id())?;
+    User::update_fields(&user.id, update, &db).await?;
+    Ok(())
+}
+#[post("/remove_editable_room", data = "<form>")]
+async fn remove_editable_room(
+    form: Input<EditableRoomForm<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<(), Error> {
+    let admin = form.userdata.login(&db).await?;
+    if !admin.is_admin {
+        return Err(Error::Forbidden("only admins can revoke rooms"));
+    }
+    let user = User::getfromdb_name(&form.username, &db).await?;
+    let room = Room::getfromdb_name(&form.room_name, &db).await?;
+    let update = Update::new().pull(User::EDITABLE_ROOMS, &room.id())?;
+    User::update_fields(&user.id, update, &db).await?;
+    Ok(())
+}
+/// lists the usernames of the users the logged in user can book for
+#[post("/delegators", data = "<form>")]
+async fn delegators(
+    form: Input<UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<String>>, Error> {
+    let user = form.login(&db).await?;
+    let mut ret = Vec::new();
+    let mut users = db
+        .user_collection()
+        .find(doc! {"delegates": user.id}, None)
+        .await?;
+    while users.advance().await? {
+        ret.push(users.deserialize_current()?.username);
+    }
+    Ok(Json(ret))
 }
 
 pub fn routes() -> Vec<Route> {
@@ -311,7 +514,12 @@ pub fn routes() -> Vec<Route> {
         get_rooms,
         isvalid,
         update_password,
-        change
+        change,
+        add_delegate,
+        remove_delegate,
+        add_editable_room,
+        remove_editable_room,
+        delegators
     ]
 }
 #[cfg(test)]
@@ -319,16 +527,13 @@ mod tests {
     use crate::database::Error;
 
     use super::*;
-    use mongodb::Client;
     use tokio::time;
-    /// create some demo users on the life database.
+    /// create some demo users in the in-memory storage.
     #[tokio::test]
     async fn test_usercreation() {
         let mut response_times = Vec::new();
 
-        let client = Client::with_uri_str("mongodb://localhost:27017")
-            .await
-            .unwrap();
+        let client = Storage::memory();
         let users = &mut Vec::new();
         let start_time = time::Instant::now();
         #[macro_export]
@@ -0,0 +1,256 @@
+//! waitlist.rs - queueing for time slots that are already booked
+//!
+//! Copyright 2023 by Ben Mattes Krusekamp <ben.krause05@gmail.com>
+//!
+//! whenever an event is removed or released, [offer_slot] goes through the
+//! waitlist of the room in the order the entries were created. Entries with
+//! `auto_book` are booked directly, the others get a hold for [CLAIM_MINUTES]
+//! which is claimed with /room/confirm_hold.
+use crate::booking::{BookingContext, BookingDefaults};
+use crate::database::{self, Connection, DatabaseConnection, DatabaseUtils, Storage};
+use crate::error::{self, Error};
+use crate::event::{Event, EventStatus};
+use crate::input::Input;
+use crate::room::{find_room, Room};
+use crate::{debug_println, notification, user, MainDatabase};
+use bson::doc;
+use bson::oid::ObjectId;
+use chrono::{DateTime, Duration, Local};
+use rocket::serde::json::Json;
+use rocket::Route;
+use serde::*;
+
+/// how long a waiting user can claim a freed slot
+pub const CLAIM_MINUTES: i64 = 30;
+
+/// a user waiting for a time slot of a room
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaitlistEntry {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    room_id: ObjectId,
+    user_id: ObjectId,
+    headline: String,
+    description: Option<String>,
+    start: DateTime<Local>,
+    stop: DateTime<Local>,
+    /// book the slot as soon as it is free instead of offering a hold
+    auto_book: bool,
+}
+impl WaitlistEntry {
+    /// whether the entry wants (a part of) the time of the event
+    pub fn overlaps_with(&self, event: &Event) -> bool {
+        self.start < event.stop() && event.start() < self.stop
+    }
+}
+
+/// offers the time of the freed event to the users waiting for it, in the order
+/// they joined the waitlist. Every entry that could be booked is removed from the waitlist,
+/// entries of users that cannot book the room anymore are removed as well.
+pub async fn offer_slot(
+    room: &mut Room,
+    freed: &Event,
+    defaults: &BookingDefaults,
+    db: &Storage,
+) -> Result<(), database::Error> {
+    let options = mongodb::options::FindOptions::builder()
+        .sort(doc! {"_id": 1})
+        .build();
+    let mut cursor = db
+        .waitlist_collection()
+        .find(doc! {"room_id": room.id()}, options)
+        .await?;
+    let mut entries = Vec::new();
+    while cursor.advance().await? {
+        let entry: WaitlistEntry = cursor.deserialize_current()?;
+        if entry.overlaps_with(freed) {
+            entries.push(entry);
+        }
+    }
+    for entry in entries {
+        let remove = doc! {"_id": entry.id};
+        let booker = match user::User::getfromdb_id(&entry.user_id, db).await {
+            Ok(booker) => booker,
+            Err(database::Error::NotFound) => {
+                db.waitlist_collection().delete_one(remove, None).await?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let (Some(status), Some(event)) = (
+            room.booking_status(&booker),
+            Event::create(
+                booker.id().to_hex(),
+                entry.headline.clone(),
+                entry.description.clone(),
+                entry.start,
+                entry.stop,
+            ),
+        ) else {
+            db.waitlist_collection().delete_one(remove, None).await?;
+            continue;
+        };
+        let now = Local::now();
+        let event = if entry.auto_book {
+            event.with_status(status)
+        } else {
+            event.with_status(EventStatus::Held {
+                expires: now + Duration::minutes(CLAIM_MINUTES),
+            })
+        };
+        let uid = event.uid();
+        let context = BookingContext::load(room, &booker.id().to_hex(), defaults, db).await?;
+        // e.g. another event still overlaps, the entry keeps waiting
+        if room.book_event(event, &context, db).await?.is_err() {
+            continue;
+        }
+        db.waitlist_collection().delete_one(remove, None).await?;
+        let message = if entry.auto_book {
+            format!(
+                "\"{}\" in {} on {} was booked from the waitlist",
+                entry.headline,
+                room.name(),
+                entry.start.format("%d.%m.%Y %H:%M")
+            )
+        } else {
+            format!(
+                "\"{}\" in {} on {} is free, confirm the hold {} within {} minutes to book it",
+                entry.headline,
+                room.name(),
+                entry.start.format("%d.%m.%Y %H:%M"),
+                uid,
+                CLAIM_MINUTES
+            )
+        };
+        if let Err(err) = notification::notify(&booker.id().to_hex(), message, db).await {
+            debug_println!("could not notify waiting user: {:?}", err);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct JoinForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    room_name: Option<String>,
+    room_id: Option<String>,
+    headline: String,
+    description: Option<String>,
+    start: String,
+    stop: String,
+    auto_book: Option<bool>,
+}
+/// puts the user on the waitlist of the room for the time range.
+/// Returns the id of the entry
+#[post("/join", data = "<form>")]
+async fn join(form: Input<JoinForm<'_>>, db: Connection<MainDatabase>) -> Result<String, Error> {
+    let user = form.userdata.login(&db).await?;
+    let room = find_room(&form.room_id, &form.room_name, &db).await?;
+    room.booking_status(&user)
+        .ok_or(Error::Forbidden("you cannot book the room"))?;
+    let start = error::parse_time("start", &form.start)?;
+    let stop = error::parse_time("stop", &form.stop)?;
+    // validates the times the same way as a booking
+    let event = Event::create(
+        user.id().to_hex(),
+        form.headline.clone(),
+        form.description.clone(),
+        start,
+        stop,
+    )
+    .ok_or_else(|| {
+        Error::Invalid(String::from(
+            "the headline must be a single line and the event needs to end after its start",
+        ))
+    })?;
+    if event.stop() <= Local::now() {
+        return Err(Error::Invalid(String::from("the time range is over")));
+    }
+    let entry = WaitlistEntry {
+        id: ObjectId::new(),
+        room_id: room.id(),
+        user_id: user.id(),
+        headline: form.headline.clone(),
+        description: form.description.clone(),
+        start: event.start(),
+        stop: event.stop(),
+        auto_book: form.auto_book.unwrap_or(false),
+    };
+    db.waitlist_collection().insert_one(&entry, None).await?;
+    Ok(entry.id.to_hex())
+}
+
+#[derive(Debug, Deserialize, FromForm)]
+struct LeaveForm<'r> {
+    #[serde(borrow)]
+    userdata: user::UserData<'r>,
+    id: String,
+}
+/// removes an entry of the user from the waitlist
+#[post("/leave", data = "<form>")]
+async fn leave(form: Input<LeaveForm<'_>>, db: Connection<MainDatabase>) -> Result<(), Error> {
+    let user = form.userdata.login(&db).await?;
+    let id = ObjectId::parse_str(&form.id)
+        .map_err(|_| Error::Invalid(String::from("id is not a valid id")))?;
+    let result = db
+        .waitlist_collection()
+        .delete_one(doc! {"_id": id, "user_id": user.id()}, None)
+        .await?;
+    if result.deleted_count == 0 {
+        return Err(Error::NotFound("waitlist entry not found"));
+    }
+    Ok(())
+}
+
+/// lists the waitlist entries of the user as json
+#[post("/list", data = "<form>")]
+async fn list(
+    form: Input<user::UserData<'_>>,
+    db: Connection<MainDatabase>,
+) -> Result<Json<Vec<WaitlistEntry>>, Error> {
+    let user = form.login(&db).await?;
+    let mut cursor = db
+        .waitlist_collection()
+        .find(doc! {"user_id": user.id()}, None)
+        .await?;
+    let mut entries: Vec<WaitlistEntry> = Vec::new();
+    while cursor.advance().await? {
+        entries.push(cursor.deserialize_current()?);
+    }
+    Ok(Json(entries))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![join, leave, list]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::tests::event;
+    use chrono::Timelike;
+
+    #[test]
+    fn entry_overlaps_freed_event() {
+        let start = Local::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let entry = WaitlistEntry {
+            id: ObjectId::new(),
+            room_id: ObjectId::new(),
+            user_id: ObjectId::new(),
+            headline: String::from("Nachschreibklausur"),
+            description: None,
+            start,
+            stop: start + Duration::hours(1),
+            auto_book: true,
+        };
+        let minutes = Duration::minutes;
+        assert!(entry.overlaps_with(&event(start, minutes(30), minutes(90))));
+        assert!(entry.overlaps_with(&event(start, minutes(-30), minutes(120))));
+        assert!(!entry.overlaps_with(&event(start, minutes(60), minutes(90))));
+    }
+}